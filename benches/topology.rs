@@ -0,0 +1,270 @@
+//! Cost of the pair lookup done for every proposed move and iterations per second of the CN
+//! energy path, dense (site, neighbor slot) tables against the former hashed `nn` and
+//! `nn_pair_no_intersec` maps, and iterations per second of a whole `EnergyInput::Cn` run on the
+//! dense tables.
+//!
+//! cargo bench --bench topology
+use fnv::FnvHashMap;
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+use std::hint::black_box;
+use std::sync::Arc;
+use std::time::Instant;
+use MC_Cluster::energy::{EnergyInput, EnergyValues};
+use MC_Cluster::{
+    AtomNames, CreateStructure, GridStructure, Lattice, Simulation, SimulationOptions, Structure,
+};
+
+const LOOKUPS: usize = 50_000_000;
+const ITERATIONS: u64 = 20_000_000;
+
+// moves starting from `sites`
+fn random_moves(gridstructure: &GridStructure, sites: &[usize], count: usize) -> Vec<(u32, u32)> {
+    let mut rng = SmallRng::seed_from_u64(42);
+    (0..count)
+        .map(|_| {
+            let site = sites[rng.gen_range(0..sites.len())];
+            let neighbors = gridstructure.nn(site as u32);
+            (site as u32, neighbors[rng.gen_range(0..neighbors.len())])
+        })
+        .collect()
+}
+
+fn bench_pair_lookup(
+    gridstructure: &GridStructure,
+    hashed: &FnvHashMap<u64, [[u32; 7]; 2]>,
+    sites: &[usize],
+    name: &str,
+) {
+    let moves = random_moves(gridstructure, sites, 1 << 20);
+
+    let start = Instant::now();
+    let mut sum = 0_u64;
+    for (move_from, move_to) in moves.iter().cycle().take(LOOKUPS) {
+        let no_int = hashed[&(std::cmp::min(*move_from, *move_to) as u64
+            + ((std::cmp::max(*move_from, *move_to) as u64) << 32))];
+        let (from_change, to_change) = if move_to > move_from {
+            (no_int[0], no_int[1])
+        } else {
+            (no_int[1], no_int[0])
+        };
+        sum += (from_change[0] + to_change[6]) as u64;
+    }
+    black_box(sum);
+    let hashed_time = start.elapsed();
+
+    let start = Instant::now();
+    let mut sum = 0_u64;
+    for (move_from, move_to) in moves.iter().cycle().take(LOOKUPS) {
+        let (from_change, to_change) = gridstructure
+            .no_int_nn_from_move(*move_from, *move_to)
+            .unwrap();
+        sum += (from_change[0] + to_change[6]) as u64;
+    }
+    black_box(sum);
+    let dense_time = start.elapsed();
+
+    println!(
+        "pair lookup {}: hashed {:.2} ns, dense {:.2} ns ({:.2}x)",
+        name,
+        hashed_time.as_nanos() as f64 / LOOKUPS as f64,
+        dense_time.as_nanos() as f64 / LOOKUPS as f64,
+        hashed_time.as_secs_f64() / dense_time.as_secs_f64()
+    );
+}
+
+// CN energy change of moving the atom at `move_from` to `move_to`
+#[inline]
+fn cn_move_energy(
+    complet_energy: &[i64],
+    cn: &[usize],
+    occupied: &[bool],
+    from_change: &[u32],
+    to_change: &[u32],
+    move_from: u32,
+    move_to: u32,
+) -> i64 {
+    let mut energy =
+        complet_energy[cn[move_to as usize] - 1] - complet_energy[cn[move_from as usize]];
+    for site in from_change.iter().filter(|x| occupied[**x as usize]) {
+        let cn = cn[*site as usize];
+        energy += complet_energy[cn - 1] - complet_energy[cn];
+    }
+    for site in to_change.iter().filter(|x| occupied[**x as usize]) {
+        let cn = cn[*site as usize];
+        energy += complet_energy[cn + 1] - complet_energy[cn];
+    }
+    energy
+}
+
+/// Proposes moves of random surface atoms of a spherical particle to a random neighbor and
+/// computes the CN energy change of those to empty sites, the work of an iteration apart from
+/// accepting the move.
+fn bench_cn_energy_path(
+    gridstructure: &GridStructure,
+    hashed_nn: &FnvHashMap<u32, [u32; 12]>,
+    hashed: &FnvHashMap<u64, [[u32; 7]; 2]>,
+    particle_sites: &[usize],
+) {
+    let complet_energy: Vec<i64> = (0..=gridstructure.cn() as i64)
+        .map(|cn| 2000 - 170 * cn)
+        .collect();
+    let mut occupied = vec![false; gridstructure.nsites()];
+    for site in particle_sites {
+        occupied[*site] = true;
+    }
+    let cn: Vec<usize> = (0..gridstructure.nsites() as u32)
+        .map(|site| {
+            gridstructure
+                .nn(site)
+                .iter()
+                .filter(|x| occupied[**x as usize])
+                .count()
+        })
+        .collect();
+    let surface: Vec<u32> = particle_sites
+        .iter()
+        .map(|x| *x as u32)
+        .filter(|x| cn[*x as usize] < gridstructure.cn())
+        .collect();
+    let mut rng = SmallRng::seed_from_u64(42);
+    let proposals: Vec<(u32, usize)> = (0..1 << 20)
+        .map(|_| {
+            (
+                surface[rng.gen_range(0..surface.len())],
+                rng.gen_range(0..gridstructure.cn()),
+            )
+        })
+        .collect();
+
+    let start = Instant::now();
+    let mut hashed_sum = 0_i64;
+    for (move_from, slot) in proposals.iter().cycle().take(ITERATIONS as usize) {
+        let move_to = hashed_nn[move_from][*slot];
+        if occupied[move_to as usize] {
+            continue;
+        }
+        let no_int = hashed[&(std::cmp::min(*move_from, move_to) as u64
+            + ((std::cmp::max(*move_from, move_to) as u64) << 32))];
+        let (from_change, to_change) = if move_to > *move_from {
+            (&no_int[0], &no_int[1])
+        } else {
+            (&no_int[1], &no_int[0])
+        };
+        hashed_sum += cn_move_energy(
+            &complet_energy,
+            &cn,
+            &occupied,
+            from_change,
+            to_change,
+            *move_from,
+            move_to,
+        );
+    }
+    let hashed_time = start.elapsed();
+
+    let start = Instant::now();
+    let mut dense_sum = 0_i64;
+    for (move_from, slot) in proposals.iter().cycle().take(ITERATIONS as usize) {
+        let move_to = gridstructure.nn(*move_from)[*slot];
+        if occupied[move_to as usize] {
+            continue;
+        }
+        let (from_change, to_change) = gridstructure
+            .no_int_nn_from_move(*move_from, move_to)
+            .unwrap();
+        dense_sum += cn_move_energy(
+            &complet_energy,
+            &cn,
+            &occupied,
+            from_change,
+            to_change,
+            *move_from,
+            move_to,
+        );
+    }
+    let dense_time = start.elapsed();
+    assert_eq!(black_box(hashed_sum), black_box(dense_sum));
+
+    println!(
+        "Cn energy path: hashed {:.3e} iterations/s, dense {:.3e} iterations/s ({:.2}x)",
+        ITERATIONS as f64 / hashed_time.as_secs_f64(),
+        ITERATIONS as f64 / dense_time.as_secs_f64(),
+        hashed_time.as_secs_f64() / dense_time.as_secs_f64()
+    );
+}
+
+fn bench_cn_simulation(gridstructure: Arc<GridStructure>) {
+    let save_folder = tempfile::tempdir().unwrap();
+    let mut complet_energy = vec![0; gridstructure.cn() + 1];
+    for (cn, e) in complet_energy.iter_mut().enumerate() {
+        *e = 2000 - 170 * cn as i64;
+    }
+    let mut sim = Simulation::new(
+        AtomNames {
+            atoms: vec!["Pt".to_string()],
+            support: None,
+            adsorbates: Vec::new(),
+        },
+        Structure::CreateCluster(CreateStructure {
+            atoms: vec![("Pt".to_string(), 2000)],
+            support_vector: None,
+            support_atom_name: None,
+        }),
+        EnergyInput::Cn(EnergyValues {
+            complet_energy,
+            co_ads_energy: None,
+        }),
+        gridstructure,
+        SimulationOptions {
+            niter: ITERATIONS,
+            temperature: 300.,
+            start_temperature: 1500.,
+            save_folder: format!("{}/", save_folder.path().to_str().unwrap()),
+            terms: (),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    let start = Instant::now();
+    sim.run();
+    let time = start.elapsed();
+    println!(
+        "Cn simulation, dense tables: {:.3e} iterations/s",
+        ITERATIONS as f64 / time.as_secs_f64()
+    );
+}
+
+fn main() {
+    let gridstructure =
+        Arc::new(GridStructure::generate(Lattice::Fcc, 3.92, [30, 30, 30]).unwrap());
+
+    let mut hashed_nn: FnvHashMap<u32, [u32; 12]> = FnvHashMap::default();
+    let mut hashed: FnvHashMap<u64, [[u32; 7]; 2]> = FnvHashMap::default();
+    for site in 0..gridstructure.nsites() as u32 {
+        hashed_nn.insert(site, gridstructure.nn(site).try_into().unwrap());
+        for neighbor in gridstructure.nn(site).iter().filter(|x| **x > site) {
+            let (from, to) = gridstructure.no_int_nn_from_move(site, *neighbor).unwrap();
+            hashed.insert(
+                site as u64 + ((*neighbor as u64) << 32),
+                [from.try_into().unwrap(), to.try_into().unwrap()],
+            );
+        }
+    }
+    let all_sites: Vec<usize> = (0..gridstructure.nsites()).collect();
+    // the moves of a simulation stay at the surface of the particle in the center of the grid
+    let center = gridstructure.xsites_positions[gridstructure.nsites() / 2];
+    let particle_sites: Vec<usize> = all_sites
+        .iter()
+        .copied()
+        .filter(|site| {
+            let xyz = gridstructure.xsites_positions[*site];
+            (0..3).map(|i| (xyz[i] - center[i]).powi(2)).sum::<f64>() < 15_f64.powi(2)
+        })
+        .collect();
+    bench_pair_lookup(&gridstructure, &hashed, &all_sites, "whole grid");
+    bench_pair_lookup(&gridstructure, &hashed, &particle_sites, "particle");
+    bench_cn_energy_path(&gridstructure, &hashed_nn, &hashed, &particle_sites);
+
+    bench_cn_simulation(gridstructure);
+}
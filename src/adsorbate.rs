@@ -0,0 +1,407 @@
+use super::energy_input::Unit;
+use super::{Sites, MAX_ADSORBATES};
+use anyhow::{bail, Context};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// Adsorbate species as read from JSON, the energies in milli eV unless `unit` is `"eV"`:
+/// ```json
+/// {
+///   "species": {
+///     "CO": {
+///       "chemical_potential": -1500,
+///       "bond_energy": [-900, -880, -860, -840, -820, -800, -760, -720, -680, -640, -600, -560, -520],
+///       "repulsion": {"CO": 120, "O": 60}
+///     },
+///     "O": {"chemical_potential": -2800, "bond_energy": {"Pt": [-1400, -1380, -1360, -1340, -1320, -1300, -1250, -1200, -1150, -1100, -1050, -1000, -950]}}
+///   }
+/// }
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AdsorbateInput {
+    #[serde(default)]
+    pub unit: Unit,
+    pub species: BTreeMap<String, SpeciesInput>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct SpeciesInput {
+    /// Chemical potential of the species in the gas, relative to the reference of the bond
+    /// energies.
+    pub chemical_potential: f64,
+    /// Energy of the bond to a metal atom by the CN of the metal atom from zero to the lattice
+    /// CN, for all elements or by element.
+    pub bond_energy: BondEnergyInput,
+    /// Energy of two adsorbates on neighboring sites by the other species, zero for species left
+    /// out. Given for one of the two species or for both with the same value.
+    #[serde(default)]
+    pub repulsion: BTreeMap<String, f64>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum BondEnergyInput {
+    All(Vec<f64>),
+    ByElement(BTreeMap<String, Vec<f64>>),
+}
+
+impl AdsorbateInput {
+    /// Names of the species, in the order of their occupancy codes.
+    pub fn names(&self) -> anyhow::Result<Vec<String>> {
+        if self.species.is_empty() || self.species.len() > MAX_ADSORBATES {
+            bail!(
+                "adsorbates need between 1 and {} species but got {}",
+                MAX_ADSORBATES,
+                self.species.len()
+            );
+        }
+        Ok(self.species.keys().cloned().collect())
+    }
+}
+
+/// Adsorbates on the empty sites next to the particle as a lattice gas in contact with a gas
+/// reservoir. An adsorbate on a site binds to every metal neighbor with an energy by the element
+/// and CN of that neighbor and repels adsorbates on neighboring sites. Adsorption and desorption
+/// happen at the chemical potential of the species, which is subtracted from the energy of every
+/// adsorbate, so the energy of the simulation is the grand potential.
+#[derive(Clone, Debug)]
+pub struct Adsorbates {
+    /// By species, element indexed like `AtomNames::atoms` and CN of the metal atom.
+    pub bond_energy: Vec<Vec<Vec<i64>>>,
+    /// By the species of both adsorbates.
+    pub repulsion: Vec<Vec<i64>>,
+    pub chemical_potential: Vec<i64>,
+    /// Fraction of the iterations trying an adsorption or desorption.
+    pub ratio: f64,
+}
+
+impl Adsorbates {
+    /// Energies of the species of `input` for the elements `atoms` on a lattice of CN `cn`.
+    pub fn new(
+        input: &AdsorbateInput,
+        atoms: &[String],
+        cn: usize,
+        ratio: f64,
+    ) -> anyhow::Result<Adsorbates> {
+        let names = input.names()?;
+        let unit = input.unit;
+        let mut bond_energy = Vec::with_capacity(names.len());
+        let mut chemical_potential = Vec::with_capacity(names.len());
+        let mut repulsion = vec![vec![None; names.len()]; names.len()];
+        for (i, (name, species)) in input.species.iter().enumerate() {
+            chemical_potential.push(unit.to_milli_ev(
+                &format!("{}.chemical_potential", name),
+                species.chemical_potential,
+            )?);
+            let by_element = atoms
+                .iter()
+                .map(|atom| {
+                    let (key, values) = match &species.bond_energy {
+                        BondEnergyInput::All(values) => (format!("{}.bond_energy", name), values),
+                        BondEnergyInput::ByElement(by_element) => {
+                            let key = format!("{}.bond_energy.{}", name, atom);
+                            let values = by_element
+                                .get(atom)
+                                .with_context(|| format!("adsorbates have no {}", key))?;
+                            (key, values)
+                        }
+                    };
+                    if values.len() != cn + 1 {
+                        bail!(
+                            "{} has {} values but needs one for every CN from 0 to {}",
+                            key,
+                            values.len(),
+                            cn
+                        );
+                    }
+                    values
+                        .iter()
+                        .map(|value| unit.to_milli_ev(&key, *value))
+                        .collect::<anyhow::Result<Vec<i64>>>()
+                })
+                .collect::<anyhow::Result<_>>()?;
+            bond_energy.push(by_element);
+            if let BondEnergyInput::ByElement(by_element) = &species.bond_energy {
+                if let Some(element) = by_element.keys().find(|x| !atoms.contains(x)) {
+                    bail!(
+                        "{}.bond_energy has {} which is not one of the elements {:?}",
+                        name,
+                        element,
+                        atoms
+                    );
+                }
+            }
+
+            for (other, value) in &species.repulsion {
+                let key = format!("{}.repulsion.{}", name, other);
+                let j = names.iter().position(|x| x == other).with_context(|| {
+                    format!("{} is not one of the adsorbates {:?}", other, names)
+                })?;
+                let value = unit.to_milli_ev(&key, *value)?;
+                for (a, b) in [(i, j), (j, i)] {
+                    match repulsion[a][b] {
+                        Some(given) if given != value => bail!(
+                            "the repulsion of {} and {} is given twice with different values",
+                            name,
+                            other
+                        ),
+                        _ => repulsion[a][b] = Some(value),
+                    }
+                }
+            }
+        }
+        Ok(Adsorbates {
+            bond_energy,
+            repulsion: repulsion
+                .into_iter()
+                .map(|row| row.into_iter().map(Option::unwrap_or_default).collect())
+                .collect(),
+            chemical_potential,
+            ratio,
+        })
+    }
+
+    pub fn species(&self) -> usize {
+        self.chemical_potential.len()
+    }
+
+    /// Energy change of an adsorbate of `species` adsorbing on the empty `site`, its bonds to
+    /// the metal neighbors and repulsion from the adsorbate neighbors minus the chemical
+    /// potential. The negative is the change of its desorption.
+    pub fn adsorption_energy(&self, sites: &Sites, site: u32, species: usize) -> i64 {
+        let bond_energy = &self.bond_energy[species];
+        let repulsion = &self.repulsion[species];
+        let mut energy = -self.chemical_potential[species];
+        for neighbor in sites.gridstructure.nn(site) {
+            let atom = sites.atom(*neighbor);
+            if atom.is_metal() {
+                energy += bond_energy[atom.element()][atom.cn_metal];
+            } else if atom.is_adsorbate() {
+                energy += repulsion[atom.adsorbate()];
+            }
+        }
+        energy
+    }
+
+    /// Energy of all adsorbates of a start structure.
+    pub fn total_energy(&self, sites: &Sites) -> i64 {
+        (0..sites.atom_pos.len() as u32)
+            .filter(|site| sites.atom(*site).is_adsorbate())
+            .map(|site| {
+                let species = sites.atom(site).adsorbate();
+                let mut energy = self.adsorption_energy(sites, site, species);
+                // every repulsion is counted from the adsorbate with the smaller index
+                for neighbor in sites.gridstructure.nn(site) {
+                    let atom = sites.atom(*neighbor);
+                    if *neighbor < site && atom.is_adsorbate() {
+                        energy -= self.repulsion[species][atom.adsorbate()];
+                    }
+                }
+                energy
+            })
+            .sum()
+    }
+
+    /// Bond energies of the adsorbates around `site` to a metal atom of `element` and CN `cn`
+    /// there.
+    #[inline]
+    fn bonds_of(&self, sites: &Sites, site: u32, element: usize, cn: usize) -> i64 {
+        sites
+            .gridstructure
+            .nn(site)
+            .iter()
+            .map(|x| sites.atom(*x))
+            .filter(|x| x.is_adsorbate())
+            .map(|x| self.bond_energy[x.adsorbate()][element][cn])
+            .sum()
+    }
+
+    /// Change of the adsorbate energies when the metal atom at `move_from` moves to the empty
+    /// neighbor site `move_to`: its own bonds and those of the metal neighbors whose CN changes.
+    pub fn move_energy_diff(&self, sites: &Sites, move_from: u32, move_to: u32) -> i64 {
+        let from = sites.atom(move_from);
+        let element = from.element();
+        let from_neighbors = sites.gridstructure.nn(move_from);
+        let to_neighbors = sites.gridstructure.nn(move_to);
+        let mut energy_diff_1000 =
+            self.bonds_of(sites, move_to, element, sites.atom(move_to).cn_metal - 1)
+                - self.bonds_of(sites, move_from, element, from.cn_metal);
+        for o in from_neighbors.iter().filter(|x| !to_neighbors.contains(x)) {
+            let atom = sites.atom(*o);
+            if atom.is_metal() {
+                energy_diff_1000 += self.bonds_of(sites, *o, atom.element(), atom.cn_metal - 1)
+                    - self.bonds_of(sites, *o, atom.element(), atom.cn_metal);
+            }
+        }
+        for o in to_neighbors
+            .iter()
+            .filter(|x| **x != move_from && !from_neighbors.contains(x))
+        {
+            let atom = sites.atom(*o);
+            if atom.is_metal() {
+                energy_diff_1000 += self.bonds_of(sites, *o, atom.element(), atom.cn_metal + 1)
+                    - self.bonds_of(sites, *o, atom.element(), atom.cn_metal);
+            }
+        }
+        energy_diff_1000
+    }
+
+    /// Change of the bond energies when the elements of the atoms at `site_a` and `site_b` are
+    /// swapped.
+    pub fn swap_energy_diff(&self, sites: &Sites, site_a: u32, site_b: u32) -> i64 {
+        let (a, b) = (sites.atom(site_a), sites.atom(site_b));
+        self.bonds_of(sites, site_a, b.element(), a.cn_metal)
+            - self.bonds_of(sites, site_a, a.element(), a.cn_metal)
+            + self.bonds_of(sites, site_b, a.element(), b.cn_metal)
+            - self.bonds_of(sites, site_b, b.element(), b.cn_metal)
+    }
+
+    /// Whether moving the metal atom at `move_from` to `move_to` leaves an adsorbate without
+    /// metal neighbor, which is not allowed as it could not adsorb there.
+    pub fn strands_adsorbate(sites: &Sites, move_from: u32, move_to: u32) -> bool {
+        sites.gridstructure.nn(move_from).iter().any(|x| {
+            let atom = sites.atom(*x);
+            atom.is_adsorbate()
+                && atom.cn_metal == 1
+                && !sites.gridstructure.nn(*x).contains(&move_to)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::energy::{EnergyInput, PairBondEnergy};
+    use crate::test_support::{fcc_grid, particle, recomputed_energy, run_and_recompute};
+    use crate::{Simulation, SimulationOptions, EMPTY};
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    const ADSORBATES: &str = r#"{
+        "species": {
+            "CO": {
+                "chemical_potential": -1500,
+                "bond_energy": [-900, -880, -860, -840, -820, -800, -760, -720, -680, -640, -600, -560, -520],
+                "repulsion": {"CO": 120, "O": 60}
+            },
+            "O": {
+                "chemical_potential": -2800,
+                "bond_energy": {
+                    "Pt": [-1400, -1380, -1360, -1340, -1320, -1300, -1250, -1200, -1150, -1100, -1050, -1000, -950],
+                    "Pd": [-1500, -1470, -1440, -1410, -1380, -1350, -1300, -1250, -1200, -1150, -1100, -1050, -1000]
+                }
+            }
+        }
+    }"#;
+
+    fn adsorbates() -> Adsorbates {
+        let input: AdsorbateInput = serde_json::from_str(ADSORBATES).unwrap();
+        Adsorbates::new(&input, &["Pt".into(), "Pd".into()], 12, 0.2).unwrap()
+    }
+
+    /// Checks that the adsorption sites are the empty sites next to a metal atom and the
+    /// adsorbate list the sites of the adsorbates.
+    fn check_site_lists<E: crate::energy_model::EnergyModel>(sim: &Simulation<E>) {
+        let sorted = |sites: std::slice::Iter<'_, u32>| {
+            let mut sites: Vec<u32> = sites.copied().collect();
+            sites.sort_unstable();
+            sites
+        };
+        let where_atom = |is: fn(&crate::AtomPosition) -> bool| {
+            (0..sim.atom_pos.len() as u32)
+                .filter(|site| is(&sim.atom_pos[*site as usize]))
+                .collect::<Vec<u32>>()
+        };
+        assert_eq!(
+            sorted(sim.adsorption_sites.iter()),
+            where_atom(|x| x.occ == EMPTY && x.cn_metal > 0)
+        );
+        assert_eq!(
+            sorted(sim.adsorbate_list.iter()),
+            where_atom(|x| x.is_adsorbate())
+        );
+    }
+
+    #[test]
+    fn repulsion_is_symmetric_and_bond_energies_by_element() {
+        let adsorbates = adsorbates();
+        assert_eq!(adsorbates.repulsion, vec![vec![120, 60], vec![60, 0]]);
+        assert_eq!(adsorbates.chemical_potential, vec![-1500, -2800]);
+        assert_eq!(adsorbates.bond_energy[0][0], adsorbates.bond_energy[0][1]);
+        assert_eq!(
+            (
+                adsorbates.bond_energy[1][0][12],
+                adsorbates.bond_energy[1][1][12]
+            ),
+            (-950, -1000)
+        );
+    }
+
+    #[test]
+    fn energy_after_exchanges_and_hops_matches_total_energy() {
+        let mut sim = particle(
+            "adsorbates",
+            fcc_grid([8, 8, 8]),
+            &[("Pt", 40), ("Pd", 20)],
+            true,
+            EnergyInput::PairBond(PairBondEnergy {
+                bond_energy: vec![vec![-880, -900], vec![-900, -850]],
+                atom_energy: vec![3250, 3000],
+            }),
+            SimulationOptions {
+                niter: 20000,
+                temperature: 600.,
+                start_temperature: 1200.,
+                support_e: -170,
+                adsorbates: Some(adsorbates()),
+                ..Default::default()
+            },
+        );
+        check_site_lists(&sim);
+        let adsorbates = sim.adsorbates.clone().unwrap();
+        let mut rng = SmallRng::seed_from_u64(23);
+        for step in 0..600 {
+            match step % 4 {
+                0 | 1 => {
+                    let site = sim.adsorption_sites.choose_random_site(&mut rng);
+                    let species = rng.gen_range(0..adsorbates.species());
+                    let energy1000_diff = adsorbates.adsorption_energy(&sim.sites(), site, species);
+                    sim.adsorb(site, species, energy1000_diff);
+                }
+                2 if !sim.adsorbate_list.is_empty() => {
+                    let site = sim.adsorbate_list.choose_random_site(&mut rng);
+                    let species = sim.atom_pos[site as usize].adsorbate();
+                    let energy1000_diff =
+                        -adsorbates.adsorption_energy(&sim.sites(), site, species);
+                    sim.desorb(site, energy1000_diff);
+                }
+                _ => {
+                    let (move_from, move_to) = loop {
+                        let (move_from, move_to, _) =
+                            sim.possible_moves.choose_random_item_mc(&mut rng).unwrap();
+                        if !sim.strands_adsorbate(move_from, move_to) {
+                            break (move_from, move_to);
+                        }
+                    };
+                    assert_eq!(sim.atom_pos[move_to as usize].occ, EMPTY);
+                    let energy1000_diff = sim.energy_change_by_move(move_from, move_to);
+                    sim.perform_move(move_from, move_to, energy1000_diff, true);
+                    sim.update_possible_moves(move_from, move_to);
+                }
+            }
+            assert_eq!(
+                sim.total_energy_1000,
+                recomputed_energy(&sim),
+                "step {}",
+                step
+            );
+            check_site_lists(&sim);
+        }
+        assert!(!sim.adsorbate_list.is_empty());
+
+        run_and_recompute(&mut sim);
+        check_site_lists(&sim);
+    }
+}
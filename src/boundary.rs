@@ -0,0 +1,254 @@
+use super::GridStructure;
+use fnv::FnvHashMap;
+use std::collections::VecDeque;
+
+// difference vectors closer than this in Angstrom are the same
+const TOLERANCE: f64 = 1e-3;
+
+/// What happens when an occupied site comes closer to the cell boundary than the margin.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BoundaryAction {
+    /// Translate the whole occupancy by a lattice vector, so the particle is back at the center.
+    Recenter,
+    /// End the simulation with an error.
+    Stop,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Boundary {
+    /// Smallest allowed distance of an occupied site to the faces of the cell in Angstrom.
+    pub margin: f64,
+    pub action: BoundaryAction,
+}
+
+/// Smallest distance of one of the sites to the faces of the cell, with all positions wrapped into
+/// the cell.
+pub(crate) fn distance_to_boundary(
+    gridstructure: &GridStructure,
+    sites: impl Iterator<Item = u32>,
+) -> f64 {
+    let plane_distances = gridstructure.cell.plane_distances();
+    sites
+        .map(|site| {
+            let fractional = gridstructure
+                .cell
+                .fractional(gridstructure.xsites_positions[site as usize]);
+            (0..3)
+                .map(|v| {
+                    let f = fractional[v] - fractional[v].floor();
+                    f.min(1. - f) * plane_distances[v]
+                })
+                .fold(f64::INFINITY, f64::min)
+        })
+        .fold(f64::INFINITY, f64::min)
+}
+
+/// Center of mass of the sites, unwrapped along the neighbor lists starting from the first one so
+/// that particles spanning more than half of the cell are not torn apart. Sites not connected to
+/// the first one count with their periodic image closest to it.
+fn center_of_mass(gridstructure: &GridStructure, sites: &[u32]) -> [f64; 3] {
+    let positions = &gridstructure.xsites_positions;
+    let diff = |a: u32, b: u32| {
+        gridstructure
+            .cell
+            .minimum_image([0, 1, 2].map(|d| positions[b as usize][d] - positions[a as usize][d]))
+    };
+    let mut unwrapped: FnvHashMap<u32, [f64; 3]> =
+        sites.iter().map(|site| (*site, [f64::NAN; 3])).collect();
+    unwrapped.insert(sites[0], [0.; 3]);
+    let mut queue = VecDeque::from([sites[0]]);
+    while let Some(site) = queue.pop_front() {
+        let offset = unwrapped[&site];
+        for &neighbor in gridstructure.nn(site) {
+            if let Some(neighbor_offset) = unwrapped.get_mut(&neighbor) {
+                if neighbor_offset[0].is_nan() {
+                    let step = diff(site, neighbor);
+                    *neighbor_offset = [0, 1, 2].map(|d| offset[d] + step[d]);
+                    queue.push_back(neighbor);
+                }
+            }
+        }
+    }
+    let mut sum = [0_f64; 3];
+    for (site, offset) in unwrapped.iter() {
+        let offset = if offset[0].is_nan() {
+            diff(sites[0], *site)
+        } else {
+            *offset
+        };
+        (0..3).for_each(|d| sum[d] += offset[d]);
+    }
+    let reference = positions[sites[0] as usize];
+    [0, 1, 2].map(|d| reference[d] + sum[d] / sites.len() as f64)
+}
+
+/// Site permutation of the grid which moves `from` onto `to`, `None` if the translation between
+/// them does not map the grid onto itself. The map is built along the neighbor lists, a neighbor
+/// of a mapped site goes to the neighbor of the image with the same difference vector.
+fn translation_map(gridstructure: &GridStructure, from: u32, to: u32) -> Option<Vec<u32>> {
+    let positions = &gridstructure.xsites_positions;
+    let diff = |a: u32, b: u32| {
+        gridstructure
+            .cell
+            .minimum_image([0, 1, 2].map(|d| positions[b as usize][d] - positions[a as usize][d]))
+    };
+    let mut map = vec![u32::MAX; gridstructure.nsites()];
+    let mut is_image = vec![false; gridstructure.nsites()];
+    map[from as usize] = to;
+    is_image[to as usize] = true;
+    let mut queue = VecDeque::from([from]);
+    while let Some(site) = queue.pop_front() {
+        let image = map[site as usize];
+        for &neighbor in gridstructure.nn(site) {
+            let step = diff(site, neighbor);
+            let neighbor_image = *gridstructure.nn(image).iter().find(|&&x| {
+                let image_step = diff(image, x);
+                (0..3).all(|d| (image_step[d] - step[d]).abs() < TOLERANCE)
+            })?;
+            if map[neighbor as usize] == u32::MAX {
+                if is_image[neighbor_image as usize] {
+                    return None;
+                }
+                map[neighbor as usize] = neighbor_image;
+                is_image[neighbor_image as usize] = true;
+                queue.push_back(neighbor);
+            } else if map[neighbor as usize] != neighbor_image {
+                return None;
+            }
+        }
+    }
+    map.iter().all(|x| *x != u32::MAX).then_some(map)
+}
+
+/// Site permutation moving the center of mass of the occupied sites as close to the center of the
+/// cell as the lattice allows, and the translation vector it applies.
+pub(crate) fn recentering_map(
+    gridstructure: &GridStructure,
+    occupied: &[u32],
+) -> Option<(Vec<u32>, [f64; 3])> {
+    let positions = &gridstructure.xsites_positions;
+    let cell = &gridstructure.cell;
+    let center = cell.cartesian([0.5; 3]);
+    let center_of_mass = center_of_mass(gridstructure, occupied);
+    let anchor = occupied[0];
+    let target = [0, 1, 2].map(|d| positions[anchor as usize][d] + center[d] - center_of_mass[d]);
+    let distance_to_target = |site: usize| {
+        let diff = cell.minimum_image([0, 1, 2].map(|d| positions[site][d] - target[d]));
+        diff.iter().map(|x| x * x).sum::<f64>()
+    };
+    let mut candidates: Vec<usize> = (0..gridstructure.nsites()).collect();
+    candidates.sort_by(|a, b| distance_to_target(*a).total_cmp(&distance_to_target(*b)));
+    // sites of another sublattice are not images under a translation, the closest site of the
+    // same sublattice is among the next few
+    candidates
+        .into_iter()
+        .take(2 * gridstructure.cn() + 1)
+        .find_map(|site| {
+            let map = translation_map(gridstructure, anchor, site as u32)?;
+            let to_target = cell.minimum_image([0, 1, 2].map(|d| positions[site][d] - target[d]));
+            let translation = [0, 1, 2].map(|d| center[d] - center_of_mass[d] + to_target[d]);
+            Some((map, translation))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::energy::{AlloyEnergy, EnergyValues};
+    use crate::test_support::{fcc_grid, particle, recomputed_energy};
+    use crate::{Simulation, SimulationOptions};
+
+    fn cn_histograms(sim: &Simulation<AlloyEnergy>) -> (Vec<u32>, Vec<Vec<u32>>) {
+        let cn = sim.gridstructure.cn();
+        let mut cn_dict = vec![0; cn + 1];
+        let mut by_element = vec![vec![0; cn + 1]; 2];
+        for site in sim.onlyocc.iter() {
+            let atom = &sim.atom_pos[*site as usize];
+            cn_dict[atom.cn_metal] += 1;
+            by_element[atom.element()][atom.cn_metal] += 1;
+        }
+        (cn_dict, by_element)
+    }
+
+    fn possible_moves(sim: &Simulation<AlloyEnergy>, map: &[u32]) -> Vec<(u32, u32)> {
+        let mut moves: Vec<(u32, u32)> = sim
+            .possible_moves
+            .moves
+            .iter()
+            .map(|(from, to, _)| (map[*from as usize], map[*to as usize]))
+            .collect();
+        moves.sort_unstable();
+        moves
+    }
+
+    #[test]
+    fn recentering_keeps_energy_moves_and_cn_histograms() {
+        let gridstructure = fcc_grid([8, 8, 8]);
+        let mut sim = particle(
+            "recenter",
+            gridstructure.clone(),
+            &[("Pt", 40), ("Pd", 20)],
+            false,
+            AlloyEnergy {
+                cn_energy: vec![
+                    EnergyValues {
+                        complet_energy: (0..=12).map(|cn| 3700 - 370 * cn).collect(),
+                        co_ads_energy: None,
+                    },
+                    EnergyValues {
+                        complet_energy: (0..=12).map(|cn| 3400 - 340 * cn).collect(),
+                        co_ads_energy: None,
+                    },
+                ],
+                mixing_energy: vec![vec![0, -40], vec![-40, 0]],
+            },
+            SimulationOptions::default(),
+        );
+
+        // move the particle across the boundary of the cell
+        let positions = &gridstructure.xsites_positions;
+        let anchor = *sim.onlyocc.iter().next().unwrap();
+        let target = positions[anchor as usize].map(|x| x - 11.);
+        let distance = |site: usize| {
+            let diff = gridstructure
+                .cell
+                .minimum_image([0, 1, 2].map(|d| positions[site][d] - target[d]));
+            diff.iter().map(|x| x * x).sum::<f64>()
+        };
+        let shifted = (0..gridstructure.nsites())
+            .min_by(|a, b| distance(*a).total_cmp(&distance(*b)))
+            .unwrap();
+        let shift = translation_map(&gridstructure, anchor, shifted as u32).unwrap();
+        sim.move_sites(&shift);
+        assert_eq!(sim.total_energy_1000, recomputed_energy(&sim));
+
+        let energy = sim.total_energy_1000;
+        let cn_dict = sim.cn_dict.clone();
+        let cn_dict_by_element = sim.cn_dict_by_element.clone();
+        assert_eq!(
+            cn_histograms(&sim),
+            (cn_dict.clone(), cn_dict_by_element.clone())
+        );
+        let identity: Vec<u32> = (0..gridstructure.nsites() as u32).collect();
+        let moves = possible_moves(&sim, &identity);
+
+        let boundary = Boundary {
+            margin: 3.,
+            action: BoundaryAction::Recenter,
+        };
+        let (map, _) = sim.keep_off_boundary(boundary).unwrap().unwrap();
+        assert!(distance_to_boundary(&gridstructure, sim.onlyocc.iter().copied()) >= 3.);
+        assert_eq!(sim.total_energy_1000, energy);
+        assert_eq!(recomputed_energy(&sim), energy);
+        assert_eq!(sim.cn_dict, cn_dict);
+        assert_eq!(sim.cn_dict_by_element, cn_dict_by_element);
+        assert_eq!(cn_histograms(&sim), (cn_dict, cn_dict_by_element));
+        let mut mapped = moves
+            .iter()
+            .map(|(from, to)| (map[*from as usize], map[*to as usize]))
+            .collect::<Vec<_>>();
+        mapped.sort_unstable();
+        assert_eq!(possible_moves(&sim, &identity), mapped);
+        assert!(sim.keep_off_boundary(boundary).unwrap().is_none());
+    }
+}
@@ -0,0 +1,162 @@
+use anyhow::bail;
+
+/// Periodic simulation cell. The rows of the matrix are the cell vectors a, b and c in Angstrom,
+/// in the standard orientation with a along x and b in the xy plane, as chemfiles reads and
+/// writes triclinic cells.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cell {
+    matrix: [[f64; 3]; 3],
+    inverse: [[f64; 3]; 3],
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+impl Cell {
+    pub fn from_matrix(matrix: [[f64; 3]; 3]) -> anyhow::Result<Cell> {
+        let [a, b, c] = matrix;
+        // columns of the inverse, each perpendicular to two cell vectors
+        let columns = [cross(b, c), cross(c, a), cross(a, b)];
+        let volume = dot(a, columns[0]);
+        if !volume.is_finite() || volume.abs() < 1e-9 {
+            bail!("cell {:?} has no volume", matrix);
+        }
+        let inverse = [0, 1, 2].map(|row| [0, 1, 2].map(|col| columns[col][row] / volume));
+        Ok(Cell { matrix, inverse })
+    }
+
+    pub fn orthorhombic(lengths: [f64; 3]) -> anyhow::Result<Cell> {
+        Cell::from_matrix([
+            [lengths[0], 0., 0.],
+            [0., lengths[1], 0.],
+            [0., 0., lengths[2]],
+        ])
+    }
+
+    /// Cell from the lengths of a, b and c and the angles alpha (b, c), beta (a, c) and gamma
+    /// (a, b) in degrees.
+    pub fn from_lengths_angles(lengths: [f64; 3], angles: [f64; 3]) -> anyhow::Result<Cell> {
+        let [cos_alpha, cos_beta, cos_gamma] = angles.map(|x| x.to_radians().cos());
+        let sin_gamma = angles[2].to_radians().sin();
+        let c_y = (cos_alpha - cos_beta * cos_gamma) / sin_gamma;
+        let c_z = (1. - cos_beta * cos_beta - c_y * c_y).sqrt();
+        Cell::from_matrix([
+            [lengths[0], 0., 0.],
+            [lengths[1] * cos_gamma, lengths[1] * sin_gamma, 0.],
+            [lengths[2] * cos_beta, lengths[2] * c_y, lengths[2] * c_z],
+        ])
+    }
+
+    pub fn matrix(&self) -> [[f64; 3]; 3] {
+        self.matrix
+    }
+
+    pub fn lengths(&self) -> [f64; 3] {
+        self.matrix.map(|v| dot(v, v).sqrt())
+    }
+
+    /// Angles alpha, beta and gamma in degrees.
+    pub fn angles(&self) -> [f64; 3] {
+        let [a, b, c] = self.matrix;
+        let angle = |u: [f64; 3], v: [f64; 3]| {
+            (dot(u, v) / (dot(u, u) * dot(v, v)).sqrt())
+                .clamp(-1., 1.)
+                .acos()
+                .to_degrees()
+        };
+        [angle(b, c), angle(a, c), angle(a, b)]
+    }
+
+    pub fn volume(&self) -> f64 {
+        let [a, b, c] = self.matrix;
+        dot(a, cross(b, c)).abs()
+    }
+
+    /// Distances between neighboring lattice planes spanned by (b, c), (a, c) and (a, b).
+    pub fn plane_distances(&self) -> [f64; 3] {
+        [0, 1, 2].map(|v| {
+            1. / (0..3)
+                .map(|d| self.inverse[d][v].powi(2))
+                .sum::<f64>()
+                .sqrt()
+        })
+    }
+
+    /// Cell repeated `cells` times along each cell vector.
+    pub fn repeated(&self, cells: [u32; 3]) -> anyhow::Result<Cell> {
+        Cell::from_matrix([0, 1, 2].map(|v| self.matrix[v].map(|x| x * cells[v] as f64)))
+    }
+
+    pub fn is_orthorhombic(&self) -> bool {
+        (0..3).all(|row| (0..3).all(|col| row == col || self.matrix[row][col].abs() < 1e-9))
+    }
+
+    pub fn fractional(&self, xyz: [f64; 3]) -> [f64; 3] {
+        [0, 1, 2].map(|col| (0..3).map(|d| xyz[d] * self.inverse[d][col]).sum())
+    }
+
+    pub fn cartesian(&self, fractional: [f64; 3]) -> [f64; 3] {
+        [0, 1, 2].map(|d| (0..3).map(|v| fractional[v] * self.matrix[v][d]).sum())
+    }
+
+    /// The periodic image of a difference vector closest to the origin, exact for cells which are
+    /// not strongly skewed.
+    pub fn minimum_image(&self, diff: [f64; 3]) -> [f64; 3] {
+        self.cartesian(self.fractional(diff).map(|x| x - x.round()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: [f64; 3], b: [f64; 3]) {
+        assert!(
+            (0..3).all(|d| (a[d] - b[d]).abs() < 1e-9),
+            "{:?} {:?}",
+            a,
+            b
+        );
+    }
+
+    /// Grid files store the cell as lengths and angles, so a triclinic cell in the standard
+    /// orientation has to come back unchanged from them.
+    #[test]
+    fn triclinic_cell_survives_lengths_and_angles() {
+        let cell = Cell::from_lengths_angles([5., 6., 7.], [80., 95., 110.]).unwrap();
+        assert!(!cell.is_orthorhombic());
+        assert_close(cell.lengths(), [5., 6., 7.]);
+        assert_close(cell.angles(), [80., 95., 110.]);
+        let read = Cell::from_lengths_angles(cell.lengths(), cell.angles()).unwrap();
+        for v in 0..3 {
+            assert_close(read.matrix()[v], cell.matrix()[v]);
+        }
+    }
+
+    #[test]
+    fn fractional_and_cartesian_are_inverse() {
+        let cell = Cell::from_lengths_angles([5., 6., 7.], [80., 95., 110.]).unwrap();
+        let xyz = [1.3, -2.1, 4.7];
+        assert_close(cell.cartesian(cell.fractional(xyz)), xyz);
+        assert_close(cell.fractional(cell.matrix()[1]), [0., 1., 0.]);
+        assert!(
+            (cell.volume() - cell.plane_distances()[2] * 5. * 6. * 110_f64.to_radians().sin())
+                .abs()
+                < 1e-9
+        );
+    }
+
+    #[test]
+    fn flat_cell_is_rejected() {
+        assert!(Cell::from_matrix([[1., 0., 0.], [0., 1., 0.], [1., 1., 0.]]).is_err());
+    }
+}
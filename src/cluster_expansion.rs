@@ -0,0 +1,554 @@
+use super::energy_input::Unit;
+use super::GridStructure;
+use fnv::FnvHashSet;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+/// Largest difference in Angstrom between a distance of the input and one of the grid, ECI files
+/// usually give the distances rounded to two decimals.
+const DISTANCE_TOLERANCE: f64 = 1e-2;
+
+/// Largest cluster, a quadruplet.
+const MAX_CLUSTER_SIZE: usize = 4;
+
+/// Effective cluster interactions as read from JSON, the energies in milli eV unless `unit` is
+/// `"eV"`:
+/// ```json
+/// {
+///   "atom_energy": {"Pt": 0, "Ni": 120},
+///   "clusters": [
+///     {"distances": [2.77], "eci": {"Pt-Pt": -310, "Pt-Ni": -350, "Ni-Ni": -290}},
+///     {"distances": [2.77, 2.77, 2.77], "eci": {"Pt-Pt-Ni": 15, "Pt-Ni-Ni": 12}}
+///   ]
+/// }
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterExpansionInput {
+    #[serde(default)]
+    pub unit: Unit,
+    /// Energy of a single atom by its element, zero for elements left out.
+    #[serde(default)]
+    pub atom_energy: HashMap<String, f64>,
+    pub clusters: Vec<ClusterInput>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterInput {
+    /// Distances in Angstrom between all sites of the cluster, one for a pair, three for a
+    /// triplet and six for a quadruplet. Every set of sites with these distances is a cluster.
+    pub distances: Vec<f64>,
+    /// ECI by the elements on the cluster joined by "-" in any order, e.g. "Pt-Ni-Ni".
+    /// Decorations left out are zero.
+    pub eci: HashMap<String, f64>,
+}
+
+/// All clusters of one kind on the grid with their ECIs.
+struct Orbit {
+    size: usize,
+    distances: Vec<f64>,
+    /// ECI by the elements of the sites of a cluster, `e0 + n * e1 + n^2 * e2 ...` for `n`
+    /// elements, filled for every order of the sites.
+    eci: Vec<i64>,
+    /// Start of the clusters of every site in `others`.
+    offsets: Vec<u32>,
+    /// For every cluster of a site the other `size - 1` sites.
+    others: Vec<u32>,
+}
+
+impl Orbit {
+    /// The other sites of every cluster of `site`.
+    #[inline]
+    fn clusters(&self, site: u32) -> std::slice::ChunksExact<'_, u32> {
+        let start = self.offsets[site as usize] as usize;
+        let end = self.offsets[site as usize + 1] as usize;
+        self.others[start..end].chunks_exact(self.size - 1)
+    }
+}
+
+/// Cluster expansion of an alloy particle: the energy is the sum of the ECIs of all clusters
+/// whose sites are all occupied by metal atoms, plus the energy of every single atom. Empty
+/// sites end a cluster, so the pairs alone count the bonds like `PairBondEnergy`.
+#[derive(Clone)]
+pub struct ClusterExpansion {
+    /// Indexed like `AtomNames::atoms`.
+    pub atom_energy: Vec<i64>,
+    orbits: Arc<[Orbit]>,
+}
+
+impl std::fmt::Debug for ClusterExpansion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClusterExpansion")
+            .field("atom_energy", &self.atom_energy)
+            .field(
+                "clusters",
+                &self
+                    .orbits
+                    .iter()
+                    .map(|orbit| {
+                        (
+                            &orbit.distances,
+                            orbit.others.len() / (orbit.size * (orbit.size - 1)),
+                        )
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl ClusterExpansion {
+    /// Finds every cluster of the input on the grid, `atoms` are the elements like
+    /// `AtomNames::atoms`.
+    pub fn new(
+        input: &ClusterExpansionInput,
+        atoms: &[String],
+        gridstructure: &GridStructure,
+    ) -> anyhow::Result<ClusterExpansion> {
+        let element = |name: &str| {
+            atoms
+                .iter()
+                .position(|atom| atom == name)
+                .ok_or_else(|| anyhow::anyhow!("{} is not one of the elements {:?}", name, atoms))
+        };
+        let mut atom_energy = vec![0; atoms.len()];
+        for (name, energy) in &input.atom_energy {
+            atom_energy[element(name)?] = input
+                .unit
+                .to_milli_ev(&format!("atom_energy.{}", name), *energy)?;
+        }
+
+        let cutoff = input
+            .clusters
+            .iter()
+            .flat_map(|cluster| cluster.distances.iter())
+            .fold(0., |max: f64, x| max.max(*x))
+            + DISTANCE_TOLERANCE;
+        let within: Vec<Vec<u32>> = (0..gridstructure.nsites() as u32)
+            .map(|site| sites_within(gridstructure, site, cutoff))
+            .collect();
+
+        let mut orbits = Vec::with_capacity(input.clusters.len());
+        for cluster in &input.clusters {
+            let size = (2..=MAX_CLUSTER_SIZE)
+                .find(|size| size * (size - 1) / 2 == cluster.distances.len())
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "a cluster needs 1, 3 or 6 distances for a pair, triplet or quadruplet but \
+                         got {:?}",
+                        cluster.distances
+                    )
+                })?;
+            let mut distances = cluster.distances.clone();
+            distances.sort_by(f64::total_cmp);
+
+            let mut eci = vec![0; atoms.len().pow(size as u32)];
+            let mut given: Vec<Vec<usize>> = Vec::new();
+            for (decoration, value) in &cluster.eci {
+                let mut elements = decoration
+                    .split('-')
+                    .map(element)
+                    .collect::<anyhow::Result<Vec<usize>>>()?;
+                if elements.len() != size {
+                    anyhow::bail!(
+                        "the decoration {} of the cluster {:?} needs {} elements",
+                        decoration,
+                        cluster.distances,
+                        size
+                    );
+                }
+                elements.sort_unstable();
+                if given.contains(&elements) {
+                    anyhow::bail!(
+                        "the cluster {:?} has more than one ECI for {}",
+                        cluster.distances,
+                        decoration
+                    );
+                }
+                let value = input.unit.to_milli_ev(decoration, *value)?;
+                for (index, value_of_order) in eci.iter_mut().enumerate() {
+                    let mut order = decoration_elements(index, size, atoms.len());
+                    order.sort_unstable();
+                    if order == elements {
+                        *value_of_order = value;
+                    }
+                }
+                given.push(elements);
+            }
+
+            let mut clusters_of_site: Vec<Vec<u32>> = vec![Vec::new(); gridstructure.nsites()];
+            let mut members = Vec::with_capacity(size);
+            for site in 0..gridstructure.nsites() as u32 {
+                members.push(site);
+                find_clusters(
+                    gridstructure,
+                    &within[site as usize],
+                    size,
+                    &distances,
+                    &mut members,
+                    &mut |cluster| {
+                        for member in cluster {
+                            let others = &mut clusters_of_site[*member as usize];
+                            others.extend(cluster.iter().filter(|x| *x != member));
+                        }
+                    },
+                );
+                members.clear();
+            }
+            if clusters_of_site.iter().all(|others| others.is_empty()) {
+                anyhow::bail!(
+                    "the grid has no cluster with the distances {:?}",
+                    cluster.distances
+                );
+            }
+            let mut offsets = Vec::with_capacity(gridstructure.nsites() + 1);
+            offsets.push(0);
+            for others in &clusters_of_site {
+                offsets.push(offsets.last().unwrap() + others.len() as u32);
+            }
+            orbits.push(Orbit {
+                size,
+                distances,
+                eci,
+                offsets,
+                others: clusters_of_site.concat(),
+            });
+        }
+        Ok(ClusterExpansion {
+            atom_energy,
+            orbits: orbits.into(),
+        })
+    }
+
+    /// Sum of the ECIs of the clusters of `site` with an atom of `element` on it. `element_of`
+    /// gives the element on another site of a cluster, `None` leaves the cluster out.
+    #[inline]
+    pub(crate) fn cluster_energy<F>(&self, site: u32, element: usize, element_of: F) -> i64
+    where
+        F: Fn(u32) -> Option<usize>,
+    {
+        let elements = self.atom_energy.len();
+        let mut energy = 0;
+        for orbit in self.orbits.iter() {
+            'clusters: for others in orbit.clusters(site) {
+                let mut index = element;
+                let mut factor = elements;
+                for other in others {
+                    match element_of(*other) {
+                        Some(element) => index += element * factor,
+                        None => continue 'clusters,
+                    }
+                    factor *= elements;
+                }
+                energy += orbit.eci[index];
+            }
+        }
+        energy
+    }
+}
+
+/// The elements of the sites of a cluster from its index into `Orbit::eci`.
+fn decoration_elements(mut index: usize, size: usize, elements: usize) -> Vec<usize> {
+    (0..size)
+        .map(|_| {
+            let element = index % elements;
+            index /= elements;
+            element
+        })
+        .collect()
+}
+
+fn distance(gridstructure: &GridStructure, a: u32, b: u32) -> f64 {
+    let (a, b) = (
+        gridstructure.xsites_positions[a as usize],
+        gridstructure.xsites_positions[b as usize],
+    );
+    gridstructure
+        .cell
+        .minimum_image([0, 1, 2].map(|d| b[d] - a[d]))
+        .iter()
+        .map(|x| x * x)
+        .sum::<f64>()
+        .sqrt()
+}
+
+/// Sites closer than `cutoff` to `site` with a larger index, found through the neighbor list.
+fn sites_within(gridstructure: &GridStructure, site: u32, cutoff: f64) -> Vec<u32> {
+    let hop = gridstructure
+        .nn(site)
+        .iter()
+        .map(|x| distance(gridstructure, site, *x))
+        .fold(0., f64::max);
+    let mut within = Vec::new();
+    let mut seen: FnvHashSet<u32> = FnvHashSet::default();
+    seen.insert(site);
+    let mut queue = VecDeque::from([site]);
+    while let Some(o) = queue.pop_front() {
+        for neighbor in gridstructure.nn(o) {
+            if !seen.insert(*neighbor) {
+                continue;
+            }
+            let distance = distance(gridstructure, site, *neighbor);
+            if distance <= cutoff + hop {
+                queue.push_back(*neighbor);
+            }
+            if distance <= cutoff && *neighbor > site {
+                within.push(*neighbor);
+            }
+        }
+    }
+    within.sort_unstable();
+    within
+}
+
+/// Calls `found` with every cluster of `size` sites and the sorted `distances` made of `members`
+/// and sites of the sorted `candidates`, which all have a larger index than the members, so every
+/// cluster is found once from its smallest site.
+fn find_clusters<F>(
+    gridstructure: &GridStructure,
+    candidates: &[u32],
+    size: usize,
+    distances: &[f64],
+    members: &mut Vec<u32>,
+    found: &mut F,
+) where
+    F: FnMut(&[u32]),
+{
+    if members.len() == size {
+        let mut cluster_distances: Vec<f64> = members
+            .iter()
+            .enumerate()
+            .flat_map(|(i, a)| {
+                members[i + 1..]
+                    .iter()
+                    .map(|b| distance(gridstructure, *a, *b))
+            })
+            .collect();
+        cluster_distances.sort_by(f64::total_cmp);
+        if cluster_distances
+            .iter()
+            .zip(distances)
+            .all(|(a, b)| (a - b).abs() < DISTANCE_TOLERANCE)
+        {
+            found(members);
+        }
+        return;
+    }
+    for (i, candidate) in candidates.iter().enumerate() {
+        let fits = members.iter().all(|member| {
+            let distance = distance(gridstructure, *member, *candidate);
+            distances
+                .iter()
+                .any(|x| (x - distance).abs() < DISTANCE_TOLERANCE)
+        });
+        if fits {
+            members.push(*candidate);
+            find_clusters(
+                gridstructure,
+                &candidates[i + 1..],
+                size,
+                distances,
+                members,
+                found,
+            );
+            members.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{atoms_at, fcc_grid, particle, run_and_recompute, summed_energy};
+    use crate::{Simulation, SimulationOptions, Sites, SwapMode, Swaps, SUPPORT};
+
+    const ECI_EXAMPLE: &str = include_str!("../example_data/eci_example.json");
+
+    fn pt_ni() -> Vec<String> {
+        vec!["Pt".to_string(), "Ni".to_string()]
+    }
+
+    fn from_json(json: &str, gridstructure: &GridStructure) -> anyhow::Result<ClusterExpansion> {
+        let input: ClusterExpansionInput = serde_json::from_str(json).unwrap();
+        ClusterExpansion::new(&input, &pt_ni(), gridstructure)
+    }
+
+    #[test]
+    fn example_clusters_are_found_on_the_grid() {
+        let gridstructure = fcc_grid([8, 8, 8]);
+        let ce = from_json(ECI_EXAMPLE, &gridstructure).unwrap();
+        assert_eq!(ce.atom_energy, vec![3250, 3000]);
+
+        // per site 6 nearest neighbor and 3 second neighbor pairs, 8 triangles, 12 triangles
+        // with one second neighbor distance and 2 tetrahedra
+        let clusters: Vec<usize> = ce
+            .orbits
+            .iter()
+            .map(|orbit| orbit.others.len() / (orbit.size * (orbit.size - 1)))
+            .collect();
+        assert_eq!(clusters, vec![12288, 6144, 16384, 24576, 4096]);
+        for orbit in ce.orbits.iter() {
+            assert_eq!(*orbit.offsets.last().unwrap() as usize, orbit.others.len());
+            for site in [0, 777, 2047] {
+                for others in orbit.clusters(site) {
+                    let mut members = others.to_vec();
+                    members.push(site);
+                    let mut distances: Vec<f64> = members
+                        .iter()
+                        .enumerate()
+                        .flat_map(|(i, a)| {
+                            members[i + 1..]
+                                .iter()
+                                .map(|b| distance(&gridstructure, *a, *b))
+                        })
+                        .collect();
+                    distances.sort_by(f64::total_cmp);
+                    for (found, expected) in distances.iter().zip(&orbit.distances) {
+                        assert!((found - expected).abs() < DISTANCE_TOLERANCE);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn eci_are_given_for_every_order_in_milli_ev() {
+        let ce = from_json(ECI_EXAMPLE, &fcc_grid([8, 8, 8])).unwrap();
+        let eci = |orbit: usize, elements: &[usize]| {
+            let index = elements.iter().rev().fold(0, |index, x| index * 2 + x);
+            ce.orbits[orbit].eci[index]
+        };
+        assert_eq!((eci(0, &[0, 0]), eci(0, &[1, 1])), (-880, -800));
+        assert_eq!((eci(0, &[0, 1]), eci(0, &[1, 0])), (-900, -900));
+        assert_eq!(
+            (eci(1, &[0, 1]), eci(1, &[1, 0]), eci(1, &[0, 0])),
+            (-20, -20, 0)
+        );
+        for order in [[0, 1, 1], [1, 0, 1], [1, 1, 0]] {
+            assert_eq!(eci(2, &order), -12);
+        }
+        assert_eq!((eci(2, &[0, 0, 0]), eci(2, &[1, 1, 1])), (3, 0));
+        for order in [[0, 0, 1, 1], [0, 1, 0, 1], [1, 1, 0, 0], [1, 0, 1, 0]] {
+            assert_eq!(eci(4, &order), 30);
+        }
+        assert_eq!(eci(4, &[1, 0, 1, 1]), -7);
+    }
+
+    #[test]
+    fn invalid_clusters_are_rejected() {
+        let gridstructure = fcc_grid([4, 4, 4]);
+        for (json, error) in [
+            (
+                r#"{"clusters": [{"distances": [2.77], "eci": {"Pt-Co": -100}}]}"#,
+                "Co is not one of the elements",
+            ),
+            (
+                r#"{"clusters": [{"distances": [2.77, 2.77], "eci": {}}]}"#,
+                "needs 1, 3 or 6 distances",
+            ),
+            (
+                r#"{"clusters": [{"distances": [2.5], "eci": {}}]}"#,
+                "no cluster with the distances",
+            ),
+            (
+                r#"{"clusters": [{"distances": [2.77], "eci": {"Pt-Ni-Ni": 10}}]}"#,
+                "needs 2 elements",
+            ),
+            (
+                r#"{"clusters": [{"distances": [2.77], "eci": {"Pt-Ni": -100, "Ni-Pt": -90}}]}"#,
+                "more than one ECI",
+            ),
+            (
+                r#"{"unit": "meV", "clusters": [{"distances": [2.77], "eci": {"Pt-Ni": -0.5}}]}"#,
+                "no whole number of meV",
+            ),
+        ] {
+            let err = from_json(json, &gridstructure).unwrap_err();
+            assert!(err.to_string().contains(error), "{}: {}", json, err);
+        }
+    }
+
+    /// Energy of all clusters summed over every site and divided by the cluster size, so it
+    /// does not rely on counting every cluster from its smallest site.
+    fn full_cluster_sum(sim: &Simulation<ClusterExpansion>) -> i64 {
+        let ce = &sim.energy;
+        let element = |site: u32| {
+            let atom = &sim.atom_pos[site as usize];
+            atom.is_metal().then(|| atom.element())
+        };
+        let mut energy = 0;
+        for site in sim.onlyocc.iter() {
+            let own = element(*site).unwrap();
+            let at_support = sim
+                .gridstructure
+                .nn(*site)
+                .iter()
+                .any(|o| sim.atom_pos[*o as usize].occ == SUPPORT);
+            energy += ce.atom_energy[own] + sim.support_e * at_support as i64;
+        }
+        for orbit in ce.orbits.iter() {
+            let mut sum = 0;
+            for site in sim.onlyocc.iter() {
+                for others in orbit.clusters(*site) {
+                    let elements: Option<Vec<usize>> = std::iter::once(*site)
+                        .chain(others.iter().copied())
+                        .map(element)
+                        .collect();
+                    if let Some(elements) = elements {
+                        sum += orbit.eci[elements.iter().rev().fold(0, |index, x| index * 2 + x)];
+                    }
+                }
+            }
+            assert_eq!(sum % orbit.size as i64, 0);
+            energy += sum / orbit.size as i64;
+        }
+        energy
+    }
+
+    /// A Pt-Ni pair of neighbors has the atom energies and the ECI of its pair only, every other
+    /// cluster has an empty site.
+    #[test]
+    fn known_energy_of_a_pair_of_neighbors() {
+        let gridstructure = fcc_grid([8, 8, 8]);
+        let ce = from_json(ECI_EXAMPLE, &gridstructure).unwrap();
+        let neighbor = gridstructure.nn(777)[0];
+        let atom_pos = atoms_at(&gridstructure, &[(777, 0), (neighbor, 1)]);
+        let sites = Sites {
+            atom_pos: &atom_pos,
+            gridstructure: &gridstructure,
+            support_e: 0,
+        };
+        assert_eq!(summed_energy(&ce, &(), &sites), 3250 + 3000 - 900);
+    }
+
+    /// The energy kept by the simulation, which counts every cluster from its smallest site, is
+    /// the sum over all clusters of the particle.
+    #[test]
+    fn energy_of_a_run_is_the_full_cluster_sum() {
+        let gridstructure = fcc_grid([8, 8, 8]);
+        let ce = from_json(ECI_EXAMPLE, &gridstructure).unwrap();
+        let mut sim = particle(
+            "cluster_expansion",
+            gridstructure,
+            &[("Pt", 40), ("Ni", 30)],
+            true,
+            ce,
+            SimulationOptions {
+                niter: 20000,
+                temperature: 900.,
+                start_temperature: 1500.,
+                support_e: -120,
+                swaps: Some(Swaps {
+                    ratio: 0.3,
+                    mode: SwapMode::Surface,
+                }),
+                ..Default::default()
+            },
+        );
+        assert_eq!(sim.total_energy_1000, full_cluster_sum(&sim));
+        run_and_recompute(&mut sim);
+        assert_eq!(sim.total_energy_1000, full_cluster_sum(&sim));
+    }
+}
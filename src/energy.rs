@@ -1,3 +1,5 @@
+use super::cluster_expansion::ClusterExpansion;
+use super::KB;
 use std::ops::Index;
 
 #[derive(Clone, Debug)]
@@ -9,31 +11,331 @@ pub struct EnergyValues<T> {
 #[derive(Clone, Debug)]
 pub enum EnergyInput {
     LinearCn(EnergyValues<[i64; 2]>),
-    Cn(EnergyValues<[i64; 13]>),
+    Cn(EnergyValues<Vec<i64>>),
+    AlloyCn(AlloyEnergy),
+    PairBond(PairBondEnergy),
+    Gcn(GcnEnergy),
+    PowerCn(PowerCnEnergy),
+    ClusterExpansion(ClusterExpansion),
 }
 
-pub fn energy_1000_calculation(
-    energy: &EnergyInput,
-    cn: usize,
-    at_support: u8,
-    support_e: i64,
-) -> i64 {
-    match energy {
-        EnergyInput::LinearCn(e) => {
-            e.complet_energy[0] * cn as i64 + e.complet_energy[1] + support_e * at_support as i64
-        }
-        EnergyInput::Cn(e) => {
-            if let Some(co_ads) = e.co_ads_energy {
-                e.complet_energy[cn]
-                    + co_ads[cn] * (at_support as i64)
-                    + support_e * (at_support as i64)
-            } else {
-                e.complet_energy[cn] + support_e * (at_support as i64)
+/// Energies of a particle with more than one metal, indexed like `AtomNames::atoms`.
+#[derive(Clone, Debug)]
+pub struct AlloyEnergy {
+    /// CN energies of every element.
+    pub cn_energy: Vec<EnergyValues<Vec<i64>>>,
+    /// Energy of a bond between two metal atoms on top of their CN energies, by their elements.
+    /// Usually zero for atoms of the same element and the mixing energy for unlike atoms.
+    pub mixing_energy: Vec<Vec<i64>>,
+}
+
+impl AlloyEnergy {
+    /// Energy of one atom without its bonds.
+    #[inline]
+    pub fn atom_energy(&self, element: usize, cn: usize, at_support: u8, support_e: i64) -> i64 {
+        let energy = &self.cn_energy[element];
+        let mut energy_1000 = energy.complet_energy[cn];
+        if at_support == 1 {
+            energy_1000 += support_e;
+            if let Some(co_ads) = &energy.co_ads_energy {
+                energy_1000 += co_ads[cn];
             }
         }
+        energy_1000
+    }
+
+    /// Mixing energy of the bonds of an atom to neighbors of the given elements.
+    #[inline]
+    pub fn bond_energy(
+        &self,
+        element: usize,
+        neighbor_elements: impl Iterator<Item = usize>,
+    ) -> i64 {
+        let mixing_energy = &self.mixing_energy[element];
+        neighbor_elements.map(|x| mixing_energy[x]).sum()
+    }
+
+    /// Change of the CN energies of the atoms given as element, CN and whether they are at the
+    /// support, when the ones in `cn_from_list` lose and the ones in `cn_to_list` gain a neighbor.
+    pub fn neighbor_energy_diff<I, O>(&self, cn_from_list: I, cn_to_list: O) -> i64
+    where
+        I: Iterator<Item = (usize, usize, u8)>,
+        O: Iterator<Item = (usize, usize, u8)>,
+    {
+        let mut energy_diff_1000 = 0;
+        for (element, cn_from, is_at_supp) in cn_from_list {
+            energy_diff_1000 += self.atom_energy(element, cn_from - 1, is_at_supp, 0)
+                - self.atom_energy(element, cn_from, is_at_supp, 0);
+        }
+        for (element, cn_to, is_at_supp) in cn_to_list {
+            energy_diff_1000 += self.atom_energy(element, cn_to + 1, is_at_supp, 0)
+                - self.atom_energy(element, cn_to, is_at_supp, 0);
+        }
+        energy_diff_1000
+    }
+}
+
+/// Nearest neighbor bond counting: the energy of a particle is the sum of the energies of its
+/// bonds, indexed by the elements like `AtomNames::atoms`, plus a constant energy of every atom.
+/// For one element this is `LinearCn` with twice its slope as bond energy and its intercept as
+/// atom energy.
+#[derive(Clone, Debug)]
+pub struct PairBondEnergy {
+    pub bond_energy: Vec<Vec<i64>>,
+    pub atom_energy: Vec<i64>,
+}
+
+impl PairBondEnergy {
+    /// Energy of one atom with all of its bonds, `at_support` adds the support energy.
+    #[inline]
+    pub fn atom_energy(
+        &self,
+        element: usize,
+        neighbor_elements: impl Iterator<Item = usize>,
+        at_support: u8,
+        support_e: i64,
+    ) -> i64 {
+        self.atom_energy[element]
+            + self.bond_energy(element, neighbor_elements)
+            + support_e * at_support as i64
+    }
+
+    /// Energy of the bonds of an atom to neighbors of the given elements.
+    #[inline]
+    pub fn bond_energy(
+        &self,
+        element: usize,
+        neighbor_elements: impl Iterator<Item = usize>,
+    ) -> i64 {
+        let bond_energy = &self.bond_energy[element];
+        neighbor_elements.map(|x| bond_energy[x]).sum()
+    }
+
+    /// Energy change of moving an atom from a site with the neighbors `from_neighbors` to a site
+    /// with the neighbors `to_neighbors`, both given by their elements and without the atom
+    /// itself.
+    pub fn energy_diff<I, O>(
+        &self,
+        element: usize,
+        from_neighbors: I,
+        to_neighbors: O,
+        from_at_support: u8,
+        to_at_support: u8,
+        support_e: i64,
+    ) -> i64
+    where
+        I: Iterator<Item = usize>,
+        O: Iterator<Item = usize>,
+    {
+        self.atom_energy(element, to_neighbors, to_at_support, support_e)
+            - self.atom_energy(element, from_neighbors, from_at_support, support_e)
+    }
+}
+
+/// Energy of an atom by its generalized coordination number, the sum of the CNs of its metal
+/// neighbors divided by the CN of the lattice.
+#[derive(Clone, Debug)]
+pub struct GcnEnergy {
+    /// For every element indexed like `AtomNames::atoms` the energy by the sum of the neighbor
+    /// CNs, from zero to the square of the lattice CN.
+    pub energy: Vec<Vec<i64>>,
+}
+
+impl GcnEnergy {
+    /// Energy table of `slope` times the GCN plus `intercept`.
+    pub fn linear(slope: i64, intercept: i64, cn: usize) -> Vec<i64> {
+        (0..=cn * cn)
+            .map(|cn_sum| (slope as f64 * cn_sum as f64 / cn as f64).round() as i64 + intercept)
+            .collect()
+    }
+
+    /// Energy table interpolated linearly between the `energies` at the GCNs 0, 1, ..., `cn`.
+    pub fn interpolated(energies: &[i64], cn: usize) -> Vec<i64> {
+        assert_eq!(energies.len(), cn + 1);
+        (0..=cn * cn)
+            .map(|cn_sum| {
+                let (lower, rest) = (cn_sum / cn, cn_sum % cn);
+                if rest == 0 {
+                    energies[lower]
+                } else {
+                    let fraction = rest as f64 / cn as f64;
+                    (energies[lower] as f64 * (1. - fraction)
+                        + energies[lower + 1] as f64 * fraction)
+                        .round() as i64
+                }
+            })
+            .collect()
+    }
+
+    #[inline]
+    pub fn atom_energy(
+        &self,
+        element: usize,
+        cn_sum: usize,
+        at_support: u8,
+        support_e: i64,
+    ) -> i64 {
+        self.energy[element][cn_sum] + support_e * at_support as i64
+    }
+}
+
+/// Energy of an atom relative to the bulk falling with a power of its CN, as
+/// `cohesive_energy * (1 - (cn / cn_max)^exponent)`. The exponent 0.5 is the square root of the
+/// second moment tight binding approximation. The energies are computed once into a CN table, so
+/// moves cost the same as with `Cn`.
+#[derive(Clone, Debug)]
+pub struct PowerCnEnergy {
+    pub cohesive_energy: i64,
+    pub exponent: f64,
+    pub table: EnergyValues<Vec<i64>>,
+}
+
+impl PowerCnEnergy {
+    pub fn new(cohesive_energy: i64, exponent: f64, cn: usize) -> PowerCnEnergy {
+        PowerCnEnergy {
+            cohesive_energy,
+            exponent,
+            table: EnergyValues {
+                complet_energy: PowerCnEnergy::table(cohesive_energy, exponent, cn),
+                co_ads_energy: None,
+            },
+        }
+    }
+
+    /// Energies for the CNs from zero to `cn`.
+    pub fn table(cohesive_energy: i64, exponent: f64, cn: usize) -> Vec<i64> {
+        (0..=cn)
+            .map(|x| {
+                (cohesive_energy as f64 * (1. - (x as f64 / cn as f64).powf(exponent))).round()
+                    as i64
+            })
+            .collect()
+    }
+}
+
+/// Energy of an atom by its number of metal second shell neighbors, a value for every number from
+/// zero to the second shell CN of the grid.
+#[derive(Clone, Debug)]
+pub struct SecondShellEnergy {
+    pub energy: Vec<i64>,
+}
+
+/// Energy of an atom at the support by its number of support neighbors and its CN, in place of
+/// the one `support_e` for every atom touching the support. Atoms without support neighbor have
+/// no support energy.
+#[derive(Clone, Debug)]
+pub struct SupportEnergy {
+    /// By element indexed like `AtomNames::atoms`, number of support neighbors and CN. Atoms
+    /// with more support neighbors than rows take the last row.
+    pub energy: Vec<Vec<Vec<i64>>>,
+}
+
+impl SupportEnergy {
+    /// Table of one element as the sum of an energy by CN, with a value for every CN from zero to
+    /// `cn`, and an energy by the number of support neighbors starting at one.
+    pub fn table(by_cn: Option<&[i64]>, by_contacts: Option<&[i64]>, cn: usize) -> Vec<Vec<i64>> {
+        let max_contacts = by_contacts.map_or(1, |x| x.len());
+        let mut table = vec![vec![0; cn + 1]];
+        for contacts in 1..=max_contacts {
+            table.push(
+                (0..=cn)
+                    .map(|x| {
+                        by_cn.map_or(0, |by_cn| by_cn[x])
+                            + by_contacts.map_or(0, |by_contacts| by_contacts[contacts - 1])
+                    })
+                    .collect(),
+            );
+        }
+        table
+    }
+
+    #[inline]
+    pub fn atom_energy(&self, element: usize, contacts: u8, cn: usize) -> i64 {
+        let table = &self.energy[element];
+        table[(contacts as usize).min(table.len() - 1)][cn]
+    }
+}
+
+/// Gas adsorbing on the particle after Langmuir. Every atom below the CN of the lattice is
+/// exposed and has one adsorption site, which is covered with the probability
+/// `1 / (1 + exp((E_ads(cn) - mu) / kT))` for the chemical potential `mu` of the gas. The site
+/// adds its free energy `-kT ln(1 + exp(-(E_ads(cn) - mu) / kT))` to the energy of the atom in any
+/// energy model, so strongly binding gases favor shapes with many low coordinated atoms.
+#[derive(Clone, Copy, Debug)]
+pub struct GasAdsorption {
+    /// Gas temperature in K.
+    pub temperature: f64,
+    /// Partial pressure of the gas in bar.
+    pub pressure: f64,
+    /// Chemical potential of the gas at `temperature` and 1 bar in milli eV, relative to the
+    /// reference of the adsorption energies.
+    pub standard_chemical_potential: i64,
+}
+
+impl GasAdsorption {
+    fn kt_1000(&self) -> f64 {
+        1000. * KB * self.temperature
+    }
+
+    /// Chemical potential of the gas in milli eV.
+    pub fn chemical_potential(&self) -> f64 {
+        self.standard_chemical_potential as f64 + self.kt_1000() * self.pressure.ln()
+    }
+
+    /// Fraction of time a site with the `adsorption_energy` in milli eV is covered.
+    pub fn coverage(&self, adsorption_energy: i64) -> f64 {
+        let x = (adsorption_energy as f64 - self.chemical_potential()) / self.kt_1000();
+        1. / (1. + x.exp())
+    }
+
+    /// Free energy in milli eV of a site with the `adsorption_energy` in milli eV.
+    pub fn site_free_energy(&self, adsorption_energy: i64) -> i64 {
+        let x = (self.chemical_potential() - adsorption_energy as f64) / self.kt_1000();
+        // ln(1 + e^x) without overflow for strongly binding sites
+        let ln_partition = x.max(0.) + (-x.abs()).exp().ln_1p();
+        (-self.kt_1000() * ln_partition).round() as i64
+    }
+
+    /// Free energies of the adsorption sites by element and CN of the `adsorption_energy` of
+    /// every element, indexed like `AtomNames::atoms`, with a value for every CN from zero to the
+    /// lattice CN. A fully coordinated atom has no site.
+    pub fn site_free_energies(&self, adsorption_energy: &[Vec<i64>]) -> GasAdsorptionEnergy {
+        let energy = adsorption_energy
+            .iter()
+            .map(|adsorption_energy| {
+                let cn = adsorption_energy.len() - 1;
+                adsorption_energy
+                    .iter()
+                    .enumerate()
+                    .map(|(x, adsorption_energy)| {
+                        if x < cn {
+                            self.site_free_energy(*adsorption_energy)
+                        } else {
+                            0
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+        GasAdsorptionEnergy { energy }
+    }
+}
+
+/// Free energy of the adsorption site of every exposed atom in a gas, see [`GasAdsorption`].
+#[derive(Clone, Debug)]
+pub struct GasAdsorptionEnergy {
+    /// By element indexed like `AtomNames::atoms` and CN.
+    pub energy: Vec<Vec<i64>>,
+}
+
+impl GasAdsorptionEnergy {
+    #[inline]
+    pub fn atom_energy(&self, element: usize, cn: usize) -> i64 {
+        self.energy[element][cn]
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn energy_diff_cn<T, I, O>(
     energy: &EnergyValues<T>,
     cn_from_list: I,
@@ -114,3 +416,147 @@ pub fn energy_diff_l_cn(
         - (2 * ((cn_from as i64) * energy[0] + energy[1]))
         - (support_e * from_at_support as i64)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gas(pressure: f64) -> GasAdsorption {
+        GasAdsorption {
+            temperature: 500.,
+            pressure,
+            standard_chemical_potential: -1000,
+        }
+    }
+
+    /// At the chemical potential a site is half covered at 1 bar and `p / (1 + p)` covered at
+    /// the pressure `p`, far below it fully covered and far above it empty.
+    #[test]
+    fn langmuir_coverage() {
+        assert!((gas(1.).coverage(-1000) - 0.5).abs() < 1e-12);
+        assert!((gas(3.).coverage(-1000) - 0.75).abs() < 1e-12);
+        assert!((gas(0.25).coverage(-1000) - 0.2).abs() < 1e-12);
+        assert!(gas(1.).coverage(-3000) > 1. - 1e-12);
+        assert!(gas(1.).coverage(1000) < 1e-12);
+        let kt_1000 = 1000. * KB * 500.;
+        let x = 60. / kt_1000;
+        assert!((gas(1.).coverage(-1060) - 1. / (1. + (-x).exp())).abs() < 1e-12);
+    }
+
+    #[test]
+    fn langmuir_site_free_energy() {
+        let kt_1000 = 1000. * KB * 500.;
+        assert_eq!(
+            gas(1.).site_free_energy(-1000),
+            (-kt_1000 * 2_f64.ln()).round() as i64
+        );
+        // a strongly binding site has about the adsorption energy minus the chemical potential
+        assert_eq!(gas(1.).site_free_energy(-41000), -40000);
+        assert_eq!(gas(1.).site_free_energy(40000), 0);
+
+        let table = gas(1.).site_free_energies(&[vec![-41000, -1000, 40000, -41000]]);
+        assert_eq!(
+            table.energy,
+            vec![vec![-40000, gas(1.).site_free_energy(-1000), 0, 0]]
+        );
+    }
+
+    #[test]
+    fn power_law_tables() {
+        let square_root = PowerCnEnergy::table(5840, 0.5, 12);
+        assert_eq!(square_root.len(), 13);
+        assert_eq!(
+            (square_root[0], square_root[3], square_root[12]),
+            (5840, 2920, 0)
+        );
+        assert_eq!(
+            square_root[1],
+            (5840. * (1. - (1_f64 / 12.).sqrt())).round() as i64
+        );
+        // the first bonds of an atom gain the most
+        let gains: Vec<i64> = square_root.windows(2).map(|x| x[0] - x[1]).collect();
+        assert!(gains.windows(2).all(|x| x[0] > x[1]));
+
+        assert_eq!(PowerCnEnergy::table(5840, 1., 12)[6], 2920);
+        assert_eq!(PowerCnEnergy::table(5840, 2., 12)[6], 4380);
+        assert_eq!(
+            PowerCnEnergy::table(8900, 0.5, 8),
+            PowerCnEnergy::new(8900, 0.5, 8).table.complet_energy
+        );
+        assert_eq!(PowerCnEnergy::table(8900, 0.5, 8)[2], 4450);
+    }
+
+    fn pt_ni() -> AlloyEnergy {
+        AlloyEnergy {
+            cn_energy: vec![
+                EnergyValues {
+                    complet_energy: (0..=12).map(|cn| 3700 - 370 * cn).collect(),
+                    co_ads_energy: Some((0..=12).map(|cn| -10 * cn).collect()),
+                },
+                EnergyValues {
+                    complet_energy: (0..=12).map(|cn| 3000 - 300 * cn).collect(),
+                    co_ads_energy: None,
+                },
+            ],
+            mixing_energy: vec![vec![0, -45], vec![-45, 0]],
+        }
+    }
+
+    #[test]
+    fn alloy_energy_by_element_and_cn() {
+        let alloy = pt_ni();
+        assert_eq!(alloy.atom_energy(0, 9, 0, -170), 370);
+        assert_eq!(alloy.atom_energy(1, 9, 0, -170), 300);
+        // at the support the support energy and the sections are added
+        assert_eq!(alloy.atom_energy(0, 9, 1, -170), 370 - 170 - 90);
+        assert_eq!(alloy.atom_energy(1, 9, 1, -170), 300 - 170);
+    }
+
+    #[test]
+    fn alloy_bonds_by_the_elements_of_the_neighbors() {
+        let alloy = pt_ni();
+        assert_eq!(alloy.bond_energy(0, [0, 0, 0].into_iter()), 0);
+        assert_eq!(alloy.bond_energy(0, [0, 1, 1].into_iter()), -90);
+        assert_eq!(alloy.bond_energy(1, [0, 1, 0].into_iter()), -90);
+    }
+
+    #[test]
+    fn support_table_adds_the_energies_by_cn_and_contacts() {
+        let by_cn = [-10, -20, -30];
+        let by_contacts = [-100, -150, -180];
+        assert_eq!(
+            SupportEnergy::table(Some(&by_cn), Some(&by_contacts), 2),
+            vec![
+                vec![0, 0, 0],
+                vec![-110, -120, -130],
+                vec![-160, -170, -180],
+                vec![-190, -200, -210]
+            ]
+        );
+        assert_eq!(
+            SupportEnergy::table(Some(&by_cn), None, 2),
+            vec![vec![0, 0, 0], vec![-10, -20, -30]]
+        );
+        assert_eq!(
+            SupportEnergy::table(None, Some(&by_contacts[..1]), 2),
+            vec![vec![0, 0, 0], vec![-100, -100, -100]]
+        );
+    }
+
+    #[test]
+    fn support_energy_by_contacts_and_cn() {
+        let support = SupportEnergy {
+            energy: vec![
+                SupportEnergy::table(Some(&[-10, -20, -30]), Some(&[-100, -150]), 2),
+                SupportEnergy::table(None, Some(&[-50]), 2),
+            ],
+        };
+        assert_eq!(support.atom_energy(0, 0, 2), 0);
+        assert_eq!(support.atom_energy(0, 1, 0), -110);
+        assert_eq!(support.atom_energy(0, 2, 2), -180);
+        // more contacts than rows take the last row
+        assert_eq!(support.atom_energy(0, 4, 1), -170);
+        assert_eq!(support.atom_energy(1, 1, 2), -50);
+        assert_eq!(support.atom_energy(1, 3, 0), -50);
+    }
+}
@@ -0,0 +1,423 @@
+use super::energy::EnergyValues;
+use anyhow::{bail, Context};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+/// Unit of the energies of an input file, converted to the milli eV integers of the simulation.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum Unit {
+    #[default]
+    #[serde(rename = "meV")]
+    MilliElectronVolt,
+    #[serde(rename = "eV")]
+    ElectronVolt,
+}
+
+impl Unit {
+    /// The energy `value` of the entry `key` in milli eV.
+    pub fn to_milli_ev(self, key: &str, value: f64) -> anyhow::Result<i64> {
+        match self {
+            Unit::ElectronVolt => Ok((value * 1000.).round() as i64),
+            Unit::MilliElectronVolt if value.fract() == 0. => Ok(value as i64),
+            Unit::MilliElectronVolt => bail!(
+                "{} has the value {} which is no whole number of meV, set \"unit\": \"eV\" for \
+                 energies in eV",
+                key,
+                value
+            ),
+        }
+    }
+
+    /// `value` in milli eV without rounding, for energies that are not used in the simulation
+    /// directly.
+    pub fn to_milli_ev_f64(self, value: f64) -> f64 {
+        match self {
+            Unit::ElectronVolt => value * 1000.,
+            Unit::MilliElectronVolt => value,
+        }
+    }
+
+    fn all_to_milli_ev(self, key: &str, values: &[f64]) -> anyhow::Result<Vec<i64>> {
+        values
+            .iter()
+            .map(|value| self.to_milli_ev(key, *value))
+            .collect()
+    }
+}
+
+/// Reads the file at `input`, or `input` itself as a JSON object if there is no such file.
+pub fn read_json<T: DeserializeOwned>(input: &str) -> anyhow::Result<T> {
+    if Path::new(input).is_file() {
+        let json = std::fs::read_to_string(input)
+            .with_context(|| format!("could not read energy file {}", input))?;
+        serde_json::from_str(&json).with_context(|| format!("energy file {} is not valid", input))
+    } else if input.trim_start().starts_with('{') {
+        serde_json::from_str(input).context("energy input is not valid")
+    } else {
+        bail!(
+            "energy input {} is neither an existing file nor a JSON object",
+            input
+        );
+    }
+}
+
+/// Energies of all elements or by element.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Section {
+    All(Vec<f64>),
+    ByElement(BTreeMap<String, Vec<f64>>),
+}
+
+/// Energy input of the `--e-*` flags, lists of energies by key:
+/// ```json
+/// {
+///   "unit": "eV",
+///   "CN_energy_Pt": [20.0, 2.051, 1.81, 1.569, 1.328, 1.099, 0.832, 0.575, 0.552, 0.426, 0.283, 0.241, 0.0],
+///   "sections": {"CO": [0, 0, 0, 0, -0.1, -0.1, -0.2, -0.2, -0.2, -0.3, -0.3, -0.3, -0.3]}
+/// }
+/// ```
+/// A key ending in `_Pt` is for that element only and replaces the key without it. Every entry
+/// has to be used by the energy model, so misspelled keys are errors instead of missing energies.
+#[derive(Deserialize, Debug)]
+pub struct EnergyFile {
+    /// Milli eV if not given.
+    #[serde(default)]
+    unit: Unit,
+    /// Named per-CN energies of atoms next to the support, e.g. of an adsorbate at the perimeter,
+    /// added up with `ads_e_CO` for every element.
+    #[serde(default)]
+    sections: BTreeMap<String, Section>,
+    #[serde(flatten)]
+    values: BTreeMap<String, Vec<f64>>,
+    #[serde(skip)]
+    used: BTreeSet<String>,
+}
+
+impl EnergyFile {
+    pub fn read(input: &str) -> anyhow::Result<EnergyFile> {
+        read_json(input)
+    }
+
+    /// Energies of `key` in milli eV.
+    pub fn get(&mut self, key: &str) -> anyhow::Result<Option<Vec<i64>>> {
+        let Some(values) = self.values.get(key) else {
+            return Ok(None);
+        };
+        let values = self.unit.all_to_milli_ev(key, values)?;
+        self.used.insert(key.to_string());
+        Ok(Some(values))
+    }
+
+    /// Energies of one element and the key they were found under, `key_element` if given,
+    /// otherwise `key`.
+    pub fn get_for_element(
+        &mut self,
+        key: &str,
+        element: &str,
+    ) -> anyhow::Result<Option<(String, Vec<i64>)>> {
+        let element_key = format!("{}_{}", key, element);
+        if let Some(values) = self.get(&element_key)? {
+            return Ok(Some((element_key, values)));
+        }
+        Ok(self.get(key)?.map(|values| (key.to_string(), values)))
+    }
+
+    /// Energies which have to be given with `len` values, for one element if `element` is given.
+    pub fn require(
+        &mut self,
+        key: &str,
+        element: Option<&str>,
+        len: usize,
+    ) -> anyhow::Result<Vec<i64>> {
+        let (key, values) = match element {
+            Some(element) => self.get_for_element(key, element)?.with_context(|| {
+                format!("energy input has neither {}_{} nor {}", key, element, key)
+            })?,
+            None => (
+                key.to_string(),
+                self.get(key)?
+                    .with_context(|| format!("energy input has no {}", key))?,
+            ),
+        };
+        check_len(&key, &values, len)?;
+        Ok(values)
+    }
+
+    /// A single energy of one element, e.g. `"atom_energy_Pt": [3250]`.
+    pub fn single(&mut self, key: &str, element: &str) -> anyhow::Result<Option<i64>> {
+        match self.get_for_element(key, element)? {
+            Some((key, values)) => {
+                check_len(&key, &values, 1)?;
+                Ok(Some(values[0]))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// CN energies of one element for a lattice of CN `cn`, with the sections added up.
+    pub fn cn_energy(
+        &mut self,
+        element: &str,
+        cn: usize,
+    ) -> anyhow::Result<EnergyValues<Vec<i64>>> {
+        let context = || {
+            format!(
+                "the CN energies of {} need a value for every CN from 0 to {}",
+                element, cn
+            )
+        };
+        Ok(EnergyValues {
+            complet_energy: self
+                .require("CN_energy", Some(element), cn + 1)
+                .with_context(context)?,
+            co_ads_energy: self.section_energy(element, cn + 1).with_context(context)?,
+        })
+    }
+
+    /// Sum of `ads_e_CO` and the sections of one element, `None` without any.
+    fn section_energy(&mut self, element: &str, len: usize) -> anyhow::Result<Option<Vec<i64>>> {
+        let mut sections = Vec::new();
+        if let Some(section) = self.get_for_element("ads_e_CO", element)? {
+            sections.push(section);
+        }
+        for (name, section) in &self.sections {
+            let (key, values) = match section {
+                Section::All(values) => (format!("sections.{}", name), values),
+                Section::ByElement(by_element) => match by_element.get(element) {
+                    Some(values) => (format!("sections.{}.{}", name, element), values),
+                    None => continue,
+                },
+            };
+            let values = self.unit.all_to_milli_ev(&key, values)?;
+            sections.push((key, values));
+        }
+
+        let mut energy: Option<Vec<i64>> = None;
+        for (key, values) in sections {
+            check_len(&key, &values, len)?;
+            let energy = energy.get_or_insert_with(|| vec![0; len]);
+            for (sum, value) in energy.iter_mut().zip(values) {
+                *sum += value;
+            }
+            self.used.insert(key);
+        }
+        Ok(energy)
+    }
+
+    /// Energies of every pair of elements, e.g. `mixing_energy_Pt_Ni` in either order. Pairs not
+    /// given are zero unless `required`.
+    pub fn pair_energy(
+        &mut self,
+        key: &str,
+        atoms: &[String],
+        required: bool,
+    ) -> anyhow::Result<Vec<Vec<i64>>> {
+        let mut energy = vec![vec![0; atoms.len()]; atoms.len()];
+        for (i, a) in atoms.iter().enumerate() {
+            for (j, b) in atoms.iter().enumerate() {
+                let pair = format!("{}_{}_{}", key, a, b);
+                let values = match self.get(&pair)? {
+                    Some(values) => Some(values),
+                    None => self.get(&format!("{}_{}_{}", key, b, a))?,
+                };
+                match values {
+                    Some(values) => {
+                        check_len(&pair, &values, 1)?;
+                        energy[i][j] = values[0];
+                    }
+                    None if required => bail!("energy input has no {}", pair),
+                    None => {}
+                }
+            }
+        }
+        Ok(energy)
+    }
+
+    /// Fails for entries no energy model asked for, which are most likely misspelled.
+    pub fn check_all_used(&self) -> anyhow::Result<()> {
+        let mut keys: Vec<String> = self.values.keys().cloned().collect();
+        for (name, section) in &self.sections {
+            match section {
+                Section::All(_) => keys.push(format!("sections.{}", name)),
+                Section::ByElement(by_element) => keys.extend(
+                    by_element
+                        .keys()
+                        .map(|element| format!("sections.{}.{}", name, element)),
+                ),
+            }
+        }
+        let unused: Vec<String> = keys
+            .into_iter()
+            .filter(|key| !self.used.contains(key))
+            .collect();
+        if !unused.is_empty() {
+            bail!(
+                "energy input has entries the energy model does not use: {}",
+                unused.join(", ")
+            );
+        }
+        Ok(())
+    }
+}
+
+fn check_len(key: &str, values: &[i64], len: usize) -> anyhow::Result<()> {
+    if values.len() != len {
+        bail!("{} has {} values but needs {}", key, values.len(), len);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CN_EXAMPLE: &str = "example_data/cn_input_example.json";
+
+    fn energy_file(json: &str) -> EnergyFile {
+        EnergyFile::read(json).unwrap()
+    }
+
+    fn error(result: anyhow::Result<impl std::fmt::Debug>) -> String {
+        format!("{:#}", result.unwrap_err())
+    }
+
+    #[test]
+    fn file_and_inline_json_are_the_same() {
+        let inline = std::fs::read_to_string(CN_EXAMPLE).unwrap();
+        let mut from_file = energy_file(CN_EXAMPLE);
+        let mut from_inline = energy_file(&inline);
+        assert_eq!(
+            from_file.cn_energy("Pt", 12).unwrap().complet_energy,
+            from_inline.cn_energy("Pt", 12).unwrap().complet_energy
+        );
+        from_file.check_all_used().unwrap();
+        from_inline.check_all_used().unwrap();
+    }
+
+    #[test]
+    fn input_that_is_neither_file_nor_json_is_rejected() {
+        let err = error(EnergyFile::read("example_data/no_such_file.json"));
+        assert!(
+            err.contains("neither an existing file nor a JSON object"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn invalid_json_names_the_file() {
+        let folder = tempfile::tempdir().unwrap();
+        let path = folder.path().join("invalid_energy.json");
+        std::fs::write(&path, r#"{"CN_energy": 3}"#).unwrap();
+        let err = error(EnergyFile::read(path.to_str().unwrap()));
+        assert!(err.contains(path.to_str().unwrap()), "{}", err);
+        let err = error(EnergyFile::read(r#"{"CN_energy": 3}"#));
+        assert!(err.contains("energy input is not valid"), "{}", err);
+    }
+
+    #[test]
+    fn missing_key_is_rejected() {
+        let err = error(energy_file(r#"{"CN_energy_Ni": [0]}"#).cn_energy("Pt", 12));
+        assert!(
+            err.contains("neither CN_energy_Pt nor CN_energy"),
+            "{}",
+            err
+        );
+        let err = error(energy_file("{}").pair_energy("mixing_energy", &["Pt".into()], true));
+        assert!(err.contains("no mixing_energy_Pt_Pt"), "{}", err);
+    }
+
+    #[test]
+    fn too_few_entries_are_rejected() {
+        let err = error(energy_file(r#"{"CN_energy": [1, 2, 3]}"#).cn_energy("Pt", 12));
+        assert!(
+            err.contains("CN_energy has 3 values but needs 13"),
+            "{}",
+            err
+        );
+        assert!(err.contains("every CN from 0 to 12"), "{}", err);
+    }
+
+    #[test]
+    fn too_many_entries_are_rejected() {
+        let err =
+            error(energy_file(r#"{"atom_energy_Pt": [3250, 3000]}"#).single("atom_energy", "Pt"));
+        assert!(
+            err.contains("atom_energy_Pt has 2 values but needs 1"),
+            "{}",
+            err
+        );
+        let json = format!(r#"{{"CN_energy": {:?}}}"#, [0; 14]);
+        let err = error(energy_file(&json).cn_energy("Pt", 12));
+        assert!(
+            err.contains("CN_energy has 14 values but needs 13"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn energies_in_ev_are_converted() {
+        let mut input =
+            energy_file(r#"{"unit": "eV", "bond_energy": [-0.88], "CN_energy": [2.0515]}"#);
+        assert_eq!(input.get("bond_energy").unwrap(), Some(vec![-880]));
+        assert_eq!(input.get("CN_energy").unwrap(), Some(vec![2052]));
+        assert_eq!(Unit::ElectronVolt.to_milli_ev_f64(0.0015), 1.5);
+    }
+
+    #[test]
+    fn fractional_milli_ev_are_rejected() {
+        let err = error(energy_file(r#"{"bond_energy": [-0.88]}"#).get("bond_energy"));
+        assert!(err.contains("no whole number of meV"), "{}", err);
+        let err = error(EnergyFile::read(r#"{"unit": "ev"}"#));
+        assert!(err.contains("energy input is not valid"), "{}", err);
+    }
+
+    #[test]
+    fn unused_entries_are_rejected() {
+        let mut input = energy_file(
+            r#"{"CN_energy": [0, 0, 0], "CN_enrgy_Pt": [1], "sections": {"H": {"Ni": [0, 0, 0]}}}"#,
+        );
+        input.cn_energy("Pt", 2).unwrap();
+        let err = error(input.check_all_used());
+        assert!(err.contains("CN_enrgy_Pt, sections.H.Ni"), "{}", err);
+        input.cn_energy("Ni", 2).unwrap();
+        assert!(input.check_all_used().is_err());
+    }
+
+    #[test]
+    fn mixing_energies_are_given_in_either_order() {
+        let atoms = ["Pt".to_string(), "Ni".to_string()];
+        let mut input = energy_file(r#"{"mixing_energy_Ni_Pt": [-45]}"#);
+        assert_eq!(
+            input.pair_energy("mixing_energy", &atoms, false).unwrap(),
+            vec![vec![0, -45], vec![-45, 0]]
+        );
+        input.check_all_used().unwrap();
+        let mut input =
+            energy_file(r#"{"bond_energy_Pt_Pt": [-880], "bond_energy_Pt_Ni": [-900]}"#);
+        let err = error(input.pair_energy("bond_energy", &atoms, true));
+        assert!(err.contains("no bond_energy_Ni_Ni"), "{}", err);
+        let mut input = energy_file(r#"{"mixing_energy_Pt_Ni": [-45, -40]}"#);
+        let err = error(input.pair_energy("mixing_energy", &atoms, false));
+        assert!(
+            err.contains("mixing_energy_Pt_Ni has 2 values but needs 1"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn sections_are_added_up_by_element() {
+        let mut input = energy_file(
+            r#"{"CN_energy": [0, 0], "ads_e_CO": [1, 2], "sections": {"H": [10, 20], "O": {"Pt": [100, 200]}}}"#,
+        );
+        let pt = input.cn_energy("Pt", 1).unwrap().co_ads_energy;
+        let ni = input.cn_energy("Ni", 1).unwrap().co_ads_energy;
+        assert_eq!((pt, ni), (Some(vec![111, 222]), Some(vec![11, 22])));
+        input.check_all_used().unwrap();
+    }
+}
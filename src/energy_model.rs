@@ -0,0 +1,1049 @@
+use super::cluster_expansion::ClusterExpansion;
+use super::energy::{
+    self, AlloyEnergy, EnergyInput, EnergyValues, GasAdsorptionEnergy, GcnEnergy, PairBondEnergy,
+    PowerCnEnergy, SecondShellEnergy, SupportEnergy,
+};
+use super::free_energy::FreeEnergy;
+use super::{AtomPosition, GridStructure};
+use anyhow::bail;
+
+/// Read-only view of the sites an [`EnergyModel`] computes energies from.
+pub struct Sites<'a> {
+    pub atom_pos: &'a [AtomPosition],
+    pub gridstructure: &'a GridStructure,
+    /// Energy of an atom next to the support.
+    pub support_e: i64,
+}
+
+impl<'a> Sites<'a> {
+    #[inline]
+    pub fn atom(&self, site: u32) -> &'a AtomPosition {
+        &self.atom_pos[site as usize]
+    }
+
+    /// The metal atoms among the sites.
+    #[inline]
+    pub fn metal_atoms(&self, sites: &'a [u32]) -> impl Iterator<Item = &'a AtomPosition> {
+        let atom_pos = self.atom_pos;
+        sites
+            .iter()
+            .map(move |x| &atom_pos[*x as usize])
+            .filter(|x| x.is_metal())
+    }
+
+    /// Elements of the metal neighbors of `site` except the one at `other`.
+    #[inline]
+    pub fn neighbor_elements_without(
+        &self,
+        site: u32,
+        other: u32,
+    ) -> impl Iterator<Item = usize> + 'a {
+        let atom_pos = self.atom_pos;
+        self.gridstructure
+            .nn(site)
+            .iter()
+            .filter(move |x| **x != other)
+            .map(move |x| &atom_pos[*x as usize])
+            .filter(|x| x.is_metal())
+            .map(|x| x.element())
+    }
+
+    /// Whether `move_from` and `move_to` are next to the support and the support energy, all
+    /// zero without a support energy.
+    #[inline]
+    pub fn move_support(&self, move_from: u32, move_to: u32) -> (u8, u8, i64) {
+        if self.support_e != 0 {
+            (
+                self.atom(move_from).nn_support(),
+                self.atom(move_to).nn_support(),
+                self.support_e,
+            )
+        } else {
+            (0, 0, 0)
+        }
+    }
+
+    /// Neighbors of `move_from` losing and neighbors of `move_to` gaining a neighbor by the move,
+    /// which has to go to a neighbor.
+    #[inline]
+    pub fn no_int_nn_from_move(&self, move_from: u32, move_to: u32) -> (&'a [u32], &'a [u32]) {
+        self.gridstructure
+            .no_int_nn_from_move(move_from, move_to)
+            .unwrap_or_else(|| {
+                panic!(
+                    "move from {} to {} does not go to a neighbor",
+                    move_from, move_to
+                )
+            })
+    }
+
+    /// Change of an energy of the atoms by their element, number of support neighbors and CN
+    /// when an atom moves from `move_from` to `move_to`, its own and those of the neighbors whose
+    /// CN changes.
+    pub fn cn_energy_change<F>(&self, move_from: u32, move_to: u32, atom_energy: F) -> i64
+    where
+        F: Fn(usize, u8, usize) -> i64,
+    {
+        let from = self.atom(move_from);
+        let to = self.atom(move_to);
+        let from_neighbors = self.gridstructure.nn(move_from);
+        let to_neighbors = self.gridstructure.nn(move_to);
+        let mut energy_diff_1000 =
+            atom_energy(from.element(), to.support_contacts, to.cn_metal - 1)
+                - atom_energy(from.element(), from.support_contacts, from.cn_metal);
+        for o in from_neighbors.iter().filter(|x| !to_neighbors.contains(x)) {
+            let atom = self.atom(*o);
+            if atom.is_metal() {
+                energy_diff_1000 +=
+                    atom_energy(atom.element(), atom.support_contacts, atom.cn_metal - 1)
+                        - atom_energy(atom.element(), atom.support_contacts, atom.cn_metal);
+            }
+        }
+        for o in to_neighbors
+            .iter()
+            .filter(|x| **x != move_from && !from_neighbors.contains(x))
+        {
+            let atom = self.atom(*o);
+            if atom.is_metal() {
+                energy_diff_1000 +=
+                    atom_energy(atom.element(), atom.support_contacts, atom.cn_metal + 1)
+                        - atom_energy(atom.element(), atom.support_contacts, atom.cn_metal);
+            }
+        }
+        energy_diff_1000
+    }
+
+    /// Change of an energy of the atoms by their element, number of support neighbors and CN
+    /// when the elements of the atoms at `site_a` and `site_b` are swapped.
+    pub fn cn_energy_change_by_swap<F>(&self, site_a: u32, site_b: u32, atom_energy: F) -> i64
+    where
+        F: Fn(usize, u8, usize) -> i64,
+    {
+        let (a, b) = (self.atom(site_a), self.atom(site_b));
+        atom_energy(b.element(), a.support_contacts, a.cn_metal)
+            + atom_energy(a.element(), b.support_contacts, b.cn_metal)
+            - atom_energy(a.element(), a.support_contacts, a.cn_metal)
+            - atom_energy(b.element(), b.support_contacts, b.cn_metal)
+    }
+}
+
+/// Energy of the particle as the sum of the energies of its atoms, with the change by a move or a
+/// swap computed from the sites around it. `Simulation` is generic over the model, so models of
+/// other crates run as fast as the built-in ones.
+pub trait EnergyModel: Clone + std::fmt::Debug {
+    /// Data the model keeps for every site, e.g. a sum over the neighbors, `()` if it needs none.
+    type SiteCache: Clone + Default;
+
+    /// Fills the cache of every site for the start structure.
+    fn init_cache(&self, _sites: &Sites, _cache: &mut [Self::SiteCache]) {}
+
+    /// Updates the cache for an atom moving from `move_from` to `move_to`, called with the sites
+    /// as they are before the move.
+    fn update_cache(
+        &self,
+        _sites: &Sites,
+        _cache: &mut [Self::SiteCache],
+        _move_from: u32,
+        _move_to: u32,
+    ) {
+    }
+
+    /// Energy of the atom at `site`. Summed over all atoms this is the energy of the particle, so
+    /// terms shared by several atoms have to be counted for one of them only.
+    fn site_energy(&self, sites: &Sites, cache: &[Self::SiteCache], site: u32) -> i64;
+
+    /// Energy change of moving the atom at `move_from` to the empty neighbor site `move_to`.
+    fn move_energy_diff(
+        &self,
+        sites: &Sites,
+        cache: &[Self::SiteCache],
+        move_from: u32,
+        move_to: u32,
+    ) -> i64;
+
+    /// Energy change of swapping the elements of the atoms at `site_a` and `site_b`, zero by
+    /// default for models that do not depend on the element.
+    fn swap_energy_diff(
+        &self,
+        _sites: &Sites,
+        _cache: &[Self::SiteCache],
+        _site_a: u32,
+        _site_b: u32,
+    ) -> i64 {
+        0
+    }
+}
+
+/// An energy on top of the one of the [`EnergyModel`], like the second shell, support or free
+/// energy, which can be combined with any model. `Simulation` is generic over its terms like over
+/// its model and adds them to the energy of every metal atom and to the change by every move and
+/// swap. [`EnergyTerms`] holds the built-in ones, `()` is no term at all.
+pub trait EnergyTerm: Clone + std::fmt::Debug + Send {
+    /// Checks the term against the grid and the number of `elements` of the particle.
+    fn check(&self, _gridstructure: &GridStructure, _elements: usize) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Energy of the metal atom at `site`.
+    fn site_energy(&self, sites: &Sites, site: u32) -> i64;
+
+    /// Energy change of moving the atom at `move_from` to the empty neighbor site `move_to`.
+    fn move_energy_diff(&self, sites: &Sites, move_from: u32, move_to: u32) -> i64;
+
+    /// Energy change of swapping the elements of the atoms at `site_a` and `site_b`, zero by
+    /// default for terms that do not depend on the element.
+    fn swap_energy_diff(&self, _sites: &Sites, _site_a: u32, _site_b: u32) -> i64 {
+        0
+    }
+
+    /// Whether the energies have to be rebuilt for the annealing temperature `temperature`.
+    fn is_outdated(&self, _temperature: f64) -> bool {
+        false
+    }
+
+    /// Rebuilds the energies for the annealing temperature `temperature`.
+    fn set_temperature(&mut self, _temperature: f64) {}
+}
+
+impl EnergyTerm for () {
+    fn site_energy(&self, _sites: &Sites, _site: u32) -> i64 {
+        0
+    }
+
+    fn move_energy_diff(&self, _sites: &Sites, _move_from: u32, _move_to: u32) -> i64 {
+        0
+    }
+}
+
+/// A term that is left out has no energy.
+impl<T: EnergyTerm> EnergyTerm for Option<T> {
+    fn check(&self, gridstructure: &GridStructure, elements: usize) -> anyhow::Result<()> {
+        self.as_ref()
+            .map_or(Ok(()), |term| term.check(gridstructure, elements))
+    }
+
+    #[inline]
+    fn site_energy(&self, sites: &Sites, site: u32) -> i64 {
+        self.as_ref()
+            .map_or(0, |term| term.site_energy(sites, site))
+    }
+
+    #[inline]
+    fn move_energy_diff(&self, sites: &Sites, move_from: u32, move_to: u32) -> i64 {
+        self.as_ref()
+            .map_or(0, |term| term.move_energy_diff(sites, move_from, move_to))
+    }
+
+    #[inline]
+    fn swap_energy_diff(&self, sites: &Sites, site_a: u32, site_b: u32) -> i64 {
+        self.as_ref()
+            .map_or(0, |term| term.swap_energy_diff(sites, site_a, site_b))
+    }
+
+    fn is_outdated(&self, temperature: f64) -> bool {
+        self.as_ref()
+            .is_some_and(|term| term.is_outdated(temperature))
+    }
+
+    fn set_temperature(&mut self, temperature: f64) {
+        if let Some(term) = self {
+            term.set_temperature(temperature);
+        }
+    }
+}
+
+/// The terms of the command line, each of them optional.
+#[derive(Clone, Debug, Default)]
+pub struct EnergyTerms {
+    pub second_shell: Option<SecondShellEnergy>,
+    pub support: Option<SupportEnergy>,
+    pub gas_adsorption: Option<GasAdsorptionEnergy>,
+    pub free_energy: Option<FreeEnergy>,
+}
+
+impl EnergyTerm for EnergyTerms {
+    fn check(&self, gridstructure: &GridStructure, elements: usize) -> anyhow::Result<()> {
+        self.second_shell.check(gridstructure, elements)?;
+        self.support.check(gridstructure, elements)?;
+        self.gas_adsorption.check(gridstructure, elements)?;
+        self.free_energy.check(gridstructure, elements)
+    }
+
+    #[inline]
+    fn site_energy(&self, sites: &Sites, site: u32) -> i64 {
+        self.second_shell.site_energy(sites, site)
+            + self.support.site_energy(sites, site)
+            + self.gas_adsorption.site_energy(sites, site)
+            + self.free_energy.site_energy(sites, site)
+    }
+
+    #[inline]
+    fn move_energy_diff(&self, sites: &Sites, move_from: u32, move_to: u32) -> i64 {
+        self.second_shell
+            .move_energy_diff(sites, move_from, move_to)
+            + self.support.move_energy_diff(sites, move_from, move_to)
+            + self
+                .gas_adsorption
+                .move_energy_diff(sites, move_from, move_to)
+            + self.free_energy.move_energy_diff(sites, move_from, move_to)
+    }
+
+    #[inline]
+    fn swap_energy_diff(&self, sites: &Sites, site_a: u32, site_b: u32) -> i64 {
+        self.second_shell.swap_energy_diff(sites, site_a, site_b)
+            + self.support.swap_energy_diff(sites, site_a, site_b)
+            + self.gas_adsorption.swap_energy_diff(sites, site_a, site_b)
+            + self.free_energy.swap_energy_diff(sites, site_a, site_b)
+    }
+
+    /// Only the free energy depends on the temperature.
+    fn is_outdated(&self, temperature: f64) -> bool {
+        self.free_energy.is_outdated(temperature)
+    }
+
+    fn set_temperature(&mut self, temperature: f64) {
+        self.free_energy.set_temperature(temperature);
+    }
+}
+
+/// The cache of the second shell counts is `AtomPosition::cn2_metal`, kept by `Simulation` for
+/// grids with second shell neighbors.
+impl EnergyTerm for SecondShellEnergy {
+    fn check(&self, gridstructure: &GridStructure, _elements: usize) -> anyhow::Result<()> {
+        if gridstructure.cn2() == 0 {
+            bail!("a second shell energy needs the second shell neighbors of the grid");
+        }
+        if self.energy.len() != gridstructure.cn2() + 1 {
+            bail!(
+                "a second shell energy needs {} values, one for every number of second shell \
+                 neighbors, but has {}",
+                gridstructure.cn2() + 1,
+                self.energy.len()
+            );
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn site_energy(&self, sites: &Sites, site: u32) -> i64 {
+        self.energy[sites.atom(site).cn2_metal as usize]
+    }
+
+    /// Atoms in the second shell of both sites keep their number of second shell neighbors.
+    fn move_energy_diff(&self, sites: &Sites, move_from: u32, move_to: u32) -> i64 {
+        let energy = &self.energy;
+        let from_shell = sites.gridstructure.nn2(move_from);
+        let to_shell = sites.gridstructure.nn2(move_to);
+        let mut energy_diff_1000 = energy[sites.atom(move_to).cn2_metal as usize]
+            - energy[sites.atom(move_from).cn2_metal as usize];
+        for o in from_shell.iter().filter(|x| !to_shell.contains(x)) {
+            let atom = sites.atom(*o);
+            if atom.is_metal() {
+                energy_diff_1000 +=
+                    energy[atom.cn2_metal as usize - 1] - energy[atom.cn2_metal as usize];
+            }
+        }
+        for o in to_shell.iter().filter(|x| !from_shell.contains(x)) {
+            let atom = sites.atom(*o);
+            if atom.is_metal() {
+                energy_diff_1000 +=
+                    energy[atom.cn2_metal as usize + 1] - energy[atom.cn2_metal as usize];
+            }
+        }
+        energy_diff_1000
+    }
+}
+
+impl EnergyTerm for SupportEnergy {
+    fn check(&self, gridstructure: &GridStructure, elements: usize) -> anyhow::Result<()> {
+        if self.energy.len() != elements {
+            bail!(
+                "a support energy needs a table for each of the {} elements but has {}",
+                elements,
+                self.energy.len()
+            );
+        }
+        if self
+            .energy
+            .iter()
+            .flatten()
+            .any(|x| x.len() != gridstructure.cn() + 1)
+        {
+            bail!(
+                "a support energy needs a value for every CN from 0 to {}",
+                gridstructure.cn()
+            );
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn site_energy(&self, sites: &Sites, site: u32) -> i64 {
+        let atom = sites.atom(site);
+        self.atom_energy(atom.element(), atom.support_contacts, atom.cn_metal)
+    }
+
+    fn move_energy_diff(&self, sites: &Sites, move_from: u32, move_to: u32) -> i64 {
+        sites.cn_energy_change(move_from, move_to, |element, contacts, cn| {
+            self.atom_energy(element, contacts, cn)
+        })
+    }
+
+    fn swap_energy_diff(&self, sites: &Sites, site_a: u32, site_b: u32) -> i64 {
+        sites.cn_energy_change_by_swap(site_a, site_b, |element, contacts, cn| {
+            self.atom_energy(element, contacts, cn)
+        })
+    }
+}
+
+/// The table is rebuilt once the annealing temperature moved away from its temperature by
+/// `FREE_ENERGY_TEMPERATURE_STEP`.
+impl EnergyTerm for FreeEnergy {
+    fn check(&self, gridstructure: &GridStructure, elements: usize) -> anyhow::Result<()> {
+        if self.table().len() != elements
+            || self
+                .table()
+                .iter()
+                .any(|x| x.len() != gridstructure.cn() + 1)
+        {
+            bail!("a free energy needs a value for every element and CN");
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn site_energy(&self, sites: &Sites, site: u32) -> i64 {
+        let atom = sites.atom(site);
+        self.atom_energy(atom.element(), atom.cn_metal)
+    }
+
+    fn move_energy_diff(&self, sites: &Sites, move_from: u32, move_to: u32) -> i64 {
+        sites.cn_energy_change(move_from, move_to, |element, _, cn| {
+            self.atom_energy(element, cn)
+        })
+    }
+
+    fn swap_energy_diff(&self, sites: &Sites, site_a: u32, site_b: u32) -> i64 {
+        sites.cn_energy_change_by_swap(site_a, site_b, |element, _, cn| {
+            self.atom_energy(element, cn)
+        })
+    }
+
+    fn is_outdated(&self, temperature: f64) -> bool {
+        (temperature - self.temperature()).abs() >= super::FREE_ENERGY_TEMPERATURE_STEP
+    }
+
+    fn set_temperature(&mut self, temperature: f64) {
+        FreeEnergy::set_temperature(self, temperature);
+    }
+}
+
+impl EnergyTerm for GasAdsorptionEnergy {
+    fn check(&self, gridstructure: &GridStructure, elements: usize) -> anyhow::Result<()> {
+        if self.energy.len() != elements
+            || self
+                .energy
+                .iter()
+                .any(|x| x.len() != gridstructure.cn() + 1)
+        {
+            bail!("a gas adsorption energy needs a value for every element and CN");
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn site_energy(&self, sites: &Sites, site: u32) -> i64 {
+        let atom = sites.atom(site);
+        self.atom_energy(atom.element(), atom.cn_metal)
+    }
+
+    fn move_energy_diff(&self, sites: &Sites, move_from: u32, move_to: u32) -> i64 {
+        sites.cn_energy_change(move_from, move_to, |element, _, cn| {
+            self.atom_energy(element, cn)
+        })
+    }
+
+    fn swap_energy_diff(&self, sites: &Sites, site_a: u32, site_b: u32) -> i64 {
+        sites.cn_energy_change_by_swap(site_a, site_b, |element, _, cn| {
+            self.atom_energy(element, cn)
+        })
+    }
+}
+
+/// `LinearCn` energies, a slope and intercept of the CN.
+impl EnergyModel for EnergyValues<[i64; 2]> {
+    type SiteCache = ();
+
+    #[inline]
+    fn site_energy(&self, sites: &Sites, _cache: &[()], site: u32) -> i64 {
+        let atom = sites.atom(site);
+        self.complet_energy[0] * atom.cn_metal as i64
+            + self.complet_energy[1]
+            + sites.support_e * atom.nn_support() as i64
+    }
+
+    #[inline]
+    fn move_energy_diff(&self, sites: &Sites, _cache: &[()], move_from: u32, move_to: u32) -> i64 {
+        let (from_at_support, to_at_support, support_e) = sites.move_support(move_from, move_to);
+        energy::energy_diff_l_cn(
+            self.complet_energy,
+            sites.atom(move_from).cn_metal,
+            sites.atom(move_to).cn_metal - 1,
+            from_at_support,
+            to_at_support,
+            support_e,
+        )
+    }
+}
+
+/// `Cn` energies, a value for every CN.
+impl EnergyModel for EnergyValues<Vec<i64>> {
+    type SiteCache = ();
+
+    #[inline]
+    fn site_energy(&self, sites: &Sites, _cache: &[()], site: u32) -> i64 {
+        let atom = sites.atom(site);
+        let at_support = atom.nn_support() as i64;
+        let co_ads = self
+            .co_ads_energy
+            .as_ref()
+            .map_or(0, |co_ads| co_ads[atom.cn_metal]);
+        self.complet_energy[atom.cn_metal] + (co_ads + sites.support_e) * at_support
+    }
+
+    #[inline]
+    fn move_energy_diff(&self, sites: &Sites, _cache: &[()], move_from: u32, move_to: u32) -> i64 {
+        let (from_at_support, to_at_support, support_e) = sites.move_support(move_from, move_to);
+        let (from_change, to_change) = sites.no_int_nn_from_move(move_from, move_to);
+        energy::energy_diff_cn(
+            self,
+            sites
+                .metal_atoms(from_change)
+                .map(|x| (x.cn_metal, x.nn_support())),
+            sites
+                .metal_atoms(to_change)
+                .map(|x| (x.cn_metal, x.nn_support())),
+            sites.atom(move_from).cn_metal,
+            sites.atom(move_to).cn_metal,
+            from_at_support,
+            to_at_support,
+            support_e,
+        )
+    }
+}
+
+impl EnergyModel for PowerCnEnergy {
+    type SiteCache = ();
+
+    #[inline]
+    fn site_energy(&self, sites: &Sites, cache: &[()], site: u32) -> i64 {
+        self.table.site_energy(sites, cache, site)
+    }
+
+    #[inline]
+    fn move_energy_diff(&self, sites: &Sites, cache: &[()], move_from: u32, move_to: u32) -> i64 {
+        self.table
+            .move_energy_diff(sites, cache, move_from, move_to)
+    }
+}
+
+impl EnergyModel for AlloyEnergy {
+    type SiteCache = ();
+
+    fn site_energy(&self, sites: &Sites, _cache: &[()], site: u32) -> i64 {
+        let atom = sites.atom(site);
+        // every bond once
+        self.atom_energy(
+            atom.element(),
+            atom.cn_metal,
+            atom.nn_support(),
+            sites.support_e,
+        ) + self.bond_energy(
+            atom.element(),
+            sites
+                .gridstructure
+                .nn(site)
+                .iter()
+                .filter(|u| **u > site)
+                .map(|u| sites.atom(*u))
+                .filter(|u| u.is_metal())
+                .map(|u| u.element()),
+        )
+    }
+
+    fn move_energy_diff(&self, sites: &Sites, _cache: &[()], move_from: u32, move_to: u32) -> i64 {
+        let (from_at_support, to_at_support, support_e) = sites.move_support(move_from, move_to);
+        let (from_change, to_change) = sites.no_int_nn_from_move(move_from, move_to);
+        let element = sites.atom(move_from).element();
+        self.neighbor_energy_diff(
+            sites
+                .metal_atoms(from_change)
+                .map(|x| (x.element(), x.cn_metal, x.nn_support())),
+            sites
+                .metal_atoms(to_change)
+                .map(|x| (x.element(), x.cn_metal, x.nn_support())),
+        ) + self.atom_energy(
+            element,
+            sites.atom(move_to).cn_metal - 1,
+            to_at_support,
+            support_e,
+        ) - self.atom_energy(
+            element,
+            sites.atom(move_from).cn_metal,
+            from_at_support,
+            support_e,
+        ) + self.bond_energy(element, sites.neighbor_elements_without(move_to, move_from))
+            - self.bond_energy(
+                element,
+                sites
+                    .metal_atoms(sites.gridstructure.nn(move_from))
+                    .map(|x| x.element()),
+            )
+    }
+
+    /// All coordination numbers stay the same, only the CN energies of the two atoms and the
+    /// mixing energies of their bonds change.
+    fn swap_energy_diff(&self, sites: &Sites, _cache: &[()], site_a: u32, site_b: u32) -> i64 {
+        let (atom_a, atom_b) = (sites.atom(site_a), sites.atom(site_b));
+        let (element_a, element_b) = (atom_a.element(), atom_b.element());
+        let atom_energy = |element, atom: &AtomPosition| {
+            self.atom_energy(element, atom.cn_metal, atom.nn_support(), sites.support_e)
+        };
+        atom_energy(element_b, atom_a) + atom_energy(element_a, atom_b)
+            - atom_energy(element_a, atom_a)
+            - atom_energy(element_b, atom_b)
+            + bond_change_by_swap(sites, site_a, site_b, |element, neighbors| {
+                self.bond_energy(element, neighbors)
+            })
+    }
+}
+
+impl EnergyModel for PairBondEnergy {
+    type SiteCache = ();
+
+    fn site_energy(&self, sites: &Sites, _cache: &[()], site: u32) -> i64 {
+        let atom = sites.atom(site);
+        // every bond once
+        self.atom_energy[atom.element()]
+            + sites.support_e * atom.nn_support() as i64
+            + self.bond_energy(
+                atom.element(),
+                sites
+                    .gridstructure
+                    .nn(site)
+                    .iter()
+                    .filter(|u| **u > site)
+                    .map(|u| sites.atom(*u))
+                    .filter(|u| u.is_metal())
+                    .map(|u| u.element()),
+            )
+    }
+
+    fn move_energy_diff(&self, sites: &Sites, _cache: &[()], move_from: u32, move_to: u32) -> i64 {
+        let (from_at_support, to_at_support, support_e) = sites.move_support(move_from, move_to);
+        self.energy_diff(
+            sites.atom(move_from).element(),
+            sites
+                .metal_atoms(sites.gridstructure.nn(move_from))
+                .map(|x| x.element()),
+            sites.neighbor_elements_without(move_to, move_from),
+            from_at_support,
+            to_at_support,
+            support_e,
+        )
+    }
+
+    fn swap_energy_diff(&self, sites: &Sites, _cache: &[()], site_a: u32, site_b: u32) -> i64 {
+        bond_change_by_swap(sites, site_a, site_b, |element, neighbors| {
+            self.bond_energy(element, neighbors)
+        })
+    }
+}
+
+/// The cache holds the sum of the CNs of the metal neighbors of every site.
+impl EnergyModel for GcnEnergy {
+    type SiteCache = u16;
+
+    fn init_cache(&self, sites: &Sites, cache: &mut [u16]) {
+        for (site, cn_sum) in cache.iter_mut().enumerate() {
+            *cn_sum = sites
+                .metal_atoms(sites.gridstructure.nn(site as u32))
+                .map(|x| x.cn_metal as u16)
+                .sum();
+        }
+    }
+
+    fn update_cache(&self, sites: &Sites, cache: &mut [u16], move_from: u32, move_to: u32) {
+        for (site, change) in cn_sum_changes(sites, move_from, move_to) {
+            let cn_sum = &mut cache[site as usize];
+            *cn_sum = (*cn_sum as i64 + change) as u16;
+        }
+    }
+
+    #[inline]
+    fn site_energy(&self, sites: &Sites, cache: &[u16], site: u32) -> i64 {
+        let atom = sites.atom(site);
+        self.atom_energy(
+            atom.element(),
+            cache[site as usize] as usize,
+            atom.nn_support(),
+            sites.support_e,
+        )
+    }
+
+    fn move_energy_diff(&self, sites: &Sites, cache: &[u16], move_from: u32, move_to: u32) -> i64 {
+        let (from_at_support, to_at_support, support_e) = sites.move_support(move_from, move_to);
+        let element = sites.atom(move_from).element();
+        let mut energy_diff_1000 = 0;
+        let mut to_cn_sum = cache[move_to as usize] as i64;
+        for (site, change) in cn_sum_changes(sites, move_from, move_to) {
+            if site == move_to {
+                to_cn_sum += change;
+            } else if site != move_from && sites.atom(site).is_metal() {
+                let element = sites.atom(site).element();
+                let cn_sum = cache[site as usize] as i64;
+                energy_diff_1000 += self.atom_energy(element, (cn_sum + change) as usize, 0, 0)
+                    - self.atom_energy(element, cn_sum as usize, 0, 0);
+            }
+        }
+        energy_diff_1000 + self.atom_energy(element, to_cn_sum as usize, to_at_support, support_e)
+            - self.atom_energy(
+                element,
+                cache[move_from as usize] as usize,
+                from_at_support,
+                support_e,
+            )
+    }
+
+    /// The sums of the neighbor CNs stay the same, only the two atoms change their energies.
+    fn swap_energy_diff(&self, sites: &Sites, cache: &[u16], site_a: u32, site_b: u32) -> i64 {
+        let (element_a, element_b) = (sites.atom(site_a).element(), sites.atom(site_b).element());
+        let atom_energy =
+            |element, site: u32| self.atom_energy(element, cache[site as usize] as usize, 0, 0);
+        atom_energy(element_b, site_a) + atom_energy(element_a, site_b)
+            - atom_energy(element_a, site_a)
+            - atom_energy(element_b, site_b)
+    }
+}
+
+/// Only the clusters of the changed sites are summed for a move or a swap.
+impl EnergyModel for ClusterExpansion {
+    type SiteCache = ();
+
+    fn site_energy(&self, sites: &Sites, _cache: &[()], site: u32) -> i64 {
+        let atom = sites.atom(site);
+        // every cluster once, from its smallest site
+        self.atom_energy[atom.element()]
+            + sites.support_e * atom.nn_support() as i64
+            + self.cluster_energy(site, atom.element(), |o| {
+                let other = sites.atom(o);
+                (o > site && other.is_metal()).then(|| other.element())
+            })
+    }
+
+    fn move_energy_diff(&self, sites: &Sites, _cache: &[()], move_from: u32, move_to: u32) -> i64 {
+        let (from_at_support, to_at_support, support_e) = sites.move_support(move_from, move_to);
+        let element = sites.atom(move_from).element();
+        let metal_element = |o: u32| {
+            let other = sites.atom(o);
+            other.is_metal().then(|| other.element())
+        };
+        // clusters with both sites have an empty site before and after the move
+        self.cluster_energy(move_to, element, |o| {
+            if o == move_from {
+                None
+            } else {
+                metal_element(o)
+            }
+        }) - self.cluster_energy(move_from, element, metal_element)
+            + support_e * (to_at_support as i64 - from_at_support as i64)
+    }
+
+    fn swap_energy_diff(&self, sites: &Sites, _cache: &[()], site_a: u32, site_b: u32) -> i64 {
+        let (element_a, element_b) = (sites.atom(site_a).element(), sites.atom(site_b).element());
+        let before = |o: u32| {
+            let other = sites.atom(o);
+            other.is_metal().then(|| other.element())
+        };
+        let after = |o: u32| {
+            if o == site_a {
+                Some(element_b)
+            } else if o == site_b {
+                Some(element_a)
+            } else {
+                before(o)
+            }
+        };
+        // clusters with both sites are counted with site_a only
+        self.cluster_energy(site_a, element_b, after)
+            - self.cluster_energy(site_a, element_a, before)
+            + self.cluster_energy(site_b, element_a, |o| {
+                (o != site_a).then(|| after(o)).flatten()
+            })
+            - self.cluster_energy(site_b, element_b, |o| {
+                (o != site_a).then(|| before(o)).flatten()
+            })
+    }
+}
+
+/// Dispatches to the model of the variant, the cache is only used by `Gcn`.
+impl EnergyModel for EnergyInput {
+    type SiteCache = u16;
+
+    fn init_cache(&self, sites: &Sites, cache: &mut [u16]) {
+        if let EnergyInput::Gcn(gcn) = self {
+            gcn.init_cache(sites, cache);
+        }
+    }
+
+    fn update_cache(&self, sites: &Sites, cache: &mut [u16], move_from: u32, move_to: u32) {
+        if let EnergyInput::Gcn(gcn) = self {
+            gcn.update_cache(sites, cache, move_from, move_to);
+        }
+    }
+
+    #[inline]
+    fn site_energy(&self, sites: &Sites, cache: &[u16], site: u32) -> i64 {
+        match self {
+            EnergyInput::LinearCn(e) => e.site_energy(sites, &[], site),
+            EnergyInput::Cn(e) => e.site_energy(sites, &[], site),
+            EnergyInput::AlloyCn(e) => e.site_energy(sites, &[], site),
+            EnergyInput::PairBond(e) => e.site_energy(sites, &[], site),
+            EnergyInput::Gcn(e) => e.site_energy(sites, cache, site),
+            EnergyInput::PowerCn(e) => e.site_energy(sites, &[], site),
+            EnergyInput::ClusterExpansion(e) => e.site_energy(sites, &[], site),
+        }
+    }
+
+    #[inline]
+    fn move_energy_diff(&self, sites: &Sites, cache: &[u16], move_from: u32, move_to: u32) -> i64 {
+        match self {
+            EnergyInput::LinearCn(e) => e.move_energy_diff(sites, &[], move_from, move_to),
+            EnergyInput::Cn(e) => e.move_energy_diff(sites, &[], move_from, move_to),
+            EnergyInput::AlloyCn(e) => e.move_energy_diff(sites, &[], move_from, move_to),
+            EnergyInput::PairBond(e) => e.move_energy_diff(sites, &[], move_from, move_to),
+            EnergyInput::Gcn(e) => e.move_energy_diff(sites, cache, move_from, move_to),
+            EnergyInput::PowerCn(e) => e.move_energy_diff(sites, &[], move_from, move_to),
+            EnergyInput::ClusterExpansion(e) => e.move_energy_diff(sites, &[], move_from, move_to),
+        }
+    }
+
+    fn swap_energy_diff(&self, sites: &Sites, cache: &[u16], site_a: u32, site_b: u32) -> i64 {
+        match self {
+            EnergyInput::LinearCn(e) => e.swap_energy_diff(sites, &[], site_a, site_b),
+            EnergyInput::Cn(e) => e.swap_energy_diff(sites, &[], site_a, site_b),
+            EnergyInput::AlloyCn(e) => e.swap_energy_diff(sites, &[], site_a, site_b),
+            EnergyInput::PairBond(e) => e.swap_energy_diff(sites, &[], site_a, site_b),
+            EnergyInput::Gcn(e) => e.swap_energy_diff(sites, cache, site_a, site_b),
+            EnergyInput::PowerCn(e) => e.swap_energy_diff(sites, &[], site_a, site_b),
+            EnergyInput::ClusterExpansion(e) => e.swap_energy_diff(sites, &[], site_a, site_b),
+        }
+    }
+}
+
+/// Change of the bond energies of two swapped atoms with their other neighbors, the bond between
+/// the two atoms stays the same.
+fn bond_change_by_swap<F>(sites: &Sites, site_a: u32, site_b: u32, bond_energy: F) -> i64
+where
+    F: Fn(usize, &mut dyn Iterator<Item = usize>) -> i64,
+{
+    let bonds = |element, site: u32, other: u32| {
+        bond_energy(element, &mut sites.neighbor_elements_without(site, other))
+    };
+    let (element_a, element_b) = (sites.atom(site_a).element(), sites.atom(site_b).element());
+    bonds(element_b, site_a, site_b) + bonds(element_a, site_b, site_a)
+        - bonds(element_a, site_a, site_b)
+        - bonds(element_b, site_b, site_a)
+}
+
+/// Changes of the sums of the neighbor CNs by an atom moving from `move_from` to `move_to`, at
+/// most one entry per site and none for unchanged sites. The CN of the neighbors of both sites
+/// changes and the atom takes its CN contribution with it.
+fn cn_sum_changes(sites: &Sites, move_from: u32, move_to: u32) -> Vec<(u32, i64)> {
+    let from_nn = sites.gridstructure.nn(move_from);
+    let to_nn = sites.gridstructure.nn(move_to);
+    // change of the CN contribution of every site whose CN or occupancy changes
+    let mut contributions: Vec<(u32, i64)> = Vec::with_capacity(2 * from_nn.len() + 2);
+    for o in from_nn {
+        if *o != move_to && !to_nn.contains(o) && sites.atom(*o).is_metal() {
+            contributions.push((*o, -1));
+        }
+    }
+    for o in to_nn {
+        if *o != move_from && !from_nn.contains(o) && sites.atom(*o).is_metal() {
+            contributions.push((*o, 1));
+        }
+    }
+    contributions.push((move_from, -(sites.atom(move_from).cn_metal as i64)));
+    contributions.push((move_to, sites.atom(move_to).cn_metal as i64 - 1));
+
+    let mut changes: Vec<(u32, i64)> = contributions
+        .iter()
+        .flat_map(|(site, change)| {
+            sites
+                .gridstructure
+                .nn(*site)
+                .iter()
+                .map(move |x| (*x, *change))
+        })
+        .collect();
+    changes.sort_unstable_by_key(|(site, _)| *site);
+    changes.dedup_by(|(site, change), (kept_site, kept_change)| {
+        if site == kept_site {
+            *kept_change += *change;
+            true
+        } else {
+            false
+        }
+    });
+    changes.retain(|(_, change)| *change != 0);
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{atoms_at, fcc_grid, particle, summed_energy};
+    use crate::{Lattice, Simulation, SimulationOptions};
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    /// Sums of the neighbor CNs computed from scratch.
+    fn cn_sums(sim: &Simulation<GcnEnergy>) -> Vec<u16> {
+        let mut cache = vec![0; sim.atom_pos.len()];
+        sim.energy.init_cache(&sim.sites(), &mut cache);
+        cache
+    }
+
+    #[test]
+    fn cn_sum_changes_match_recomputation() {
+        let mut sim = particle(
+            "gcn_cache",
+            fcc_grid([8, 8, 8]),
+            &[("Pt", 60)],
+            true,
+            GcnEnergy {
+                energy: vec![GcnEnergy::linear(-400, 3000, 12)],
+            },
+            SimulationOptions {
+                support_e: -170,
+                ..Default::default()
+            },
+        );
+        let mut rng = SmallRng::seed_from_u64(17);
+        for _ in 0..500 {
+            let (move_from, move_to, _) =
+                sim.possible_moves.choose_random_item_mc(&mut rng).unwrap();
+            let before = cn_sums(&sim);
+            let changes = cn_sum_changes(&sim.sites(), move_from, move_to);
+            assert!(changes.windows(2).all(|x| x[0].0 < x[1].0));
+
+            let energy1000_diff = sim.energy_change_by_move(move_from, move_to);
+            sim.perform_move(move_from, move_to, energy1000_diff, true);
+            sim.update_possible_moves(move_from, move_to);
+            let after = cn_sums(&sim);
+            let mut expected: Vec<(u32, i64)> = before
+                .iter()
+                .zip(&after)
+                .enumerate()
+                .filter(|(_, (before, after))| before != after)
+                .map(|(site, (before, after))| (site as u32, *after as i64 - *before as i64))
+                .collect();
+            expected.sort_unstable();
+            assert_eq!(changes, expected, "move from {} to {}", move_from, move_to);
+            assert_eq!(sim.energy_cache, after);
+        }
+    }
+
+    /// Sites of a cuboctahedron of 13 atoms, the center first. Every atom of the shell has the
+    /// center and 4 atoms of the shell as neighbors.
+    fn cuboctahedron(gridstructure: &GridStructure) -> Vec<u32> {
+        let center = 777;
+        std::iter::once(center)
+            .chain(gridstructure.nn(center).iter().copied())
+            .collect()
+    }
+
+    #[test]
+    fn known_energies_of_a_cuboctahedron() {
+        let gridstructure = fcc_grid([8, 8, 8]);
+        let sites = cuboctahedron(&gridstructure);
+        // a Ni atom in a shell of Pt
+        let atom_pos = atoms_at(
+            &gridstructure,
+            &sites
+                .iter()
+                .enumerate()
+                .map(|(i, site)| (*site, (i == 0) as usize))
+                .collect::<Vec<_>>(),
+        );
+        let sites_view = Sites {
+            atom_pos: &atom_pos,
+            gridstructure: &gridstructure,
+            support_e: 0,
+        };
+        let cn: Vec<usize> = sites
+            .iter()
+            .map(|x| atom_pos[*x as usize].cn_metal)
+            .collect();
+        assert_eq!(cn, [vec![12], vec![5; 12]].concat());
+
+        // 12 Pt-Ni and 24 Pt-Pt bonds
+        let pair_bond = PairBondEnergy {
+            bond_energy: vec![vec![-880, -900], vec![-900, -850]],
+            atom_energy: vec![3250, 3000],
+        };
+        assert_eq!(
+            summed_energy(&pair_bond, &(), &sites_view),
+            12 * 3250 + 3000 + 12 * -900 + 24 * -880
+        );
+
+        // the GCN is 60 / 12 = 5 for the center and 32 / 12 for the shell
+        let gcn = GcnEnergy {
+            energy: vec![GcnEnergy::linear(-400, 3000, 12); 2],
+        };
+        let mut cache = vec![0; atom_pos.len()];
+        gcn.init_cache(&sites_view, &mut cache);
+        let cn_sums: Vec<u16> = sites.iter().map(|x| cache[*x as usize]).collect();
+        assert_eq!(cn_sums, [vec![60], vec![32; 12]].concat());
+        assert_eq!(
+            summed_energy(&gcn, &(), &sites_view),
+            (3000 - 2000) + 12 * (3000 - 1067)
+        );
+
+        // 5840 (1 - sqrt(5 / 12)) for the shell, nothing for the center
+        let power_cn = PowerCnEnergy::new(5840, 0.5, 12);
+        assert_eq!(summed_energy(&power_cn, &(), &sites_view), 12 * 2070);
+    }
+
+    /// An atom with its 6 second shell neighbors, which are no second shell neighbors of each
+    /// other.
+    #[test]
+    fn known_second_shell_energy_of_an_octahedron() {
+        let mut gridstructure = GridStructure::generate(Lattice::Fcc, 3.92, [8, 8, 8]).unwrap();
+        gridstructure.add_second_shell().unwrap();
+        let center = 777;
+        let sites: Vec<(u32, usize)> = std::iter::once(center)
+            .chain(gridstructure.nn2(center).iter().copied())
+            .map(|site| (site, 0))
+            .collect();
+        let atom_pos = atoms_at(&gridstructure, &sites);
+        let sites_view = Sites {
+            atom_pos: &atom_pos,
+            gridstructure: &gridstructure,
+            support_e: 0,
+        };
+        let second_shell = SecondShellEnergy {
+            energy: vec![0, -10, -25, -45, -60, -70, -75],
+        };
+        second_shell.check(&gridstructure, 1).unwrap();
+        let energies: Vec<i64> = sites
+            .iter()
+            .map(|(site, _)| second_shell.site_energy(&sites_view, *site))
+            .collect();
+        assert_eq!(energies, [vec![-75], vec![-10; 6]].concat());
+        assert!(sites
+            .iter()
+            .all(|(site, _)| atom_pos[*site as usize].cn_metal == 0));
+    }
+}
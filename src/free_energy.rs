@@ -0,0 +1,375 @@
+use super::energy_input::Unit;
+use super::KB;
+use anyhow::{bail, Context};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// Free energies of an atom by its CN that depend on the temperature, read from JSON with the
+/// energies in milli eV unless `unit` is `"eV"`. `free_energy` gives a row of values for every
+/// CN from zero to the lattice CN at each of the `temperatures` in K, `vibration_energy` the
+/// quantum `hbar omega` of the vibrations of an atom by its CN. Both are given for all elements or
+/// by element, and either can be left out:
+/// ```json
+/// {
+///   "temperatures": [300, 600, 1000],
+///   "free_energy": {"Pt": [[...], [...], [...]]},
+///   "vibration_energy": [12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24]
+/// }
+/// ```
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct FreeEnergyInput {
+    #[serde(default)]
+    pub unit: Unit,
+    #[serde(default)]
+    pub temperatures: Vec<f64>,
+    pub free_energy: Option<ElementInput<Vec<Vec<f64>>>>,
+    pub vibration_energy: Option<ElementInput<Vec<f64>>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum ElementInput<T> {
+    All(T),
+    ByElement(BTreeMap<String, T>),
+}
+
+impl<T> ElementInput<T> {
+    /// Values of `element` and the key they are reported under.
+    fn get(&self, key: &str, element: &str) -> anyhow::Result<(String, &T)> {
+        match self {
+            ElementInput::All(values) => Ok((key.to_string(), values)),
+            ElementInput::ByElement(by_element) => {
+                let key = format!("{}.{}", key, element);
+                let values = by_element
+                    .get(element)
+                    .with_context(|| format!("free energy input has no {}", key))?;
+                Ok((key, values))
+            }
+        }
+    }
+
+    fn check_elements(&self, key: &str, atoms: &[String]) -> anyhow::Result<()> {
+        if let ElementInput::ByElement(by_element) = self {
+            if let Some(element) = by_element.keys().find(|x| !atoms.contains(x)) {
+                bail!(
+                    "{} has {} which is not one of the elements {:?}",
+                    key,
+                    element,
+                    atoms
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Free energy of an atom by its element and CN at the annealing temperature, added to the
+/// energies of the model. Tabulated free energies are interpolated linearly between their
+/// temperatures and kept constant outside of them. The vibrations are three harmonic oscillators
+/// per atom after Einstein with the free energy `3 (hbar omega / 2 + kT ln(1 - exp(-hbar omega / kT)))`,
+/// so atoms with low CN, which vibrate softly, gain stability at high temperatures.
+#[derive(Clone, Debug)]
+pub struct FreeEnergy {
+    /// Ascending temperatures in K of `tabulated`.
+    temperatures: Vec<f64>,
+    /// By temperature, element and CN.
+    tabulated: Vec<Vec<Vec<i64>>>,
+    /// `hbar omega` in milli eV by element and CN.
+    vibration_energy: Option<Vec<Vec<f64>>>,
+    temperature: f64,
+    /// By element and CN at `temperature`.
+    table: Vec<Vec<i64>>,
+}
+
+impl FreeEnergy {
+    /// Free energies of `input` for the elements `atoms` on a lattice of CN `cn` at
+    /// `temperature`.
+    pub fn new(
+        input: &FreeEnergyInput,
+        atoms: &[String],
+        cn: usize,
+        temperature: f64,
+    ) -> anyhow::Result<FreeEnergy> {
+        let unit = input.unit;
+        let check_len = |key: &str, len: usize| {
+            if len != cn + 1 {
+                bail!(
+                    "{} has {} values but needs one for every CN from 0 to {}",
+                    key,
+                    len,
+                    cn
+                );
+            }
+            Ok(())
+        };
+        if input.free_energy.is_none() && input.vibration_energy.is_none() {
+            bail!("free energy input has neither free_energy nor vibration_energy");
+        }
+        if input.temperatures.windows(2).any(|x| x[0] >= x[1])
+            || input.temperatures.iter().any(|x| *x < 0.)
+        {
+            bail!(
+                "the temperatures {:?} have to be positive and ascending",
+                input.temperatures
+            );
+        }
+
+        let mut tabulated = vec![Vec::with_capacity(atoms.len()); input.temperatures.len()];
+        if let Some(free_energy) = &input.free_energy {
+            if input.temperatures.is_empty() {
+                bail!("free_energy needs the temperatures of its rows");
+            }
+            free_energy.check_elements("free_energy", atoms)?;
+            for atom in atoms {
+                let (key, rows) = free_energy.get("free_energy", atom)?;
+                if rows.len() != input.temperatures.len() {
+                    bail!(
+                        "{} has {} rows but needs one for each of the {} temperatures",
+                        key,
+                        rows.len(),
+                        input.temperatures.len()
+                    );
+                }
+                for (by_temperature, row) in tabulated.iter_mut().zip(rows) {
+                    check_len(&key, row.len())?;
+                    by_temperature.push(
+                        row.iter()
+                            .map(|value| unit.to_milli_ev(&key, *value))
+                            .collect::<anyhow::Result<Vec<i64>>>()?,
+                    );
+                }
+            }
+        } else if !input.temperatures.is_empty() {
+            bail!("temperatures are given without free_energy");
+        }
+
+        let vibration_energy = match &input.vibration_energy {
+            Some(vibration_energy) => {
+                vibration_energy.check_elements("vibration_energy", atoms)?;
+                let by_element = atoms
+                    .iter()
+                    .map(|atom| {
+                        let (key, values) = vibration_energy.get("vibration_energy", atom)?;
+                        check_len(&key, values.len())?;
+                        if values.iter().any(|x| *x <= 0.) {
+                            bail!("{} has to be positive but is {:?}", key, values);
+                        }
+                        Ok(values.iter().map(|x| unit.to_milli_ev_f64(*x)).collect())
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                Some(by_element)
+            }
+            None => None,
+        };
+
+        let mut free_energy = FreeEnergy {
+            temperatures: input.temperatures.clone(),
+            tabulated,
+            vibration_energy,
+            temperature,
+            table: vec![vec![0; cn + 1]; atoms.len()],
+        };
+        free_energy.set_temperature(temperature);
+        Ok(free_energy)
+    }
+
+    /// Free energy in milli eV of three harmonic oscillators with the quantum
+    /// `vibration_energy` in milli eV, the zero point energy at 0 K.
+    pub fn harmonic_free_energy(vibration_energy: f64, temperature: f64) -> f64 {
+        let mut free_energy = vibration_energy / 2.;
+        if temperature > 0. {
+            let kt_1000 = 1000. * KB * temperature;
+            free_energy += kt_1000 * (-(-vibration_energy / kt_1000).exp()).ln_1p();
+        }
+        3. * free_energy
+    }
+
+    /// Temperature in K of the current table.
+    pub fn temperature(&self) -> f64 {
+        self.temperature
+    }
+
+    /// Rebuilds the table for `temperature` in K.
+    pub fn set_temperature(&mut self, temperature: f64) {
+        let mut table = self.tabulated_at(temperature);
+        if let Some(vibration_energy) = &self.vibration_energy {
+            for (row, vibration_energy) in table.iter_mut().zip(vibration_energy) {
+                for (energy, vibration_energy) in row.iter_mut().zip(vibration_energy) {
+                    *energy += FreeEnergy::harmonic_free_energy(*vibration_energy, temperature)
+                        .round() as i64;
+                }
+            }
+        }
+        self.temperature = temperature;
+        self.table = table;
+    }
+
+    /// Tabulated free energies interpolated to `temperature`, zeros without any.
+    fn tabulated_at(&self, temperature: f64) -> Vec<Vec<i64>> {
+        let temperatures = &self.temperatures;
+        let Some(last) = temperatures.len().checked_sub(1) else {
+            return vec![vec![0; self.table[0].len()]; self.table.len()];
+        };
+        let upper = temperatures.partition_point(|x| *x < temperature);
+        if upper == 0 {
+            return self.tabulated[0].clone();
+        } else if upper > last {
+            return self.tabulated[last].clone();
+        }
+        let fraction = (temperature - temperatures[upper - 1])
+            / (temperatures[upper] - temperatures[upper - 1]);
+        self.tabulated[upper - 1]
+            .iter()
+            .zip(&self.tabulated[upper])
+            .map(|(low, high)| {
+                low.iter()
+                    .zip(high)
+                    .map(|(low, high)| {
+                        (*low as f64 + fraction * (high - low) as f64).round() as i64
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Free energies by element and CN at the current temperature.
+    pub fn table(&self) -> &[Vec<i64>] {
+        &self.table
+    }
+
+    #[inline]
+    pub fn atom_energy(&self, element: usize, cn: usize) -> i64 {
+        self.table[element][cn]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::energy::PairBondEnergy;
+    use crate::energy_model::{EnergyTerm, EnergyTerms};
+    use crate::test_support::{fcc_grid, hop, particle, recomputed_energy};
+    use crate::SimulationOptions;
+    use rand::rngs::SmallRng;
+    use rand::SeedableRng;
+
+    /// Rows of `slope * cn` at 300 and 700 K for Pt.
+    fn tabulated(low: i64, high: i64) -> FreeEnergyInput {
+        let row = |slope: i64| (0..=12).map(|cn| (slope * cn) as f64).collect();
+        FreeEnergyInput {
+            unit: Unit::MilliElectronVolt,
+            temperatures: vec![300., 700.],
+            free_energy: Some(ElementInput::All(vec![row(low), row(high)])),
+            vibration_energy: None,
+        }
+    }
+
+    fn pt() -> Vec<String> {
+        vec!["Pt".to_string()]
+    }
+
+    #[test]
+    fn tabulated_energies_are_interpolated() {
+        let mut free_energy = FreeEnergy::new(&tabulated(-10, -30), &pt(), 12, 500.).unwrap();
+        assert_eq!(free_energy.atom_energy(0, 12), -240);
+        free_energy.set_temperature(400.);
+        assert_eq!(free_energy.atom_energy(0, 3), -45);
+        assert_eq!(free_energy.temperature(), 400.);
+        // -12.5 is rounded away from zero
+        free_energy.set_temperature(350.);
+        assert_eq!(free_energy.atom_energy(0, 1), -13);
+        free_energy.set_temperature(700.);
+        assert_eq!(free_energy.atom_energy(0, 12), -360);
+    }
+
+    #[test]
+    fn tabulated_energies_are_clamped_outside_the_temperatures() {
+        let mut free_energy = FreeEnergy::new(&tabulated(-10, -30), &pt(), 12, 0.).unwrap();
+        assert_eq!(free_energy.atom_energy(0, 12), -120);
+        free_energy.set_temperature(299.);
+        assert_eq!(free_energy.atom_energy(0, 12), -120);
+        free_energy.set_temperature(2000.);
+        assert_eq!(free_energy.atom_energy(0, 12), -360);
+    }
+
+    #[test]
+    fn harmonic_free_energy_limits() {
+        // zero point energy of three oscillators at 0 K
+        assert_eq!(FreeEnergy::harmonic_free_energy(20., 0.), 30.);
+        assert!(FreeEnergy::harmonic_free_energy(20., 300.) < 0.);
+        // classical limit 3 kT ln(hbar omega / kT) far above hbar omega / k
+        let kt_1000 = 1000. * KB * 10000.;
+        let classical = 3. * kt_1000 * (20. / kt_1000).ln();
+        assert!((FreeEnergy::harmonic_free_energy(20., 10000.) - classical).abs() < 0.1);
+    }
+
+    #[test]
+    fn rebuilt_table_adds_vibrations_to_the_tabulated_energies() {
+        let mut input = tabulated(-10, -30);
+        input.vibration_energy = Some(ElementInput::All(vec![20.; 13]));
+        let mut free_energy = FreeEnergy::new(&input, &pt(), 12, 300.).unwrap();
+        let vibrations =
+            |temperature| FreeEnergy::harmonic_free_energy(20., temperature).round() as i64;
+        assert_eq!(free_energy.atom_energy(0, 2), -20 + vibrations(300.));
+        free_energy.set_temperature(600.);
+        assert_eq!(free_energy.atom_energy(0, 2), -50 + vibrations(600.));
+        assert!(!free_energy.is_outdated(600.5));
+        assert!(free_energy.is_outdated(601.));
+    }
+
+    #[test]
+    fn invalid_input_is_rejected() {
+        let mut input = tabulated(-10, -30);
+        input.temperatures = vec![700., 300.];
+        assert!(FreeEnergy::new(&input, &pt(), 12, 300.).is_err());
+        input.temperatures = vec![300.];
+        assert!(FreeEnergy::new(&input, &pt(), 12, 300.).is_err());
+        assert!(FreeEnergy::new(&tabulated(-10, -30), &pt(), 8, 300.).is_err());
+        input.temperatures = Vec::new();
+        input.free_energy = None;
+        assert!(FreeEnergy::new(&input, &pt(), 12, 300.).is_err());
+    }
+
+    /// Rebuilding the table at a new annealing temperature shifts the energy of the particle to
+    /// the one summed up with the new table.
+    #[test]
+    fn energy_is_rebased_when_the_table_is_rebuilt() {
+        let mut input = tabulated(-10, -30);
+        input.vibration_energy = Some(ElementInput::All(
+            (0..=12).map(|cn| 10. + cn as f64).collect(),
+        ));
+        let free_energy = FreeEnergy::new(&input, &pt(), 12, 300.).unwrap();
+        let mut sim = particle(
+            "free_energy",
+            fcc_grid([8, 8, 8]),
+            &[("Pt", 60)],
+            false,
+            PairBondEnergy {
+                bond_energy: vec![vec![-880]],
+                atom_energy: vec![3250],
+            },
+            SimulationOptions {
+                start_temperature: 1000.,
+                terms: EnergyTerms {
+                    free_energy: Some(free_energy),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        assert_eq!(sim.total_energy_1000, recomputed_energy(&sim));
+        let mut rng = SmallRng::seed_from_u64(25);
+        for temperature in [900., 650., 450., 300.] {
+            for _ in 0..50 {
+                hop(&mut sim, &mut rng);
+            }
+            let before = sim.total_energy_1000;
+            sim.update_temperature(temperature);
+            assert_ne!(sim.total_energy_1000, before);
+            assert_eq!(sim.total_energy_1000, recomputed_energy(&sim));
+            sim.update_temperature(temperature + 0.5);
+            assert_eq!(sim.total_energy_1000, recomputed_energy(&sim));
+        }
+    }
+}
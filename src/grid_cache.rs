@@ -0,0 +1,492 @@
+use anyhow::{bail, Context};
+use fnv::FnvHasher;
+use memmap2::Mmap;
+use std::fs;
+use std::hash::Hasher;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+
+use super::site_table::SiteTable;
+use super::{Cell, GridStructure, Lattice};
+
+// Layout of a grid cache file, all numbers little endian:
+//
+//   0..8     magic "MCGRID\0\0"
+//   8..12    format version (u32)
+//   12..16   coordination number (u32)
+//   16..20   number of non-intersecting neighbors per pair (u32)
+//   20..24   lattice of generated grids, 0 unknown, 1 fcc, 2 bcc, 3 hcp (u32)
+//   24..32   number of sites (u64)
+//   32..104  cell matrix, one row per cell vector (9 x f64)
+//   104..112 FNV-1a checksum of everything after the header (u64)
+//   112..120 c/a ratio of hcp grids (f64)
+//   120..128 reserved
+//   128..192 size and FNV-1a hash (2 x u64) of each of the 4 text files the grid was read from,
+//            size u64::MAX for a missing file
+//
+// followed by the site positions (nsites x 3 x f64), the neighbor list (nsites x cn x u32) and for
+// every site and neighbor slot the non-intersecting neighbors of the site and of the neighbor
+// (nsites x cn x 2 x no_int x u32), laid out like the tables in GridStructure.
+const MAGIC: &[u8; 8] = b"MCGRID\0\0";
+const VERSION: u32 = 3;
+const HEADER_SIZE: usize = 192;
+const CHECKSUM_OFFSET: usize = 104;
+const SOURCES_OFFSET: usize = 128;
+const SOURCE_FILES: usize = 4;
+
+fn payload_size(nsites: usize, cn: usize, no_int_number: usize) -> usize {
+    nsites * 3 * 8 + nsites * cn * 4 + nsites * cn * 2 * no_int_number * 4
+}
+
+fn lattice_to_code(lattice: Option<Lattice>) -> (u32, f64) {
+    match lattice {
+        None => (0, 0.),
+        Some(Lattice::Fcc) => (1, 0.),
+        Some(Lattice::Bcc) => (2, 0.),
+        Some(Lattice::Hcp { c_over_a }) => (3, c_over_a),
+    }
+}
+
+fn lattice_from_code(code: u32, c_over_a: f64) -> Option<Lattice> {
+    match code {
+        1 => Some(Lattice::Fcc),
+        2 => Some(Lattice::Bcc),
+        3 => Some(Lattice::Hcp { c_over_a }),
+        _ => None,
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_f64(bytes: &[u8], offset: usize) -> f64 {
+    f64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Size and FNV-1a hash of a file, `u64::MAX` and 0 for a missing one.
+fn fingerprint(path: &str) -> std::io::Result<(u64, u64)> {
+    let mut file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok((u64::MAX, 0)),
+        Err(err) => return Err(err),
+    };
+    let mut hasher = FnvHasher::default();
+    let mut buffer = vec![0_u8; 1 << 16];
+    let mut size = 0;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            return Ok((size, hasher.finish()));
+        }
+        hasher.write(&buffer[..read]);
+        size += read as u64;
+    }
+}
+
+/// True if the cache exists and was created from text files of the same size and hash as
+/// `source_files`. Modification times are not compared, `cp -p` or `rsync -a` keep them.
+pub fn is_up_to_date(cache_file: &str, source_files: &[&str]) -> bool {
+    let mut header = [0_u8; HEADER_SIZE];
+    let is_readable = fs::File::open(cache_file).and_then(|mut file| file.read_exact(&mut header));
+    if is_readable.is_err()
+        || &header[0..8] != MAGIC
+        || read_u32(&header, 8) != VERSION
+        || source_files.len() != SOURCE_FILES
+    {
+        return false;
+    }
+    let recorded = |i: usize| -> (u64, u64) {
+        let offset = SOURCES_OFFSET + i * 16;
+        (read_u64(&header, offset), read_u64(&header, offset + 8))
+    };
+    // the sizes first, so that changed files are mostly not hashed
+    let size = |path: &str| fs::metadata(path).map_or(u64::MAX, |m| m.len());
+    source_files
+        .iter()
+        .enumerate()
+        .all(|(i, source)| size(source) == recorded(i).0)
+        && source_files
+            .iter()
+            .enumerate()
+            .all(|(i, source)| fingerprint(source).ok() == Some(recorded(i)))
+}
+
+pub fn read_grid_cache(cache_file: &str) -> anyhow::Result<GridStructure> {
+    println!("reading grid cache from: {}", cache_file);
+    let file = fs::File::open(cache_file)?;
+    // SAFETY: the mapped file must not change while it is in use. `write_grid_cache` is the only
+    // writer and renames a completely written file over the cache, which leaves the mapped file
+    // as it is. Editing the cache in place by other means is not supported. All processes
+    // mapping the same cache share its pages.
+    let mmap = unsafe { Mmap::map(&file)? };
+    let bytes: &[u8] = &mmap;
+
+    if bytes.len() < HEADER_SIZE || &bytes[0..8] != MAGIC {
+        bail!("{} is not a grid cache file", cache_file);
+    }
+    let version = read_u32(bytes, 8);
+    if version != VERSION {
+        bail!(
+            "grid cache version {} is not supported, expected version {}",
+            version,
+            VERSION
+        );
+    }
+    let cn = read_u32(bytes, 12) as usize;
+    let no_int_number = read_u32(bytes, 16) as usize;
+    if cn == 0 || no_int_number >= cn {
+        bail!(
+            "grid cache has {} neighbors and {} non-intersecting neighbors per pair",
+            cn,
+            no_int_number
+        );
+    }
+    let nsites = read_u64(bytes, 24) as usize;
+    let expected_size = HEADER_SIZE + payload_size(nsites, cn, no_int_number);
+    if bytes.len() != expected_size {
+        bail!(
+            "grid cache with {} sites should have {} bytes but has {}",
+            nsites,
+            expected_size,
+            bytes.len()
+        );
+    }
+    let cell: [[f64; 3]; 3] =
+        [0, 1, 2].map(|row| [0, 1, 2].map(|col| read_f64(bytes, 32 + (row * 3 + col) * 8)));
+    let cell = Cell::from_matrix(cell)?;
+    let lattice = lattice_from_code(read_u32(bytes, 20), read_f64(bytes, 112));
+
+    let positions_offset = HEADER_SIZE;
+    let nn_offset = positions_offset + nsites * 3 * 8;
+    let no_int_offset = nn_offset + nsites * cn * 4;
+    let no_int_len = nsites * cn * 2 * no_int_number;
+
+    let mmap = Arc::new(mmap);
+    // SAFETY: the tables only contain f64 and u32 values which are stored little endian
+    let tables = if cfg!(target_endian = "little") {
+        unsafe {
+            SiteTable::from_mmap(&mmap, positions_offset, nsites)
+                .zip(SiteTable::from_mmap(&mmap, nn_offset, nsites * cn))
+                .zip(SiteTable::from_mmap(&mmap, no_int_offset, no_int_len))
+        }
+    } else {
+        None
+    };
+    let ((xsites_positions, nn), nn_pair_no_intersec) = match tables {
+        Some(tables) => tables,
+        None => {
+            let bytes: &[u8] = &mmap;
+            let xsites_positions: Vec<[f64; 3]> = (0..nsites)
+                .map(|site| {
+                    [0, 1, 2].map(|d| read_f64(bytes, positions_offset + (site * 3 + d) * 8))
+                })
+                .collect();
+            let nn: Vec<u32> = (0..nsites * cn)
+                .map(|i| read_u32(bytes, nn_offset + i * 4))
+                .collect();
+            let nn_pair_no_intersec: Vec<u32> = (0..no_int_len)
+                .map(|i| read_u32(bytes, no_int_offset + i * 4))
+                .collect();
+            (
+                (xsites_positions.into(), nn.into()),
+                nn_pair_no_intersec.into(),
+            )
+        }
+    };
+
+    // the tables are indexed with these without bounds checks
+    if let Some(site) = nn
+        .iter()
+        .chain(nn_pair_no_intersec.iter())
+        .find(|x| **x as usize >= nsites)
+    {
+        bail!(
+            "grid cache {} lists site {} but has only {} sites",
+            cache_file,
+            site,
+            nsites
+        );
+    }
+
+    Ok(GridStructure {
+        cn,
+        nn_pair_no_intersec_number: no_int_number,
+        nn,
+        nn_pair_no_intersec,
+        cn2: 0,
+        nn2: Vec::new().into(),
+        xsites_positions,
+        cell,
+        lattice,
+    })
+}
+
+/// Compares the checksum in the header of the cache with the one of its tables. Loading a cache
+/// does not hash the tables, this is for checking a cache on demand.
+pub fn verify_grid_cache(cache_file: &str) -> anyhow::Result<()> {
+    let bytes = fs::read(cache_file)
+        .with_context(|| format!("could not read grid cache {}", cache_file))?;
+    if bytes.len() < HEADER_SIZE || &bytes[0..8] != MAGIC {
+        bail!("{} is not a grid cache file", cache_file);
+    }
+    let mut hasher = FnvHasher::default();
+    hasher.write(&bytes[HEADER_SIZE..]);
+    if hasher.finish() != read_u64(&bytes, CHECKSUM_OFFSET) {
+        bail!("checksum of grid cache {} does not match", cache_file);
+    }
+    Ok(())
+}
+
+/// Writes the cache of `gridstructure` with the size and hash of the `source_files` it was read
+/// from, see [`is_up_to_date`].
+pub fn write_grid_cache(
+    cache_file: &str,
+    gridstructure: &GridStructure,
+    source_files: &[&str],
+) -> anyhow::Result<()> {
+    println!("writing grid cache to: {}", cache_file);
+    if source_files.len() != SOURCE_FILES {
+        bail!(
+            "a grid cache records {} source files, got {}",
+            SOURCE_FILES,
+            source_files.len()
+        );
+    }
+    let fingerprints = source_files
+        .iter()
+        .map(|source| fingerprint(source))
+        .collect::<std::io::Result<Vec<(u64, u64)>>>()?;
+    // other processes may map the old cache at the same time, so the new one is written next to
+    // it and moved into place once it is complete
+    let tmp_file = format!("{}.{}.tmp", cache_file, std::process::id());
+    write_grid_cache_file(&tmp_file, gridstructure, &fingerprints).inspect_err(|_| {
+        fs::remove_file(&tmp_file).ok();
+    })?;
+    fs::rename(&tmp_file, cache_file)?;
+    Ok(())
+}
+
+fn write_grid_cache_file(
+    path: &str,
+    gridstructure: &GridStructure,
+    fingerprints: &[(u64, u64)],
+) -> anyhow::Result<()> {
+    let nsites = gridstructure.xsites_positions.len();
+    let file =
+        fs::File::create(path).with_context(|| format!("could not create grid cache {}", path))?;
+    let mut writer = BufWriter::new(file);
+    let mut hasher = FnvHasher::default();
+
+    let mut header = [0_u8; HEADER_SIZE];
+    header[0..8].copy_from_slice(MAGIC);
+    header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+    header[12..16].copy_from_slice(&(gridstructure.cn as u32).to_le_bytes());
+    header[16..20]
+        .copy_from_slice(&(gridstructure.nn_pair_no_intersec_number as u32).to_le_bytes());
+    let (lattice_code, c_over_a) = lattice_to_code(gridstructure.lattice);
+    header[20..24].copy_from_slice(&lattice_code.to_le_bytes());
+    header[24..32].copy_from_slice(&(nsites as u64).to_le_bytes());
+    for (i, x) in gridstructure.cell.matrix().iter().flatten().enumerate() {
+        let offset = 32 + i * 8;
+        header[offset..offset + 8].copy_from_slice(&x.to_le_bytes());
+    }
+    header[112..120].copy_from_slice(&c_over_a.to_le_bytes());
+    for (i, (size, hash)) in fingerprints.iter().enumerate() {
+        let offset = SOURCES_OFFSET + i * 16;
+        header[offset..offset + 8].copy_from_slice(&size.to_le_bytes());
+        header[offset + 8..offset + 16].copy_from_slice(&hash.to_le_bytes());
+    }
+    writer.write_all(&header)?;
+
+    let mut write_payload = |writer: &mut BufWriter<fs::File>, bytes: &[u8]| {
+        hasher.write(bytes);
+        writer.write_all(bytes)
+    };
+    for xyz in gridstructure.xsites_positions.iter() {
+        for x in xyz {
+            write_payload(&mut writer, &x.to_le_bytes())?;
+        }
+    }
+    for neighbor in gridstructure.nn.iter() {
+        write_payload(&mut writer, &neighbor.to_le_bytes())?;
+    }
+    for x in gridstructure.nn_pair_no_intersec.iter() {
+        write_payload(&mut writer, &x.to_le_bytes())?;
+    }
+
+    let mut file = writer.into_inner()?;
+    file.seek(SeekFrom::Start(CHECKSUM_OFFSET as u64))?;
+    file.write_all(&hasher.finish().to_le_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    /// Path of the cache in the folder of a test.
+    fn cache_file(folder: &TempDir) -> String {
+        folder.path().join("grid.bin").to_str().unwrap().to_string()
+    }
+
+    /// Source files of a cache in the folder of a test, which do not exist until a test writes
+    /// them.
+    fn sources(folder: &TempDir) -> [String; SOURCE_FILES] {
+        [
+            "nearest_neighbor",
+            "nn_pair_no_intersec",
+            "atom_sites",
+            "grid_file.xyz",
+        ]
+        .map(|name| folder.path().join(name).to_str().unwrap().to_string())
+    }
+
+    /// Writes the cache of a small hcp grid to a new temporary folder and returns the folder, the
+    /// path and the bytes of the cache.
+    fn written_cache() -> (TempDir, String, GridStructure, Vec<u8>) {
+        let gridstructure =
+            GridStructure::generate(Lattice::Hcp { c_over_a: 1.62 }, 2.5, [4, 3, 3]).unwrap();
+        let folder = tempfile::tempdir().unwrap();
+        let path = cache_file(&folder);
+        let sources = sources(&folder);
+        write_grid_cache(
+            &path,
+            &gridstructure,
+            &sources.each_ref().map(String::as_str),
+        )
+        .unwrap();
+        let bytes = fs::read(&path).unwrap();
+        (folder, path, gridstructure, bytes)
+    }
+
+    /// Replaces the cache with `bytes` the way `write_grid_cache` does, as an earlier read of it
+    /// may still be mapped.
+    fn replace(path: &str, bytes: Vec<u8>) {
+        let tmp_file = format!("{}.tmp", path);
+        fs::write(&tmp_file, bytes).unwrap();
+        fs::rename(&tmp_file, path).unwrap();
+    }
+
+    /// Writes `bytes` with the checksum of their payload.
+    fn write_with_checksum(path: &str, mut bytes: Vec<u8>) {
+        let mut hasher = FnvHasher::default();
+        hasher.write(&bytes[HEADER_SIZE..]);
+        bytes[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 8].copy_from_slice(&hasher.finish().to_le_bytes());
+        replace(path, bytes);
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let (_folder, path, written, _) = written_cache();
+        let read = read_grid_cache(&path).unwrap();
+        assert_eq!(read.cn, written.cn);
+        assert_eq!(
+            read.nn_pair_no_intersec_number,
+            written.nn_pair_no_intersec_number
+        );
+        assert_eq!(&read.nn[..], &written.nn[..]);
+        assert_eq!(
+            &read.nn_pair_no_intersec[..],
+            &written.nn_pair_no_intersec[..]
+        );
+        assert_eq!(&read.xsites_positions[..], &written.xsites_positions[..]);
+        assert_eq!(read.cell.matrix(), written.cell.matrix());
+        assert_eq!(read.lattice, Some(Lattice::Hcp { c_over_a: 1.62 }));
+    }
+
+    #[test]
+    fn non_orthogonal_cell_round_trip() {
+        // primitive rhombohedral cell of fcc Pt
+        let primitive =
+            Cell::from_lengths_angles([3.92 / 2_f64.sqrt(); 3], [60., 60., 60.]).unwrap();
+        let written = GridStructure::from_basis(&primitive, &[[0.; 3]], 3., [6, 6, 6]).unwrap();
+        assert_eq!(written.cn, 12);
+        assert!(!written.cell.is_orthorhombic());
+        let folder = tempfile::tempdir().unwrap();
+        let path = cache_file(&folder);
+        let sources = sources(&folder);
+        write_grid_cache(&path, &written, &sources.each_ref().map(String::as_str)).unwrap();
+        let read = read_grid_cache(&path).unwrap();
+        assert_eq!(read.cell, written.cell);
+        assert_eq!(read.cell.angles(), written.cell.angles());
+        assert_eq!(&read.xsites_positions[..], &written.xsites_positions[..]);
+        assert_eq!(&read.nn[..], &written.nn[..]);
+        assert_eq!(read.lattice, None);
+    }
+
+    #[test]
+    fn corrupted_header_is_rejected() {
+        let (_folder, path, _, bytes) = written_cache();
+        let mut magic = bytes.clone();
+        magic[0] = b'X';
+        replace(&path, magic);
+        let err = read_grid_cache(&path).err().unwrap().to_string();
+        assert!(err.contains("is not a grid cache file"), "{}", err);
+
+        let mut version = bytes.clone();
+        version[8..12].copy_from_slice(&1_u32.to_le_bytes());
+        replace(&path, version);
+        let err = read_grid_cache(&path).err().unwrap().to_string();
+        assert!(err.contains("version 1 is not supported"), "{}", err);
+
+        let mut nsites = bytes;
+        nsites[24..32].copy_from_slice(&1000_u64.to_le_bytes());
+        replace(&path, nsites);
+        let err = read_grid_cache(&path).err().unwrap().to_string();
+        assert!(err.contains("should have"), "{}", err);
+    }
+
+    #[test]
+    fn corrupted_payload_fails_the_checksum() {
+        let (_folder, path, _, mut bytes) = written_cache();
+        verify_grid_cache(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        replace(&path, bytes);
+        let err = verify_grid_cache(&path).err().unwrap().to_string();
+        assert!(err.contains("checksum"), "{}", err);
+    }
+
+    #[test]
+    fn changed_sources_are_detected_by_size_and_hash() {
+        let (folder, path, written, _) = written_cache();
+        let sources = sources(&folder);
+        let sources = sources.each_ref().map(String::as_str);
+        assert!(is_up_to_date(&path, &sources));
+        fs::write(sources[0], "0 1 2\n").unwrap();
+        assert!(!is_up_to_date(&path, &sources));
+
+        write_grid_cache(&path, &written, &sources).unwrap();
+        assert!(is_up_to_date(&path, &sources));
+        // same size and modification time, like a copy with `cp -p`
+        let modified = fs::metadata(sources[0]).unwrap().modified().unwrap();
+        fs::write(sources[0], "0 1 3\n").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(sources[0])
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        assert!(!is_up_to_date(&path, &sources));
+        fs::remove_file(sources[0]).unwrap();
+        assert!(!is_up_to_date(&path, &sources));
+    }
+
+    #[test]
+    fn out_of_range_site_is_rejected() {
+        let (_folder, path, written, mut bytes) = written_cache();
+        let nn_offset = HEADER_SIZE + written.nsites() * 3 * 8;
+        bytes[nn_offset..nn_offset + 4].copy_from_slice(&(written.nsites() as u32).to_le_bytes());
+        write_with_checksum(&path, bytes);
+        let err = read_grid_cache(&path).err().unwrap().to_string();
+        assert!(err.contains("has only"), "{}", err);
+    }
+}
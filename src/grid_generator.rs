@@ -0,0 +1,580 @@
+use anyhow::bail;
+use std::f64::consts::PI;
+
+use super::Cell;
+
+type SitesAndNeighbors = (Vec<[f64; 3]>, Vec<u32>, [f64; 3]);
+
+// particles elongate and roughen during the anneal, a grid leaves room for this many times the
+// diameter of a compact particle
+const PARTICLE_SPREAD: f64 = 1.5;
+
+// sites closer than this in Angstrom to the closest second shell neighbor are in the shell too
+const SHELL_TOLERANCE: f64 = 1e-2;
+
+/// Lattices the grid generator can build.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Lattice {
+    Fcc,
+    Bcc,
+    /// ABAB stacked layers along z, `c_over_a` is the ratio of the two lattice constants.
+    Hcp {
+        c_over_a: f64,
+    },
+}
+
+// fcc and bcc basis in units of half the lattice constant
+const FCC_BASIS: [[i64; 3]; 4] = [[0, 0, 0], [1, 1, 0], [1, 0, 1], [0, 1, 1]];
+const BCC_BASIS: [[i64; 3]; 2] = [[0, 0, 0], [1, 1, 1]];
+// orthorhombic hcp cell in units of a/2, sqrt(3)a/6 and c/2, A layer first
+const HCP_BASIS: [[i64; 3]; 4] = [[0, 0, 0], [1, 3, 0], [1, 1, 1], [0, 4, 1]];
+
+const FCC_NN_OFFSETS: [[i64; 3]; 12] = [
+    [1, 1, 0],
+    [1, -1, 0],
+    [-1, 1, 0],
+    [-1, -1, 0],
+    [1, 0, 1],
+    [1, 0, -1],
+    [-1, 0, 1],
+    [-1, 0, -1],
+    [0, 1, 1],
+    [0, 1, -1],
+    [0, -1, 1],
+    [0, -1, -1],
+];
+
+const BCC_NN_OFFSETS: [[i64; 3]; 8] = [
+    [1, 1, 1],
+    [1, 1, -1],
+    [1, -1, 1],
+    [1, -1, -1],
+    [-1, 1, 1],
+    [-1, 1, -1],
+    [-1, -1, 1],
+    [-1, -1, -1],
+];
+
+// the in-plane neighbors are the same for both layers, the B layer sees the A layers above and
+// below rotated by 180 degrees
+const HCP_A_NN_OFFSETS: [[i64; 3]; 12] = [
+    [2, 0, 0],
+    [-2, 0, 0],
+    [1, 3, 0],
+    [1, -3, 0],
+    [-1, 3, 0],
+    [-1, -3, 0],
+    [1, 1, 1],
+    [-1, 1, 1],
+    [0, -2, 1],
+    [1, 1, -1],
+    [-1, 1, -1],
+    [0, -2, -1],
+];
+
+const HCP_B_NN_OFFSETS: [[i64; 3]; 12] = [
+    [2, 0, 0],
+    [-2, 0, 0],
+    [1, 3, 0],
+    [1, -3, 0],
+    [-1, 3, 0],
+    [-1, -3, 0],
+    [-1, -1, 1],
+    [1, -1, 1],
+    [0, 2, 1],
+    [-1, -1, -1],
+    [1, -1, -1],
+    [0, 2, -1],
+];
+
+impl Lattice {
+    /// Number of nearest neighbors of every site.
+    pub fn cn(self) -> usize {
+        self.nn_offsets(0).len()
+    }
+
+    fn basis(self) -> &'static [[i64; 3]] {
+        match self {
+            Lattice::Fcc => &FCC_BASIS,
+            Lattice::Bcc => &BCC_BASIS,
+            Lattice::Hcp { .. } => &HCP_BASIS,
+        }
+    }
+
+    /// Edge lengths of the unit cell in Angstrom.
+    pub fn cell_lengths(self, lattice_constant: f64) -> [f64; 3] {
+        let period = self.period();
+        let scale = self.scale(lattice_constant);
+        [0, 1, 2].map(|d| period[d] as f64 * scale[d])
+    }
+
+    /// Size of the unit cell in grid coordinates.
+    fn period(self) -> [i64; 3] {
+        match self {
+            Lattice::Fcc | Lattice::Bcc => [2, 2, 2],
+            Lattice::Hcp { .. } => [2, 6, 2],
+        }
+    }
+
+    /// Smallest box without neighbors meeting again across the periodic boundary, the hcp cell
+    /// holds only one site per row along x.
+    fn min_cells(self) -> [u32; 3] {
+        match self {
+            Lattice::Fcc | Lattice::Bcc => [2, 2, 2],
+            Lattice::Hcp { .. } => [4, 2, 2],
+        }
+    }
+
+    /// Length of one grid coordinate step in Angstrom.
+    fn scale(self, lattice_constant: f64) -> [f64; 3] {
+        match self {
+            Lattice::Fcc | Lattice::Bcc => [lattice_constant / 2.; 3],
+            Lattice::Hcp { c_over_a } => [
+                lattice_constant / 2.,
+                3_f64.sqrt() * lattice_constant / 6.,
+                c_over_a * lattice_constant / 2.,
+            ],
+        }
+    }
+
+    fn nn_offsets(self, basis: usize) -> &'static [[i64; 3]] {
+        match self {
+            Lattice::Fcc => &FCC_NN_OFFSETS,
+            Lattice::Bcc => &BCC_NN_OFFSETS,
+            Lattice::Hcp { .. } if HCP_BASIS[basis][2] == 0 => &HCP_A_NN_OFFSETS,
+            Lattice::Hcp { .. } => &HCP_B_NN_OFFSETS,
+        }
+    }
+}
+
+fn lattice_index(lattice: Lattice, coords: [i64; 3], cells: [u32; 3]) -> u32 {
+    let period = lattice.period();
+    let wrapped = [0, 1, 2].map(|d| coords[d].rem_euclid(period[d] * cells[d] as i64));
+    let cell = [0, 1, 2].map(|d| wrapped[d] / period[d]);
+    let in_cell = [0, 1, 2].map(|d| wrapped[d] % period[d]);
+    let basis = lattice
+        .basis()
+        .iter()
+        .position(|b| *b == in_cell)
+        .expect("not a lattice point") as i64;
+    (((cell[0] * cells[1] as i64 + cell[1]) * cells[2] as i64 + cell[2])
+        * lattice.basis().len() as i64
+        + basis) as u32
+}
+
+/// Sites and periodic nearest neighbors (`lattice.cn()` per site) of a box with `cells` unit
+/// cells along x, y and z. Also returns the size of the box.
+pub fn lattice_sites(
+    lattice: Lattice,
+    lattice_constant: f64,
+    cells: [u32; 3],
+) -> anyhow::Result<SitesAndNeighbors> {
+    if lattice_constant <= 0. {
+        bail!(
+            "lattice constant has to be positive, got {}",
+            lattice_constant
+        );
+    }
+    if let Lattice::Hcp { c_over_a } = lattice {
+        if c_over_a <= 0. {
+            bail!("c/a ratio has to be positive, got {}", c_over_a);
+        }
+    }
+    let min_cells = lattice.min_cells();
+    if cells.iter().zip(min_cells).any(|(n, min)| *n < min) {
+        bail!(
+            "the grid needs at least {:?} unit cells, got {:?}",
+            min_cells,
+            cells
+        );
+    }
+    let nsites = cells.iter().map(|n| *n as usize).product::<usize>() * lattice.basis().len();
+    if nsites > u32::MAX as usize {
+        bail!("{} sites do not fit into u32 site indices", nsites);
+    }
+
+    let period = lattice.period();
+    let scale = lattice.scale(lattice_constant);
+    let mut xsites_positions: Vec<[f64; 3]> = Vec::with_capacity(nsites);
+    let mut nn: Vec<u32> = Vec::with_capacity(nsites * lattice.cn());
+
+    for ix in 0..cells[0] as i64 {
+        for iy in 0..cells[1] as i64 {
+            for iz in 0..cells[2] as i64 {
+                for (i, basis) in lattice.basis().iter().enumerate() {
+                    let coords = [
+                        period[0] * ix + basis[0],
+                        period[1] * iy + basis[1],
+                        period[2] * iz + basis[2],
+                    ];
+                    let site = lattice_index(lattice, coords, cells);
+                    debug_assert_eq!(site as usize, xsites_positions.len());
+                    xsites_positions.push([0, 1, 2].map(|d| coords[d] as f64 * scale[d]));
+
+                    for offset in lattice.nn_offsets(i) {
+                        nn.push(lattice_index(
+                            lattice,
+                            [0, 1, 2].map(|d| coords[d] + offset[d]),
+                            cells,
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    let unit_cell = [0, 1, 2].map(|d| cells[d] as f64 * period[d] as f64 * scale[d]);
+    Ok((xsites_positions, nn, unit_cell))
+}
+
+/// Edge length in Angstrom of a grid for a particle of `atom_count` atoms, each taking up
+/// `volume_per_site`: the diameter of a compact particle, a sphere or for a supported particle a
+/// hemisphere, times the expected spread during the anneal and `margin` on both sides.
+pub fn particle_box_length(
+    atom_count: u32,
+    volume_per_site: f64,
+    supported: bool,
+    margin: f64,
+) -> f64 {
+    let volume = atom_count as f64 * volume_per_site;
+    let radius = if supported {
+        (3. * volume / (2. * PI)).cbrt()
+    } else {
+        (3. * volume / (4. * PI)).cbrt()
+    };
+    2. * radius * PARTICLE_SPREAD + 2. * margin
+}
+
+/// Unit cells along x, y and z of the smallest grid of the lattice with edges of at least
+/// [`particle_box_length`].
+pub fn cells_for_particle(
+    lattice: Lattice,
+    lattice_constant: f64,
+    atom_count: u32,
+    supported: bool,
+    margin: f64,
+) -> [u32; 3] {
+    let lengths = lattice.cell_lengths(lattice_constant);
+    let volume_per_site = lengths.iter().product::<f64>() / lattice.basis().len() as f64;
+    let length = particle_box_length(atom_count, volume_per_site, supported, margin);
+    let min_cells = lattice.min_cells();
+    [0, 1, 2].map(|d| ((length / lengths[d]).ceil() as u32).max(min_cells[d]))
+}
+
+/// Sites and periodic neighbors of a crystal with the atoms at `basis` (fractional coordinates)
+/// in `cell`, repeated `cells` times. All sites closer than `cutoff` are neighbors, every atom of
+/// the basis has to end up with the same number of them.
+pub fn crystal_sites(
+    cell: &Cell,
+    basis: &[[f64; 3]],
+    cutoff: f64,
+    cells: [u32; 3],
+) -> anyhow::Result<(Vec<[f64; 3]>, Vec<u32>, Cell)> {
+    if basis.is_empty() {
+        bail!("the crystal structure contains no atoms");
+    }
+    if cutoff <= 0. {
+        bail!("cutoff has to be positive, got {}", cutoff);
+    }
+    if cells.contains(&0) {
+        bail!("the grid needs at least one unit cell in every direction");
+    }
+    let nsites = cells.iter().map(|n| *n as usize).product::<usize>() * basis.len();
+    if nsites > u32::MAX as usize {
+        bail!("{} sites do not fit into u32 site indices", nsites);
+    }
+    let basis: Vec<[f64; 3]> = basis.iter().map(|f| f.map(|x| x - x.floor())).collect();
+
+    // neighbors of every basis atom as (basis atom, unit cell shift), closest first
+    let shift_range = cell
+        .plane_distances()
+        .map(|distance| (cutoff / distance).ceil() as i64 + 1);
+    let mut basis_neighbors: Vec<Vec<(usize, [i64; 3])>> = Vec::with_capacity(basis.len());
+    for (i, from) in basis.iter().enumerate() {
+        let mut neighbors: Vec<(f64, usize, [i64; 3])> = Vec::new();
+        for (j, to) in basis.iter().enumerate() {
+            for sx in -shift_range[0]..=shift_range[0] {
+                for sy in -shift_range[1]..=shift_range[1] {
+                    for sz in -shift_range[2]..=shift_range[2] {
+                        let shift = [sx, sy, sz];
+                        let diff =
+                            cell.cartesian([0, 1, 2].map(|d| to[d] + shift[d] as f64 - from[d]));
+                        let distance = diff.iter().map(|x| x * x).sum::<f64>().sqrt();
+                        if distance < 1e-6 && (i != j || shift != [0, 0, 0]) {
+                            bail!("atoms {} and {} of the crystal structure overlap", i, j);
+                        }
+                        if distance >= 1e-6 && distance < cutoff {
+                            neighbors.push((distance, j, shift));
+                        }
+                    }
+                }
+            }
+        }
+        neighbors.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)).then(a.2.cmp(&b.2)));
+        basis_neighbors.push(
+            neighbors
+                .into_iter()
+                .map(|(_, j, shift)| (j, shift))
+                .collect(),
+        );
+    }
+    let cn = basis_neighbors[0].len();
+    if cn == 0 {
+        bail!(
+            "atom 0 of the crystal structure has no neighbors within {} A",
+            cutoff
+        );
+    }
+    if let Some((i, neighbors)) = basis_neighbors
+        .iter()
+        .enumerate()
+        .find(|(_, neighbors)| neighbors.len() != cn)
+    {
+        bail!(
+            "atom {} of the crystal structure has {} neighbors within {} A but atom 0 has {}",
+            i,
+            neighbors.len(),
+            cutoff,
+            cn
+        );
+    }
+
+    let site_index = |cell_coords: [i64; 3], atom: usize| {
+        let wrapped = [0, 1, 2].map(|d| cell_coords[d].rem_euclid(cells[d] as i64));
+        (((wrapped[0] * cells[1] as i64 + wrapped[1]) * cells[2] as i64 + wrapped[2])
+            * basis.len() as i64
+            + atom as i64) as u32
+    };
+    let mut xsites_positions: Vec<[f64; 3]> = Vec::with_capacity(nsites);
+    let mut nn: Vec<u32> = Vec::with_capacity(nsites * cn);
+    for ix in 0..cells[0] as i64 {
+        for iy in 0..cells[1] as i64 {
+            for iz in 0..cells[2] as i64 {
+                let cell_coords = [ix, iy, iz];
+                for (i, fractional) in basis.iter().enumerate() {
+                    let site = site_index(cell_coords, i);
+                    debug_assert_eq!(site as usize, xsites_positions.len());
+                    xsites_positions.push(
+                        cell.cartesian([0, 1, 2].map(|d| fractional[d] + cell_coords[d] as f64)),
+                    );
+                    let start = nn.len();
+                    for (j, shift) in &basis_neighbors[i] {
+                        let neighbor = site_index([0, 1, 2].map(|d| cell_coords[d] + shift[d]), *j);
+                        if neighbor == site || nn[start..].contains(&neighbor) {
+                            bail!(
+                                "{:?} unit cells are too few for a cutoff of {} A, neighbors meet again across the periodic boundary",
+                                cells,
+                                cutoff
+                            );
+                        }
+                        nn.push(neighbor);
+                    }
+                }
+            }
+        }
+    }
+    Ok((xsites_positions, nn, cell.repeated(cells)?))
+}
+
+/// For every site and neighbor slot the slot under which the site is found in the neighbor list
+/// of that neighbor.
+fn nn_reverse_slots(nn: &[u32], cn: usize) -> anyhow::Result<Vec<u8>> {
+    let nsites = nn.len() / cn;
+    let mut reverse_slots = vec![0_u8; nn.len()];
+    for (site, neighbors) in nn.chunks_exact(cn).enumerate() {
+        for (slot, neighbor) in neighbors.iter().enumerate() {
+            if *neighbor as usize >= nsites {
+                bail!(
+                    "site {} has neighbor {} but there are only {} sites",
+                    site,
+                    neighbor,
+                    nsites
+                );
+            }
+            let neighbor_nn = &nn[*neighbor as usize * cn..(*neighbor as usize + 1) * cn];
+            let Some(reverse_slot) = neighbor_nn.iter().position(|x| *x as usize == site) else {
+                bail!(
+                    "site {} is a neighbor of {} but not the other way around",
+                    neighbor,
+                    site
+                );
+            };
+            reverse_slots[site * cn + slot] = reverse_slot as u8;
+        }
+    }
+    Ok(reverse_slots)
+}
+
+/// For every site and neighbor slot the neighbors of the site which are neither that neighbor
+/// nor a common neighbor of both, followed by the same for the neighbor. Returns the number of
+/// these neighbors, which has to be the same for every pair of complete sites, and the flattened
+/// table. Neighbor lists of sites at the boundary of a finite grid are padded with the site
+/// itself; no atom moves between them, so the lists of their pairs are cut or padded with the
+/// site to the same number.
+pub fn nn_pair_no_intersec_from_nn(nn: &[u32], cn: usize) -> anyhow::Result<(usize, Vec<u32>)> {
+    let reverse_slots = nn_reverse_slots(nn, cn)?;
+    let is_complete = |site: usize| nn[(site + 1) * cn - 1] as usize != site;
+    let exclusive_of = |site: usize, other: u32| {
+        let other_nn = &nn[other as usize * cn..(other as usize + 1) * cn];
+        nn[site * cn..(site + 1) * cn]
+            .iter()
+            .copied()
+            .filter(move |neighbor| {
+                *neighbor != other && *neighbor as usize != site && !other_nn.contains(neighbor)
+            })
+    };
+
+    let mut no_int_number = None;
+    for (site, neighbors) in nn.chunks_exact(cn).enumerate() {
+        if !is_complete(site) {
+            continue;
+        }
+        for other in neighbors.iter().filter(|x| is_complete(**x as usize)) {
+            let count = exclusive_of(site, *other).count();
+            match no_int_number {
+                None => no_int_number = Some(count),
+                Some(expected) if expected != count => bail!(
+                    "pair {} {} has {} instead of {} non-intersecting neighbors",
+                    site,
+                    other,
+                    count,
+                    expected
+                ),
+                Some(_) => {}
+            }
+        }
+    }
+    let no_int_number = no_int_number.unwrap_or(0);
+
+    let mut exclusive: Vec<u32> = Vec::with_capacity(nn.len() * no_int_number);
+    for (site, neighbors) in nn.chunks_exact(cn).enumerate() {
+        for other in neighbors {
+            let start = exclusive.len();
+            exclusive.extend(exclusive_of(site, *other).take(no_int_number));
+            exclusive.resize(start + no_int_number, site as u32);
+        }
+    }
+
+    let mut no_int = Vec::with_capacity(nn.len() * 2 * no_int_number);
+    for (i, neighbor) in nn.iter().enumerate() {
+        let reverse = *neighbor as usize * cn + reverse_slots[i] as usize;
+        no_int.extend_from_slice(&exclusive[i * no_int_number..(i + 1) * no_int_number]);
+        no_int
+            .extend_from_slice(&exclusive[reverse * no_int_number..(reverse + 1) * no_int_number]);
+    }
+    Ok((no_int_number, no_int))
+}
+
+/// For every site the closest sites among the neighbors of its neighbors, which are not neighbors
+/// themselves. Returns their number, which has to be the same for every site, and the flattened
+/// table.
+pub fn second_shell_from_nn(
+    nn: &[u32],
+    cn: usize,
+    xsites_positions: &[[f64; 3]],
+    cell: &Cell,
+) -> anyhow::Result<(usize, Vec<u32>)> {
+    let mut cn2 = None;
+    let mut nn2 = Vec::new();
+    for (site, neighbors) in nn.chunks_exact(cn).enumerate() {
+        let mut candidates: Vec<(u32, f64)> = Vec::new();
+        for neighbor in neighbors {
+            for candidate in &nn[*neighbor as usize * cn..(*neighbor as usize + 1) * cn] {
+                if *candidate as usize == site
+                    || neighbors.contains(candidate)
+                    || candidates.iter().any(|(x, _)| x == candidate)
+                {
+                    continue;
+                }
+                let diff =
+                    cell.minimum_image([0, 1, 2].map(|d| {
+                        xsites_positions[*candidate as usize][d] - xsites_positions[site][d]
+                    }));
+                candidates.push((*candidate, diff.iter().map(|x| x * x).sum::<f64>().sqrt()));
+            }
+        }
+        let shortest = candidates
+            .iter()
+            .map(|(_, distance)| *distance)
+            .fold(f64::INFINITY, f64::min);
+        let start = nn2.len();
+        nn2.extend(
+            candidates
+                .iter()
+                .filter(|(_, distance)| *distance < shortest + SHELL_TOLERANCE)
+                .map(|(candidate, _)| *candidate),
+        );
+        let count = nn2.len() - start;
+        match cn2 {
+            None => cn2 = Some(count),
+            Some(expected) if expected != count => bail!(
+                "site {} has {} instead of {} second shell neighbors",
+                site,
+                count,
+                expected
+            ),
+            Some(_) => {}
+        }
+    }
+    Ok((cn2.unwrap_or(0), nn2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MARGIN: f64 = 10.;
+
+    #[test]
+    fn particle_box_holds_the_atoms_and_the_margin() {
+        for (lattice, lattice_constant) in [
+            (Lattice::Fcc, 3.92),
+            (Lattice::Bcc, 3.16),
+            (Lattice::Hcp { c_over_a: 1.623 }, 2.51),
+        ] {
+            let cell_lengths = lattice.cell_lengths(lattice_constant);
+            let volume_per_site =
+                cell_lengths.iter().product::<f64>() / lattice.basis().len() as f64;
+            for (atom_count, supported) in [(13, false), (1000, false), (1000, true)] {
+                let cells =
+                    cells_for_particle(lattice, lattice_constant, atom_count, supported, MARGIN);
+                let length = particle_box_length(atom_count, volume_per_site, supported, MARGIN);
+                let (xsites_positions, _, lengths) =
+                    lattice_sites(lattice, lattice_constant, cells).unwrap();
+                let inner_sites = xsites_positions
+                    .iter()
+                    .filter(|xyz| (0..3).all(|d| xyz[d] >= MARGIN && xyz[d] <= lengths[d] - MARGIN))
+                    .count();
+                assert!(
+                    inner_sites >= atom_count as usize,
+                    "{:?} with {:?} cells has {} sites off the margin for {} atoms",
+                    lattice,
+                    cells,
+                    inner_sites,
+                    atom_count
+                );
+                for d in 0..3 {
+                    assert!(lengths[d] >= length, "{:?} {:?}", lattice, lengths);
+                    // the smallest such box
+                    assert!(
+                        cells[d] == lattice.min_cells()[d]
+                            || (cells[d] - 1) as f64 * cell_lengths[d] < length,
+                        "{:?} {:?}",
+                        lattice,
+                        cells
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn supported_particle_box_is_larger() {
+        let unsupported = particle_box_length(1000, 15., false, MARGIN);
+        let supported = particle_box_length(1000, 15., true, MARGIN);
+        // a hemisphere of the same volume has a radius larger by the cube root of two
+        assert!(
+            ((supported - 2. * MARGIN) / (unsupported - 2. * MARGIN) - 2_f64.cbrt()).abs() < 1e-12
+        );
+        assert_eq!(particle_box_length(0, 15., false, MARGIN), 2. * MARGIN);
+    }
+}
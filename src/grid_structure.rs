@@ -1,40 +1,758 @@
-use super::read_and_write;
+use super::cell::Cell;
+use super::grid_generator::{self, Lattice};
+use super::site_table::SiteTable;
+use super::{grid_cache, grid_validation, read_and_write};
 use chemfiles::{Frame, Trajectory};
 use std::collections::HashMap;
 
 pub struct GridStructure {
-    pub nn: HashMap<u32, [u32; super::CN], fnv::FnvBuildHasher>,
-    pub nn_pair_no_intersec:
-        HashMap<u64, [[u32; super::NN_PAIR_NO_INTERSEC_NUMBER]; 2], fnv::FnvBuildHasher>,
-    pub xsites_positions: Vec<[f64; 3]>,
-    pub unit_cell: [f64; 3],
+    pub(crate) cn: usize,
+    pub(crate) nn_pair_no_intersec_number: usize,
+    /// `cn` neighbors per site.
+    pub(crate) nn: SiteTable<u32>,
+    /// For every site and each of its neighbors: the neighbors of the site which are neither that
+    /// neighbor nor a common neighbor of both, followed by the same for the neighbor.
+    pub(crate) nn_pair_no_intersec: SiteTable<u32>,
+    /// Number of second shell neighbors per site, zero until
+    /// [`GridStructure::add_second_shell`].
+    pub(crate) cn2: usize,
+    /// `cn2` second shell neighbors per site.
+    pub(crate) nn2: SiteTable<u32>,
+    pub xsites_positions: SiteTable<[f64; 3]>,
+    pub cell: Cell,
+    /// Only known for generated grids.
+    pub(crate) lattice: Option<Lattice>,
 }
 
 impl GridStructure {
     pub fn new(
-        (pairlist_file, nn_pair_no_int_file, atom_sites, bulk_file_name): (
+        (pairlist_file, nn_pair_no_int_file, atom_sites, bulk_file_name, grid_cache_file): (
+            String,
             String,
             String,
             String,
             String,
         ),
-    ) -> GridStructure {
-        let nn = read_and_write::read_nn(&pairlist_file);
-        let nn_pair_no_intersec = read_and_write::read_nn_pair_no_intersec(&nn_pair_no_int_file);
+    ) -> anyhow::Result<GridStructure> {
+        let source_files = [
+            pairlist_file.as_str(),
+            &nn_pair_no_int_file,
+            &atom_sites,
+            &bulk_file_name,
+        ];
+        if grid_cache::is_up_to_date(&grid_cache_file, &source_files) {
+            match grid_cache::read_grid_cache(&grid_cache_file) {
+                Ok(gridstructure) => return Ok(gridstructure),
+                Err(err) => println!("could not use grid cache {}: {}", grid_cache_file, err),
+            }
+        }
+
+        let gridstructure = GridStructure::from_text_files(
+            &pairlist_file,
+            &nn_pair_no_int_file,
+            &atom_sites,
+            &bulk_file_name,
+        )?;
+        gridstructure
+            .write_cache(&grid_cache_file, &source_files)
+            .unwrap_or_else(|err| println!("could not write grid cache: {}", err));
+        Ok(gridstructure)
+    }
 
-        let mut trajectory = Trajectory::open(bulk_file_name, 'r').unwrap();
+    /// Reads and checks the text files of a grid, the optional `nn_pair_no_int_file` against the
+    /// pairs derived from the neighbor list. Sites with fewer neighbors than the others, at the
+    /// boundary of a finite grid, are padded as described at [`GridStructure::is_complete`].
+    pub fn from_text_files(
+        pairlist_file: &str,
+        nn_pair_no_int_file: &str,
+        atom_sites: &str,
+        bulk_file_name: &str,
+    ) -> anyhow::Result<GridStructure> {
+        let nn_lines = read_and_write::read_nn(pairlist_file)?;
+        let xsites_positions = read_and_write::read_atom_sites(atom_sites)?;
+        let problems = grid_validation::validate_neighbors(&nn_lines, &xsites_positions);
+        if !problems.is_empty() {
+            anyhow::bail!(
+                "the grid in {} and {} is not valid:\n  {}",
+                pairlist_file,
+                atom_sites,
+                problems.join("\n  ")
+            );
+        }
+        let (cn, nn) = padded_neighbors(nn_lines);
+
+        let mut trajectory = Trajectory::open(bulk_file_name, 'r')?;
         let mut frame = Frame::new();
-        trajectory.read(&mut frame).unwrap();
-        // frame.cell();
-        let unit_cell = frame.cell().lengths();
+        trajectory.read(&mut frame)?;
+        let cell = Cell::from_lengths_angles(frame.cell().lengths(), frame.cell().angles())
+            .map_err(|err| anyhow::anyhow!("{} needs a periodic cell: {}", bulk_file_name, err))?;
+
+        let gridstructure = GridStructure::from_neighbors(cn, nn, xsites_positions, cell, None)?;
+
+        if std::path::Path::new(nn_pair_no_int_file).exists() {
+            let nn_pair_no_intersec_file =
+                read_and_write::read_nn_pair_no_intersec(nn_pair_no_int_file)?;
+            check_nn_pair_no_intersec(&gridstructure, &nn_pair_no_intersec_file).map_err(
+                |err| {
+                    anyhow::anyhow!(
+                        "{} does not match the neighbor list {}: {}",
+                        nn_pair_no_int_file,
+                        pairlist_file,
+                        err
+                    )
+                },
+            )?;
+        }
+        Ok(gridstructure)
+    }
+
+    pub fn generate(
+        lattice: Lattice,
+        lattice_constant: f64,
+        cells: [u32; 3],
+    ) -> anyhow::Result<GridStructure> {
+        let (xsites_positions, nn, lengths) =
+            grid_generator::lattice_sites(lattice, lattice_constant, cells)?;
+        GridStructure::from_neighbors(
+            lattice.cn(),
+            nn,
+            xsites_positions,
+            Cell::orthorhombic(lengths)?,
+            Some(lattice),
+        )
+    }
+
+    /// Generated grid just large enough for a particle of `atom_count` atoms with `margin`
+    /// Angstrom of free space around it, see [`GridStructure::recommended_length`].
+    pub fn for_particle(
+        lattice: Lattice,
+        lattice_constant: f64,
+        atom_count: u32,
+        supported: bool,
+        margin: f64,
+    ) -> anyhow::Result<GridStructure> {
+        let cells = grid_generator::cells_for_particle(
+            lattice,
+            lattice_constant,
+            atom_count,
+            supported,
+            margin,
+        );
+        GridStructure::generate(lattice, lattice_constant, cells)
+    }
 
-        let xsites_positions = read_and_write::read_atom_sites(&atom_sites, nn.len() as u32);
+    /// Grid of all atom sites of a crystal structure file repeated `cells` times, with every site
+    /// closer than `cutoff` as neighbor.
+    pub fn from_crystal(
+        structure_file: &str,
+        cutoff: f64,
+        cells: [u32; 3],
+    ) -> anyhow::Result<GridStructure> {
+        let (cell, basis) = read_and_write::read_crystal(structure_file)?;
+        GridStructure::from_basis(&cell, &basis, cutoff, cells)
+    }
 
-        GridStructure {
+    /// Like [`GridStructure::from_crystal`] with the atoms given in fractional coordinates.
+    pub fn from_basis(
+        cell: &Cell,
+        basis: &[[f64; 3]],
+        cutoff: f64,
+        cells: [u32; 3],
+    ) -> anyhow::Result<GridStructure> {
+        let (xsites_positions, nn, cell) =
+            grid_generator::crystal_sites(cell, basis, cutoff, cells)?;
+        GridStructure::from_neighbors(
+            nn.len() / xsites_positions.len(),
             nn,
-            nn_pair_no_intersec,
             xsites_positions,
-            unit_cell,
+            cell,
+            None,
+        )
+    }
+
+    fn from_neighbors(
+        cn: usize,
+        nn: Vec<u32>,
+        xsites_positions: Vec<[f64; 3]>,
+        cell: Cell,
+        lattice: Option<Lattice>,
+    ) -> anyhow::Result<GridStructure> {
+        if nn.len() != xsites_positions.len() * cn {
+            anyhow::bail!(
+                "{} sites with {} neighbors each but {} site positions",
+                nn.len() / cn,
+                cn,
+                xsites_positions.len()
+            );
+        }
+        let (nn_pair_no_intersec_number, nn_pair_no_intersec) =
+            grid_generator::nn_pair_no_intersec_from_nn(&nn, cn)?;
+        Ok(GridStructure {
+            cn,
+            nn_pair_no_intersec_number,
+            nn: nn.into(),
+            nn_pair_no_intersec: nn_pair_no_intersec.into(),
+            cn2: 0,
+            nn2: Vec::new().into(),
+            xsites_positions: xsites_positions.into(),
+            cell,
+            lattice,
+        })
+    }
+
+    #[inline]
+    pub fn nsites(&self) -> usize {
+        self.xsites_positions.len()
+    }
+
+    /// Coordination number of the lattice, the number of nearest neighbors of every site.
+    #[inline]
+    pub fn cn(&self) -> usize {
+        self.cn
+    }
+
+    /// The lattice the grid was generated for, `None` for grids read from text files.
+    pub fn lattice(&self) -> Option<Lattice> {
+        self.lattice
+    }
+
+    /// Edge length in Angstrom the grid should have for a particle of `atom_count` atoms: the
+    /// diameter of a compact particle, a hemisphere if it sits on a support, with room for the
+    /// particle to change its shape during the anneal and `margin` Angstrom on both sides.
+    pub fn recommended_length(&self, atom_count: u32, supported: bool, margin: f64) -> f64 {
+        grid_generator::particle_box_length(
+            atom_count,
+            self.cell.volume() / self.nsites() as f64,
+            supported,
+            margin,
+        )
+    }
+
+    #[inline]
+    pub fn nn(&self, site: u32) -> &[u32] {
+        &self.nn[site as usize * self.cn..(site as usize + 1) * self.cn]
+    }
+
+    /// Whether the site has all `cn` neighbors. Sites at the boundary of a finite grid have
+    /// fewer, their neighbor lists are padded with the site itself at the end. Atoms and
+    /// adsorbates never go to these sites.
+    #[inline]
+    pub fn is_complete(&self, site: u32) -> bool {
+        self.nn(site)[self.cn - 1] != site
+    }
+
+    /// Finds the second shell neighbors of every site, the closest sites among the neighbors of
+    /// its neighbors. They are not part of the grid files or the cache.
+    pub fn add_second_shell(&mut self) -> anyhow::Result<()> {
+        let (cn2, nn2) = grid_generator::second_shell_from_nn(
+            &self.nn,
+            self.cn,
+            &self.xsites_positions,
+            &self.cell,
+        )?;
+        self.cn2 = cn2;
+        self.nn2 = nn2.into();
+        Ok(())
+    }
+
+    /// Number of second shell neighbors of every site, zero if they were not added.
+    #[inline]
+    pub fn cn2(&self) -> usize {
+        self.cn2
+    }
+
+    #[inline]
+    pub fn nn2(&self, site: u32) -> &[u32] {
+        &self.nn2[site as usize * self.cn2..(site as usize + 1) * self.cn2]
+    }
+
+    /// Neighbors of `move_from` losing and neighbors of `move_to` gaining a neighbor when an atom
+    /// moves from `move_from` to `move_to`, `None` if `move_to` is not a neighbor of `move_from`.
+    #[inline]
+    pub fn no_int_nn_from_move(&self, move_from: u32, move_to: u32) -> Option<(&[u32], &[u32])> {
+        // branchless search, the slot is different for every move
+        let slot = self
+            .nn(move_from)
+            .iter()
+            .enumerate()
+            .fold(self.cn, |slot, (i, x)| if *x == move_to { i } else { slot });
+        if slot == self.cn {
+            return None;
+        }
+        let no_int_number = self.nn_pair_no_intersec_number;
+        let start = (move_from as usize * self.cn + slot) * 2 * no_int_number;
+        let no_int = &self.nn_pair_no_intersec[start..start + 2 * no_int_number];
+        Some(no_int.split_at(no_int_number))
+    }
+
+    pub fn write(&self, grid_folder: &str) -> anyhow::Result<()> {
+        read_and_write::write_grid(grid_folder, self)?;
+        let source_files = [
+            "nearest_neighbor",
+            "nn_pair_no_intersec",
+            "atom_sites",
+            "grid_file.xyz",
+        ]
+        .map(|name| format!("{}/{}", grid_folder, name));
+        self.write_cache(
+            &format!("{}/grid.bin", grid_folder),
+            &source_files.each_ref().map(String::as_str),
+        )
+    }
+
+    /// Checks the tables of a grid cache against the checksum in its header, which loading the
+    /// cache skips.
+    pub fn verify_cache(grid_cache_file: &str) -> anyhow::Result<()> {
+        grid_cache::verify_grid_cache(grid_cache_file)
+    }
+
+    /// Writes the binary cache of the grid, which is used instead of the `source_files` as long
+    /// as they do not change.
+    pub fn write_cache(&self, grid_cache_file: &str, source_files: &[&str]) -> anyhow::Result<()> {
+        grid_cache::write_grid_cache(grid_cache_file, self, source_files)
+    }
+}
+
+/// Neighbor lists sorted by site, the lines with fewer neighbors than the others padded with the
+/// site itself. Returns the number of neighbors per site and the flattened table.
+fn padded_neighbors(mut nn_lines: Vec<(u32, Vec<u32>)>) -> (usize, Vec<u32>) {
+    for (site, neighbors) in nn_lines.iter_mut() {
+        *neighbors = grid_validation::distinct_neighbors(*site, neighbors);
+    }
+    let cn = nn_lines
+        .iter()
+        .map(|(_, neighbors)| neighbors.len())
+        .max()
+        .unwrap_or(0);
+    nn_lines.sort_unstable_by_key(|(site, _)| *site);
+    let nn = nn_lines
+        .into_iter()
+        .flat_map(|(site, mut neighbors)| {
+            neighbors.resize(cn, site);
+            neighbors
+        })
+        .collect();
+    (cn, nn)
+}
+
+/// Compares the pairs of a `nn_pair_no_intersec` file with the derived ones. Every pair of the
+/// file has to be a pair of neighbors, but only the pairs of two complete sites are compared, as
+/// the lists of the pairs at the boundary of a finite grid are padded differently.
+fn check_nn_pair_no_intersec(
+    gridstructure: &GridStructure,
+    from_file: &HashMap<u64, [Vec<u32>; 2], fnv::FnvBuildHasher>,
+) -> anyhow::Result<()> {
+    let is_pair = |key: u64| {
+        let (site, neighbor) = (key as u32, (key >> 32) as u32);
+        (site as usize) < gridstructure.nsites()
+            && site != neighbor
+            && gridstructure.nn(site).contains(&neighbor)
+    };
+    if let Some(key) = from_file.keys().copied().filter(|key| !is_pair(*key)).min() {
+        anyhow::bail!(
+            "sites {} and {} are not neighbors",
+            key as u32,
+            (key >> 32) as u32
+        );
+    }
+    for site in 0..gridstructure.nsites() as u32 {
+        for neighbor in gridstructure.nn(site) {
+            if *neighbor <= site
+                || !gridstructure.is_complete(site)
+                || !gridstructure.is_complete(*neighbor)
+            {
+                continue;
+            }
+            let Some(no_int_file) = from_file.get(&(site as u64 + ((*neighbor as u64) << 32)))
+            else {
+                anyhow::bail!("pair {} {} is missing", site, neighbor);
+            };
+            let (from_change, to_change) = gridstructure
+                .no_int_nn_from_move(site, *neighbor)
+                .expect("neighbors have pairs");
+            for (expected, found) in [from_change, to_change].into_iter().zip(no_int_file) {
+                let mut expected = expected.to_vec();
+                let mut found = found.clone();
+                expected.sort_unstable();
+                found.sort_unstable();
+                if expected != found {
+                    anyhow::bail!(
+                        "pair {} {}: expected {:?} but found {:?}",
+                        site,
+                        neighbor,
+                        expected,
+                        found
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::energy::{EnergyInput, EnergyValues};
+    use crate::{test_support, SimulationOptions};
+    use rand::SeedableRng;
+    use std::io::Write;
+
+    const LATTICE_CONSTANT: f64 = 3.92;
+
+    type NnLines = Vec<(u32, Vec<u32>)>;
+
+    fn distance(gridstructure: &GridStructure, a: u32, b: u32) -> f64 {
+        let xyz = &gridstructure.xsites_positions;
+        gridstructure
+            .cell
+            .minimum_image([0, 1, 2].map(|d| xyz[b as usize][d] - xyz[a as usize][d]))
+            .iter()
+            .map(|x| x * x)
+            .sum::<f64>()
+            .sqrt()
+    }
+
+    /// Slightly more than the nearest neighbor distance of fcc.
+    const FCC_CUTOFF: f64 = 1.01 * LATTICE_CONSTANT / std::f64::consts::SQRT_2;
+
+    /// Neighbors of `site` that are not `other` and not closer than `cutoff` to it, found from
+    /// the positions instead of the neighbor list.
+    fn exclusive_by_distance(
+        gridstructure: &GridStructure,
+        site: u32,
+        other: u32,
+        cutoff: f64,
+    ) -> Vec<u32> {
+        let mut exclusive: Vec<u32> = gridstructure
+            .nn(site)
+            .iter()
+            .copied()
+            .filter(|x| *x != other && distance(gridstructure, *x, other) > cutoff)
+            .collect();
+        exclusive.sort_unstable();
+        exclusive
+    }
+
+    /// Checks that the neighbors are all sites closer than `cutoff` and that the pairs of every
+    /// move are the neighbors not shared with the other site.
+    fn check_geometry(gridstructure: &GridStructure, cutoff: f64) {
+        for site in 0..gridstructure.nsites() as u32 {
+            let mut by_distance: Vec<u32> = (0..gridstructure.nsites() as u32)
+                .filter(|x| *x != site && distance(gridstructure, site, *x) < cutoff)
+                .collect();
+            let mut neighbors = gridstructure.nn(site).to_vec();
+            by_distance.sort_unstable();
+            neighbors.sort_unstable();
+            assert_eq!(neighbors, by_distance, "neighbors of site {}", site);
+
+            for neighbor in gridstructure.nn(site) {
+                let (from_change, to_change) =
+                    gridstructure.no_int_nn_from_move(site, *neighbor).unwrap();
+                for (derived, (a, b)) in [from_change, to_change]
+                    .into_iter()
+                    .zip([(site, *neighbor), (*neighbor, site)])
+                {
+                    let mut derived = derived.to_vec();
+                    derived.sort_unstable();
+                    assert_eq!(derived, exclusive_by_distance(gridstructure, a, b, cutoff));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn derived_pairs_match_fcc_geometry() {
+        let gridstructure =
+            GridStructure::generate(Lattice::Fcc, LATTICE_CONSTANT, [3, 3, 3]).unwrap();
+        assert_eq!(gridstructure.nn_pair_no_intersec_number, 7);
+        check_geometry(&gridstructure, FCC_CUTOFF);
+    }
+
+    /// Two bcc neighbors are on different sublattices and have no common neighbor.
+    #[test]
+    fn bcc_has_cn_8_and_7_pairs() {
+        let lattice_constant = 3.16;
+        let gridstructure =
+            GridStructure::generate(Lattice::Bcc, lattice_constant, [3, 3, 3]).unwrap();
+        assert_eq!(gridstructure.nsites(), 54);
+        assert_eq!(gridstructure.cn(), 8);
+        assert_eq!(gridstructure.nn_pair_no_intersec_number, 7);
+        // between the nearest and the second nearest neighbor distance
+        check_geometry(&gridstructure, 0.95 * lattice_constant);
+    }
+
+    /// Pairs in and across the ABAB layers both share 4 neighbors, also for a `c_over_a` off the
+    /// ideal one.
+    #[test]
+    fn hcp_has_cn_12_and_7_pairs() {
+        let lattice_constant = 2.51;
+        for c_over_a in [(8_f64 / 3.).sqrt(), 1.62] {
+            let gridstructure =
+                GridStructure::generate(Lattice::Hcp { c_over_a }, lattice_constant, [4, 2, 2])
+                    .unwrap();
+            assert_eq!(gridstructure.nsites(), 64);
+            assert_eq!(gridstructure.cn(), 12);
+            assert_eq!(gridstructure.nn_pair_no_intersec_number, 7);
+            // the second nearest neighbors are sqrt(2) lattice constants away
+            check_geometry(&gridstructure, 1.1 * lattice_constant);
+        }
+    }
+
+    #[test]
+    fn crystal_grids_match_the_generated_lattices() {
+        let a = 2.51;
+        let c = (8_f64 / 3.).sqrt() * a;
+        let hexagonal = Cell::from_lengths_angles([a, a, c], [90., 90., 120.]).unwrap();
+        let hcp = [[1. / 3., 2. / 3., 0.25], [2. / 3., 1. / 3., 0.75]];
+        let gridstructure =
+            GridStructure::from_basis(&hexagonal, &hcp, 1.1 * a, [4, 4, 3]).unwrap();
+        assert_eq!(gridstructure.nsites(), 96);
+        assert_eq!(gridstructure.cn(), 12);
+        assert_eq!(gridstructure.nn_pair_no_intersec_number, 7);
+        assert_eq!(gridstructure.lattice(), None);
+        check_geometry(&gridstructure, 1.1 * a);
+
+        let cubic = Cell::orthorhombic([3.16; 3]).unwrap();
+        let bcc = [[0.; 3], [0.5; 3]];
+        let gridstructure = GridStructure::from_basis(&cubic, &bcc, 3., [3, 3, 3]).unwrap();
+        assert_eq!(gridstructure.nsites(), 54);
+        assert_eq!(gridstructure.cn(), 8);
+        assert_eq!(gridstructure.nn_pair_no_intersec_number, 7);
+        check_geometry(&gridstructure, 3.);
+    }
+
+    /// With the second shell of bcc as neighbors, nearest and second nearest neighbor pairs share
+    /// a different number of neighbors.
+    #[test]
+    fn crystal_grid_needs_the_same_pairs_for_every_neighbor() {
+        let cubic = Cell::orthorhombic([3.16; 3]).unwrap();
+        let bcc = [[0.; 3], [0.5; 3]];
+        let err = GridStructure::from_basis(&cubic, &bcc, 3.3, [3, 3, 3])
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("non-intersecting"), "{}", err);
+        let err = GridStructure::from_basis(&cubic, &bcc, 3., [1, 1, 1])
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("too few"), "{}", err);
+    }
+
+    #[test]
+    fn fcc_second_shell_has_6_sites_a_lattice_constant_away() {
+        let mut gridstructure =
+            GridStructure::generate(Lattice::Fcc, LATTICE_CONSTANT, [3, 3, 3]).unwrap();
+        assert_eq!(gridstructure.cn2(), 0);
+        gridstructure.add_second_shell().unwrap();
+        assert_eq!(gridstructure.cn2(), 6);
+        for site in 0..gridstructure.nsites() as u32 {
+            let mut by_distance: Vec<u32> = (0..gridstructure.nsites() as u32)
+                .filter(|x| (distance(&gridstructure, site, *x) - LATTICE_CONSTANT).abs() < 1e-6)
+                .collect();
+            let mut second_shell = gridstructure.nn2(site).to_vec();
+            by_distance.sort_unstable();
+            second_shell.sort_unstable();
+            assert_eq!(second_shell, by_distance, "second shell of site {}", site);
+        }
+    }
+
+    /// A pair file in the format of the former Python script, one line per pair with both sites
+    /// and the 7 + 7 non-intersecting neighbors, optionally with one neighbor replaced.
+    fn write_pair_file(gridstructure: &GridStructure, path: &std::path::Path, corrupt: bool) {
+        let mut file = std::fs::File::create(path).unwrap();
+        for site in 0..gridstructure.nsites() as u32 {
+            for neighbor in gridstructure.nn(site).iter().filter(|x| **x > site) {
+                let mut from = exclusive_by_distance(gridstructure, site, *neighbor, FCC_CUTOFF);
+                let to = exclusive_by_distance(gridstructure, *neighbor, site, FCC_CUTOFF);
+                if corrupt && site == 5 {
+                    from[0] = *neighbor;
+                }
+                let line: Vec<String> = [site, *neighbor]
+                    .iter()
+                    .chain(&from)
+                    .chain(&to)
+                    .map(|x| x.to_string())
+                    .collect();
+                writeln!(file, "{}", line.join(" ")).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn pair_file_is_checked_against_the_derived_pairs() {
+        let gridstructure =
+            GridStructure::generate(Lattice::Fcc, LATTICE_CONSTANT, [3, 3, 3]).unwrap();
+        let folder = tempfile::tempdir().unwrap();
+
+        let path = folder.path().join("nn_pair_no_intersec");
+        write_pair_file(&gridstructure, &path, false);
+        let from_file = read_and_write::read_nn_pair_no_intersec(path.to_str().unwrap()).unwrap();
+        check_nn_pair_no_intersec(&gridstructure, &from_file).unwrap();
+
+        let path = folder.path().join("nn_pair_no_intersec_corrupt");
+        write_pair_file(&gridstructure, &path, true);
+        let from_file = read_and_write::read_nn_pair_no_intersec(path.to_str().unwrap()).unwrap();
+        let err = check_nn_pair_no_intersec(&gridstructure, &from_file).unwrap_err();
+        assert!(err.to_string().starts_with("pair 5 "), "{}", err);
+    }
+
+    #[test]
+    fn move_to_a_non_neighbor_has_no_pairs() {
+        let gridstructure =
+            GridStructure::generate(Lattice::Fcc, LATTICE_CONSTANT, [3, 3, 3]).unwrap();
+        let far = (0..gridstructure.nsites() as u32)
+            .find(|x| *x != 0 && !gridstructure.nn(0).contains(x))
+            .unwrap();
+        assert!(gridstructure.no_int_nn_from_move(0, far).is_none());
+        assert!(gridstructure.no_int_nn_from_move(0, 0).is_none());
+        let neighbor = gridstructure.nn(0)[gridstructure.cn() - 1];
+        assert!(gridstructure.no_int_nn_from_move(0, neighbor).is_some());
+    }
+
+    /// Lines of a `nearest_neighbor` file, positions and cell of a finite fcc grid like those of
+    /// the former Python script: `cells` unit cells cut out of a larger periodic grid, the lines
+    /// of the sites at the boundary padded with the site itself or with their first neighbor.
+    fn finite_fcc(cells: u32) -> (NnLines, Vec<[f64; 3]>, Cell) {
+        let periodic =
+            GridStructure::generate(Lattice::Fcc, LATTICE_CONSTANT, [cells + 1; 3]).unwrap();
+        let edge = cells as f64 * LATTICE_CONSTANT - 0.1;
+        let kept: Vec<u32> = (0..periodic.nsites() as u32)
+            .filter(|x| {
+                periodic.xsites_positions[*x as usize]
+                    .iter()
+                    .all(|c| *c < edge)
+            })
+            .collect();
+        let mut index = vec![u32::MAX; periodic.nsites()];
+        for (new, old) in kept.iter().enumerate() {
+            index[*old as usize] = new as u32;
+        }
+        let xsites_positions: Vec<[f64; 3]> = kept
+            .iter()
+            .map(|x| periodic.xsites_positions[*x as usize])
+            .collect();
+        let nn_lines = kept
+            .iter()
+            .enumerate()
+            .map(|(site, old)| {
+                // without the neighbors across the periodic boundary
+                let mut neighbors: Vec<u32> = periodic
+                    .nn(*old)
+                    .iter()
+                    .map(|x| index[*x as usize])
+                    .filter(|x| {
+                        *x != u32::MAX
+                            && (0..3)
+                                .map(|d| {
+                                    xsites_positions[*x as usize][d] - xsites_positions[site][d]
+                                })
+                                .all(|diff| diff.abs() < FCC_CUTOFF)
+                    })
+                    .collect();
+                let padding = if site % 2 == 0 {
+                    site as u32
+                } else {
+                    neighbors[0]
+                };
+                neighbors.resize(periodic.cn(), padding);
+                (site as u32, neighbors)
+            })
+            .collect();
+        (nn_lines, xsites_positions, periodic.cell)
+    }
+
+    #[test]
+    fn finite_python_grid_keeps_the_pairs_of_complete_sites() {
+        let (nn_lines, xsites_positions, cell) = finite_fcc(3);
+        assert!(grid_validation::validate_neighbors(&nn_lines, &xsites_positions).is_empty());
+        let (cn, nn) = padded_neighbors(nn_lines);
+        let gridstructure =
+            GridStructure::from_neighbors(cn, nn, xsites_positions, cell, None).unwrap();
+        assert_eq!(gridstructure.cn(), 12);
+        assert_eq!(gridstructure.nn_pair_no_intersec_number, 7);
+        // sites of 3 unit cells at least half a lattice constant away from the faces
+        let complete: Vec<u32> = (0..gridstructure.nsites() as u32)
+            .filter(|x| gridstructure.is_complete(*x))
+            .collect();
+        assert_eq!((gridstructure.nsites(), complete.len()), (108, 32));
+
+        for site in 0..gridstructure.nsites() as u32 {
+            let mut by_distance: Vec<u32> = (0..gridstructure.nsites() as u32)
+                .filter(|x| *x != site && distance(&gridstructure, site, *x) < FCC_CUTOFF)
+                .collect();
+            let mut neighbors = gridstructure.nn(site).to_vec();
+            neighbors.retain(|x| *x != site);
+            by_distance.sort_unstable();
+            neighbors.sort_unstable();
+            assert_eq!(neighbors, by_distance, "neighbors of site {}", site);
+        }
+        for site in complete.iter().copied() {
+            for neighbor in gridstructure.nn(site).iter().copied() {
+                if !gridstructure.is_complete(neighbor) {
+                    continue;
+                }
+                let (from_change, to_change) =
+                    gridstructure.no_int_nn_from_move(site, neighbor).unwrap();
+                for (derived, (a, b)) in [from_change, to_change]
+                    .into_iter()
+                    .zip([(site, neighbor), (neighbor, site)])
+                {
+                    let mut derived = derived.to_vec();
+                    derived.sort_unstable();
+                    assert_eq!(
+                        derived,
+                        exclusive_by_distance(&gridstructure, a, b, FCC_CUTOFF)
+                    );
+                }
+            }
+        }
+
+        // the lines of the pairs at the boundary have fewer neighbors
+        let folder = tempfile::tempdir().unwrap();
+        let path = folder.path().join("nn_pair_no_intersec");
+        write_pair_file(&gridstructure, &path, false);
+        let mut from_file =
+            read_and_write::read_nn_pair_no_intersec(path.to_str().unwrap()).unwrap();
+        check_nn_pair_no_intersec(&gridstructure, &from_file).unwrap();
+        let far = (1..gridstructure.nsites() as u32)
+            .find(|x| !gridstructure.nn(0).contains(x))
+            .unwrap();
+        from_file.insert((far as u64) << 32, [Vec::new(), Vec::new()]);
+        let err = check_nn_pair_no_intersec(&gridstructure, &from_file).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("sites 0 and {} are not neighbors", far)
+        );
+    }
+
+    #[test]
+    fn atoms_stay_off_the_boundary_of_a_finite_grid() {
+        let (nn_lines, xsites_positions, cell) = finite_fcc(4);
+        let (cn, nn) = padded_neighbors(nn_lines);
+        let gridstructure = std::sync::Arc::new(
+            GridStructure::from_neighbors(cn, nn, xsites_positions, cell, None).unwrap(),
+        );
+        let mut sim = test_support::particle(
+            "finite_grid",
+            gridstructure.clone(),
+            &[("Pt", 30)],
+            false,
+            EnergyInput::Cn(EnergyValues {
+                complet_energy: (0..=12).map(|cn| 3700 - 370 * cn).collect(),
+                co_ads_energy: None,
+            }),
+            SimulationOptions::default(),
+        );
+        let mut rng = rand::rngs::SmallRng::seed_from_u64(7);
+        for _ in 0..3000 {
+            test_support::hop(&mut sim, &mut rng);
+            assert!(sim.onlyocc.iter().all(|x| gridstructure.is_complete(*x)));
         }
+        assert!(sim
+            .possible_moves
+            .moves
+            .iter()
+            .all(|(_, to, _)| gridstructure.is_complete(*to)));
+        assert_eq!(sim.total_energy_1000, test_support::recomputed_energy(&sim));
     }
 }
@@ -0,0 +1,227 @@
+use fnv::FnvHashMap;
+
+// examples listed per kind of problem
+const MAX_EXAMPLES: usize = 5;
+
+/// Problems of one kind, the first few of them as examples.
+struct Problems {
+    description: &'static str,
+    count: usize,
+    examples: Vec<String>,
+}
+
+impl Problems {
+    fn new(description: &'static str) -> Problems {
+        Problems {
+            description,
+            count: 0,
+            examples: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, example: impl FnOnce() -> String) {
+        if self.examples.len() < MAX_EXAMPLES {
+            self.examples.push(example());
+        }
+        self.count += 1;
+    }
+
+    fn report(self) -> Option<String> {
+        (self.count > 0).then(|| {
+            format!(
+                "{} {}: {}{}",
+                self.count,
+                self.description,
+                self.examples.join("; "),
+                if self.count > self.examples.len() {
+                    "; ..."
+                } else {
+                    ""
+                }
+            )
+        })
+    }
+}
+
+/// Neighbors of a line of a `nearest_neighbor` file without padding. The finite grids of the
+/// former Python script `create_sites.py` have sites at the boundary with fewer neighbors, whose
+/// lines are either short or padded with the site itself or repeated entries.
+pub fn distinct_neighbors(site: u32, neighbors: &[u32]) -> Vec<u32> {
+    let mut distinct = Vec::with_capacity(neighbors.len());
+    for neighbor in neighbors {
+        if *neighbor != site && !distinct.contains(neighbor) {
+            distinct.push(*neighbor);
+        }
+    }
+    distinct
+}
+
+/// Checks the lines of a `nearest_neighbor` file, given as site and neighbors, against each other
+/// and against the site positions of the `atom_sites` file. Padding as described at
+/// [`distinct_neighbors`] is not a problem. Returns one line for every kind of problem found, an
+/// empty list for a usable grid.
+pub fn validate_neighbors(
+    nn_lines: &[(u32, Vec<u32>)],
+    xsites_positions: &[[f64; 3]],
+) -> Vec<String> {
+    let mut report = Vec::new();
+    let nsites = nn_lines.len();
+    if nsites != xsites_positions.len() {
+        report.push(format!(
+            "nearest_neighbor has {} lines but atom_sites has {}",
+            nsites,
+            xsites_positions.len()
+        ));
+    }
+
+    let mut duplicate_lines = Problems::new("sites listed more than once in nearest_neighbor");
+    let mut missing_lines = Problems::new("sites missing in nearest_neighbor");
+    let mut neighbors_of: FnvHashMap<u32, &[u32]> = FnvHashMap::default();
+    for (site, neighbors) in nn_lines {
+        if neighbors_of.insert(*site, neighbors).is_some() {
+            duplicate_lines.add(|| format!("site {}", site));
+        }
+    }
+    for site in 0..nsites as u32 {
+        if !neighbors_of.contains_key(&site) {
+            missing_lines.add(|| format!("site {}", site));
+        }
+    }
+
+    let mut out_of_range = Problems::new("neighbor indices out of range");
+    let mut asymmetric = Problems::new("one-sided neighbor relations");
+    if nn_lines.iter().all(|(_, neighbors)| neighbors.is_empty()) {
+        report.push("no site in nearest_neighbor has neighbors".to_string());
+    }
+    for (site, neighbors) in nn_lines {
+        for neighbor in distinct_neighbors(*site, neighbors) {
+            if neighbor as usize >= nsites {
+                out_of_range
+                    .add(|| format!("site {} lists {} of {} sites", site, neighbor, nsites));
+                continue;
+            }
+            if let Some(back) = neighbors_of.get(&neighbor) {
+                if !back.contains(site) {
+                    asymmetric.add(|| {
+                        format!(
+                            "site {} lists {} but not the other way around",
+                            site, neighbor
+                        )
+                    });
+                }
+            }
+        }
+    }
+
+    // positions closer than 1e-3 Angstrom count as the same site
+    let mut duplicate_positions = Problems::new("sites at the same position in atom_sites");
+    let mut seen: FnvHashMap<[i64; 3], usize> = FnvHashMap::default();
+    let mut non_finite = Problems::new("sites with invalid coordinates in atom_sites");
+    for (site, xyz) in xsites_positions.iter().enumerate() {
+        if xyz.iter().any(|x| !x.is_finite()) {
+            non_finite.add(|| format!("site {}", site));
+            continue;
+        }
+        let key = xyz.map(|x| (x * 1000.).round() as i64);
+        if let Some(other) = seen.insert(key, site) {
+            duplicate_positions.add(|| format!("sites {} and {} at {:?}", other, site, xyz));
+        }
+    }
+    report.extend(
+        [
+            duplicate_lines,
+            missing_lines,
+            out_of_range,
+            asymmetric,
+            duplicate_positions,
+            non_finite,
+        ]
+        .into_iter()
+        .filter_map(Problems::report),
+    );
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type NnLines = Vec<(u32, Vec<u32>)>;
+
+    // four sites on a ring, each with its two neighbors
+    fn ring() -> (NnLines, Vec<[f64; 3]>) {
+        let nn_lines = (0..4_u32)
+            .map(|site| (site, vec![(site + 1) % 4, (site + 3) % 4]))
+            .collect();
+        let xsites_positions = (0..4).map(|site| [site as f64, 0., 0.]).collect();
+        (nn_lines, xsites_positions)
+    }
+
+    fn has_problem(report: &[String], description: &str) -> bool {
+        report.iter().any(|line| line.contains(description))
+    }
+
+    #[test]
+    fn valid_grid_has_no_problems() {
+        let (nn_lines, xsites_positions) = ring();
+        assert!(validate_neighbors(&nn_lines, &xsites_positions).is_empty());
+    }
+
+    #[test]
+    fn asymmetric_neighbors_are_reported() {
+        let (mut nn_lines, xsites_positions) = ring();
+        nn_lines[0].1 = vec![1, 2];
+        let report = validate_neighbors(&nn_lines, &xsites_positions);
+        assert_eq!(report.len(), 1, "{:?}", report);
+        assert!(
+            report[0].starts_with("2 one-sided neighbor relations"),
+            "{:?}",
+            report
+        );
+    }
+
+    #[test]
+    fn padded_and_short_neighbor_lists_are_valid() {
+        let (mut nn_lines, xsites_positions) = ring();
+        // a chain 3 0 1 2 with the lines of its ends padded and short
+        nn_lines[2].1 = vec![1, 2, 1];
+        nn_lines[3].1 = vec![0];
+        assert!(validate_neighbors(&nn_lines, &xsites_positions).is_empty());
+        assert_eq!(distinct_neighbors(2, &nn_lines[2].1), vec![1]);
+    }
+
+    #[test]
+    fn duplicate_lines_are_reported() {
+        let (mut nn_lines, xsites_positions) = ring();
+        nn_lines[3] = nn_lines[1].clone();
+        let report = validate_neighbors(&nn_lines, &xsites_positions);
+        assert!(
+            has_problem(
+                &report,
+                "1 sites listed more than once in nearest_neighbor: site 1"
+            ),
+            "{:?}",
+            report
+        );
+        assert!(
+            has_problem(&report, "1 sites missing in nearest_neighbor: site 3"),
+            "{:?}",
+            report
+        );
+    }
+
+    #[test]
+    fn out_of_range_neighbors_are_reported() {
+        let (mut nn_lines, xsites_positions) = ring();
+        nn_lines[1].1 = vec![2, 7];
+        let report = validate_neighbors(&nn_lines, &xsites_positions);
+        assert!(
+            has_problem(
+                &report,
+                "1 neighbor indices out of range: site 1 lists 7 of 4 sites"
+            ),
+            "{:?}",
+            report
+        );
+    }
+}
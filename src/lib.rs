@@ -1,5 +1,10 @@
+// the package is called MC-Cluster, so the library crate is MC_Cluster
+#![allow(non_snake_case)]
+
+use anyhow::Context;
 use csv::Writer;
 use energy::EnergyInput;
+pub use energy_model::{EnergyModel, EnergyTerm, EnergyTerms, Sites};
 use rand::distributions::{Distribution, Uniform};
 use rand::prelude::*;
 use rand::rngs::SmallRng;
@@ -10,35 +15,214 @@ use std::io::BufReader;
 use std::sync::Arc;
 use std::{fs, println};
 
+pub mod adsorbate;
+mod boundary;
+mod cell;
+pub mod cluster_expansion;
 pub mod energy;
+pub mod energy_input;
+pub mod energy_model;
+pub mod free_energy;
+mod grid_cache;
+mod grid_generator;
 mod grid_structure;
+mod grid_validation;
 mod listdict;
 mod read_and_write;
 mod results;
 mod setup;
+mod site_table;
+#[cfg(test)]
+mod test_support;
 
+pub use boundary::{Boundary, BoundaryAction};
+pub use cell::Cell;
+pub use grid_generator::Lattice;
 pub use grid_structure::GridStructure;
 pub use results::Results;
 
-const CN: usize = 12;
-const NN_PAIR_NO_INTERSEC_NUMBER: usize = 7;
 const AMOUNT_SECTIONS: usize = 10000;
 const SAVE_TH: u64 = 1000;
 
 const SAVE_ENTIRE_SIM: bool = true;
 
+/// `AtomPosition::occ` of an empty site. A metal atom has the index of its element in
+/// `AtomNames::atoms` plus one.
+const EMPTY: u8 = 0;
+/// `AtomPosition::occ` of a support atom.
+const SUPPORT: u8 = u8::MAX;
+/// Number of adsorbate species, the occupancy codes right below `SUPPORT`.
+pub const MAX_ADSORBATES: usize = 8;
+/// `AtomPosition::occ` of the first adsorbate species in `AtomNames::adsorbates`.
+const FIRST_ADSORBATE: u8 = SUPPORT - MAX_ADSORBATES as u8;
+/// Number of metal elements a particle can consist of, all occupancy codes between `EMPTY` and
+/// `FIRST_ADSORBATE`.
+pub const MAX_METALS: usize = FIRST_ADSORBATE as usize - 1;
+
+/// Boltzmann constant in eV/K.
+const KB: f64 = 8.6173324e-5;
+/// Change of the annealing temperature in K after which the free energies are rebuilt.
+const FREE_ENERGY_TEMPERATURE_STEP: f64 = 1.;
+
 #[derive(Clone, Default, Debug)]
 pub struct AtomNames {
-    pub atom: Option<String>,
+    /// Metal elements of the particle.
+    pub atoms: Vec<String>,
     pub support: Option<String>,
+    /// Adsorbate species on the sites next to the particle.
+    pub adsorbates: Vec<String>,
+}
+
+impl AtomNames {
+    /// Element name of an occupancy code, `None` for empty sites.
+    pub fn name(&self, occ: u8) -> Option<&str> {
+        match occ {
+            EMPTY => None,
+            SUPPORT => self.support.as_deref(),
+            adsorbate if adsorbate >= FIRST_ADSORBATE => self
+                .adsorbates
+                .get((adsorbate - FIRST_ADSORBATE) as usize)
+                .map(String::as_str),
+            metal => self.atoms.get(metal as usize - 1).map(String::as_str),
+        }
+    }
+
+    /// Occupancy code of an element name, `None` for unknown elements.
+    pub fn occ(&self, name: &str) -> Option<u8> {
+        if self.support.as_deref() == Some(name) {
+            return Some(SUPPORT);
+        }
+        if let Some(i) = self.adsorbates.iter().position(|x| x == name) {
+            return Some(FIRST_ADSORBATE + i as u8);
+        }
+        self.atoms
+            .iter()
+            .position(|atom| atom == name)
+            .map(|i| i as u8 + 1)
+    }
 }
 
 #[derive(Clone, Default)]
 pub struct AtomPosition {
     occ: u8,
     cn_metal: usize,
-    nn_support: u8,
-    nn: [u32; CN],
+    /// Number of support neighbors.
+    support_contacts: u8,
+    /// Metal atoms among the second shell neighbors, only counted on grids with a second shell.
+    cn2_metal: u8,
+}
+
+impl AtomPosition {
+    #[inline]
+    pub fn is_metal(&self) -> bool {
+        self.occ != EMPTY && self.occ < FIRST_ADSORBATE
+    }
+
+    #[inline]
+    pub fn is_adsorbate(&self) -> bool {
+        (FIRST_ADSORBATE..SUPPORT).contains(&self.occ)
+    }
+
+    /// Index of the species of an adsorbate in `AtomNames::adsorbates`.
+    #[inline]
+    pub fn adsorbate(&self) -> usize {
+        (self.occ - FIRST_ADSORBATE) as usize
+    }
+
+    /// Index of the element of a metal atom in `AtomNames::atoms`.
+    #[inline]
+    pub fn element(&self) -> usize {
+        self.occ as usize - 1
+    }
+
+    /// Number of metal neighbors.
+    #[inline]
+    pub fn cn_metal(&self) -> usize {
+        self.cn_metal
+    }
+
+    /// 1 for a site next to the support, 0 otherwise.
+    #[inline]
+    pub fn nn_support(&self) -> u8 {
+        (self.support_contacts > 0) as u8
+    }
+
+    /// Number of support neighbors.
+    #[inline]
+    pub fn support_contacts(&self) -> u8 {
+        self.support_contacts
+    }
+
+    /// Number of metal second shell neighbors, zero on grids without a second shell.
+    #[inline]
+    pub fn cn2_metal(&self) -> u8 {
+        self.cn2_metal
+    }
+}
+
+/// Swap moves exchanging two unlike metal atoms, which order the elements of an alloy particle
+/// much faster than moves of single atoms.
+#[derive(Clone, Copy, Debug)]
+pub struct Swaps {
+    /// Fraction of the iterations trying a swap instead of a move to an empty site.
+    pub ratio: f64,
+    pub mode: SwapMode,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SwapMode {
+    /// Swap an atom with one of its neighbors.
+    Neighbor,
+    /// Swap two atoms anywhere on the surface of the particle.
+    Surface,
+}
+
+/// Settings of a [`Simulation`] besides the start structure, the energy model and the grid. The
+/// defaults are those of the command line.
+#[derive(Clone, Debug)]
+pub struct SimulationOptions<T = EnergyTerms> {
+    pub niter: u64,
+    /// Temperature in K the annealing ends at.
+    pub temperature: f64,
+    /// Temperature in K the annealing starts at.
+    pub start_temperature: f64,
+    /// Folder the folder of the simulation is created in.
+    pub save_folder: String,
+    /// Number of snapshots spread out over the simulation.
+    pub snap_shots: Option<u32>,
+    pub heat_map: bool,
+    /// Number of the run, which is part of the folder name.
+    pub repetition: usize,
+    /// Fraction of the iterations, numerator and denominator, after which the temperature stays
+    /// constant.
+    pub optimization_cut_off_fraction: Vec<u64>,
+    /// Energy of an atom next to the support.
+    pub support_e: i64,
+    /// Energies added to the one of the energy model.
+    pub terms: T,
+    pub boundary: Option<Boundary>,
+    pub swaps: Option<Swaps>,
+    pub adsorbates: Option<adsorbate::Adsorbates>,
+}
+
+impl<T: Default> Default for SimulationOptions<T> {
+    fn default() -> Self {
+        SimulationOptions {
+            niter: 0,
+            temperature: 300.,
+            start_temperature: 5000.,
+            save_folder: String::from("./sim/"),
+            snap_shots: None,
+            heat_map: false,
+            repetition: 0,
+            optimization_cut_off_fraction: vec![1, 2],
+            support_e: 0,
+            terms: T::default(),
+            boundary: None,
+            swaps: None,
+            adsorbates: None,
+        }
+    }
 }
 
 #[derive(Clone, Default)]
@@ -48,127 +232,188 @@ pub struct Support {
 }
 
 #[derive(Clone)]
-pub struct Simulation {
+pub struct Simulation<E: EnergyModel = EnergyInput, T: EnergyTerm = EnergyTerms> {
     atom_names: AtomNames,
     atom_pos: Vec<AtomPosition>,
     niter: u64,
     number_all_atoms: u32,
     onlyocc: HashSet<u32, fnv::FnvBuildHasher>,
+    /// The same sites as `onlyocc` for drawing swap partners.
+    atom_list: listdict::SiteList,
     possible_moves: listdict::ListDict,
     total_energy_1000: i64,
-    cn_dict: [u32; CN + 1],
-    cn_dict_at_supp: [u32; CN + 1],
+    cn_dict: Vec<u32>,
+    cn_dict_at_supp: Vec<u32>,
+    /// `cn_dict` split up by the element of the atoms, indexed like `AtomNames::atoms`.
+    cn_dict_by_element: Vec<Vec<u32>>,
     save_folder: String,
     start_temperature: f64,
     temperature: f64,
     cn_dict_sections: Vec<HashMap<u8, f64>>,
+    element_cn_dict_sections: Vec<HashMap<String, HashMap<u8, f64>>>,
     energy_sections_list: Vec<f64>,
     optimization_cut_off_fraction: Vec<u64>,
     snap_shot_count: u32,
     heat_map: Option<Vec<u64>>,
     snap_shot_sections: Option<Vec<Vec<u8>>>,
     heat_map_sections: Vec<Vec<u64>>,
-    energy: EnergyInput,
+    energy: E,
+    /// Per-site data of the energy model.
+    energy_cache: Vec<E::SiteCache>,
     gridstructure: Arc<GridStructure>,
     support_e: i64,
+    /// Energies added to the one of the energy model.
+    terms: T,
+    boundary: Option<Boundary>,
+    swaps: Option<Swaps>,
+    adsorbates: Option<adsorbate::Adsorbates>,
+    /// Empty sites next to the particle an adsorbate can adsorb on.
+    adsorption_sites: listdict::SiteList,
+    /// Sites of the adsorbates.
+    adsorbate_list: listdict::SiteList,
 }
 
-fn copy_nn_in_atoms_pos(
-    atom_pos: &mut [AtomPosition],
-    nn: &HashMap<u32, [u32; CN], fnv::FnvBuildHasher>,
-) {
-    for (i, atom) in atom_pos.iter_mut().enumerate() {
-        atom.nn = *nn.get(&(i as u32)).unwrap()
-    }
-}
-
-impl Simulation {
+impl<E: EnergyModel, T: EnergyTerm> Simulation<E, T> {
     pub fn new(
         atom_names: AtomNames,
-        niter: u64,
         structure: Structure,
-        temperature: f64,
-        start_temperature: f64,
-        save_folder_name: String,
-        write_snap_shots: Option<u32>,
-        is_heat_map: bool,
-        repetition: usize,
-        optimization_cut_off_fraction: Vec<u64>,
-        energy: EnergyInput,
+        energy: E,
         gridstructure: Arc<GridStructure>,
-        support_e: i64,
-    ) -> Simulation {
-        let nsites = gridstructure.xsites_positions.len() as u32;
+        options: SimulationOptions<T>,
+    ) -> anyhow::Result<Simulation<E, T>> {
+        let SimulationOptions {
+            niter,
+            temperature,
+            start_temperature,
+            save_folder: save_folder_name,
+            snap_shots: write_snap_shots,
+            heat_map: is_heat_map,
+            repetition,
+            optimization_cut_off_fraction,
+            support_e,
+            mut terms,
+            boundary,
+            swaps,
+            adsorbates,
+        } = options;
+        terms
+            .check(&gridstructure, atom_names.atoms.len())
+            .context("the energy terms do not fit the particle")?;
+        terms.set_temperature(start_temperature);
+        let nsites = gridstructure.nsites() as u32;
         let mut atom_pos: Vec<AtomPosition> = vec![AtomPosition::default(); nsites as usize];
-        let mut cn_dict: [u32; CN + 1] = [0; CN + 1];
-        let mut cn_dict_at_supp: [u32; CN + 1] = [0; CN + 1];
+        let mut cn_dict: Vec<u32> = vec![0; gridstructure.cn() + 1];
+        let mut cn_dict_at_supp: Vec<u32> = vec![0; gridstructure.cn() + 1];
+        let mut cn_dict_by_element: Vec<Vec<u32>> =
+            vec![vec![0; gridstructure.cn() + 1]; atom_names.atoms.len()];
         let (onlyocc, number_all_atoms) = match structure {
             Structure::StartStructure(xyz) => {
                 let onlyocc = setup::occ_onlyocc_from_xyz(
                     &mut atom_pos,
                     xyz,
                     nsites,
-                    &gridstructure.xsites_positions,
                     &atom_names,
-                    &gridstructure.nn,
+                    &gridstructure,
                 );
                 let number_of_atoms: u32 = onlyocc.len() as u32;
                 (onlyocc, number_of_atoms)
             }
             Structure::CreateCluster(cluster) => {
-                let number_of_atom = cluster.atom_count;
+                let number_of_atom = cluster.atom_count();
                 let onlyocc = setup::create_input_cluster(
                     &mut atom_pos,
                     &number_of_atom,
-                    &gridstructure.xsites_positions,
-                    &gridstructure.nn,
+                    &gridstructure,
                     nsites,
                     // support.as_ref(),
                     cluster.support_vector.as_ref(),
                 );
+                let counts: Vec<u32> = cluster.atoms.iter().map(|(_, count)| *count).collect();
+                setup::assign_elements(&mut atom_pos, &onlyocc, &counts);
                 (onlyocc, number_of_atom)
             }
         };
+        // sites at the boundary of a finite grid lack neighbors
+        if let Some(site) = (0..nsites).find(|x| {
+            atom_pos[*x as usize].occ != EMPTY
+                && atom_pos[*x as usize].occ != SUPPORT
+                && !gridstructure.is_complete(*x)
+        }) {
+            anyhow::bail!(
+                "the particle reaches site {} at the boundary of the grid, which has only {} \
+                 neighbors, use a larger grid",
+                site,
+                grid_validation::distinct_neighbors(site, gridstructure.nn(site)).len()
+            );
+        }
 
         for o in 0..nsites {
             let mut neighbors: u8 = 0;
-            for o1 in gridstructure.nn[&o].iter() {
-                if atom_pos[*o1 as usize].occ == 1 {
+            for o1 in gridstructure.nn(o) {
+                if atom_pos[*o1 as usize].is_metal() {
                     // cn.entry(o).and_modify(|x| *x += 1).or_insert(1);
                     neighbors += 1;
                 }
             }
             atom_pos[o as usize].cn_metal = neighbors as usize;
-            if atom_pos[o as usize].occ == 1 {
-                if support_e != 0 {
-                    if atom_pos[o as usize].nn_support == 1 {
-                        cn_dict_at_supp[atom_pos[o as usize].cn_metal] += 1;
-                    }
+            if atom_pos[o as usize].is_metal() {
+                if atom_pos[o as usize].nn_support() == 1 {
+                    cn_dict_at_supp[atom_pos[o as usize].cn_metal] += 1;
                 }
                 cn_dict[atom_pos[o as usize].cn_metal] += 1;
+                cn_dict_by_element[atom_pos[o as usize].element()]
+                    [atom_pos[o as usize].cn_metal] += 1;
             };
         }
 
+        if gridstructure.cn2() > 0 {
+            for o in 0..nsites {
+                atom_pos[o as usize].cn2_metal = gridstructure
+                    .nn2(o)
+                    .iter()
+                    .filter(|x| atom_pos[**x as usize].is_metal())
+                    .count() as u8;
+            }
+        }
+
         let mut total_energy_1000: i64 = 0;
+        let mut energy_cache = vec![E::SiteCache::default(); nsites as usize];
+        let sites = Sites {
+            atom_pos: &atom_pos,
+            gridstructure: &gridstructure,
+            support_e,
+        };
+        energy.init_cache(&sites, &mut energy_cache);
+        let adsorbate_list = listdict::SiteList::new(
+            nsites,
+            (0..nsites).filter(|x| atom_pos[*x as usize].is_adsorbate()),
+        );
+        let adsorption_sites = if let Some(adsorbates) = &adsorbates {
+            total_energy_1000 += adsorbates.total_energy(&sites);
+            listdict::SiteList::new(
+                nsites,
+                (0..nsites).filter(|x| {
+                    atom_pos[*x as usize].occ == EMPTY
+                        && atom_pos[*x as usize].cn_metal > 0
+                        && gridstructure.is_complete(*x)
+                }),
+            )
+        } else {
+            if !adsorbate_list.is_empty() {
+                anyhow::bail!("a start structure with adsorbates needs their energies");
+            }
+            listdict::SiteList::new(nsites, std::iter::empty())
+        };
         let mut possible_moves: listdict::ListDict = listdict::ListDict::new(nsites);
         for o in onlyocc.iter() {
-            let temp_total_e = total_energy_1000;
-            match energy {
-                EnergyInput::LinearCn(_) | EnergyInput::Cn(_) => {
-                    total_energy_1000 += energy::energy_1000_calculation(
-                        &energy,
-                        atom_pos[*o as usize].cn_metal,
-                        atom_pos[*o as usize].nn_support,
-                        support_e,
-                    );
-                }
-            };
+            total_energy_1000 += energy.site_energy(&sites, &energy_cache, *o);
+            total_energy_1000 += terms.site_energy(&sites, *o);
 
-            for u in &gridstructure.nn[o] {
-                if atom_pos[*u as usize].occ == 0 {
+            for u in gridstructure.nn(*o) {
+                if atom_pos[*u as usize].occ == EMPTY {
                     // >1 so that atoms cant leave the cluster
                     // <x cant move if all neighbors are occupied
-                    if atom_pos[*u as usize].cn_metal > 1 {
+                    if atom_pos[*u as usize].cn_metal > 1 && gridstructure.is_complete(*u) {
                         possible_moves.add_item(*o, *u, None)
                     }
                 }
@@ -211,21 +456,23 @@ impl Simulation {
 
         let heat_map_sections: Vec<Vec<u64>> = Vec::new();
 
-        copy_nn_in_atoms_pos(&mut atom_pos, &gridstructure.nn);
-        Simulation {
+        Ok(Simulation {
             atom_names,
             atom_pos,
             niter,
             number_all_atoms,
+            atom_list: listdict::SiteList::new(nsites, onlyocc.iter().copied()),
             onlyocc,
             possible_moves,
             total_energy_1000,
             cn_dict,
             cn_dict_at_supp,
+            cn_dict_by_element,
             save_folder: sub_folder,
             start_temperature,
             temperature,
             cn_dict_sections,
+            element_cn_dict_sections: Vec::new(),
             energy_sections_list,
             optimization_cut_off_fraction,
             snap_shot_count: write_snap_shots.unwrap_or_default(),
@@ -233,9 +480,16 @@ impl Simulation {
             heat_map,
             heat_map_sections,
             energy,
+            energy_cache,
             gridstructure,
             support_e,
-        }
+            terms,
+            boundary,
+            swaps,
+            adsorbates,
+            adsorption_sites,
+            adsorbate_list,
+        })
     }
 
     pub fn run(&mut self) -> Results {
@@ -247,13 +501,23 @@ impl Simulation {
         let mut lowest_energy_struct = results::LowestEnergy::new();
 
         let mut temp_energy_section: i64 = 0;
-        let mut temp_cn_dict_section: [u64; CN + 1] = [0; CN + 1];
+        let mut temp_cn_dict_section: Vec<u64> = vec![0; self.gridstructure.cn() + 1];
+        let mut temp_element_cn_section: Vec<Vec<u64>> =
+            vec![vec![0; self.gridstructure.cn() + 1]; self.atom_names.atoms.len()];
 
         let start = results::Start::new(self.total_energy_1000, &self.cn_dict);
 
         if self.niter == 0 {
             lowest_energy_struct.update(self, &0);
         }
+        let mut recenterings = Vec::new();
+        let mut boundary_stop = None;
+        let mut hop_moves = results::MoveStatistics::default();
+        let mut swap_moves = results::MoveStatistics::default();
+        let mut swap_misses = 0;
+        let mut adsorbate_moves = results::MoveStatistics::default();
+        let adsorbate_ratio = self.adsorbates.as_ref().map_or(0., |x| x.ratio);
+
         let section_size: u64 = self.niter / AMOUNT_SECTIONS as u64;
         println!("section_size: {}", section_size);
         println!("SAVE_TH: {}", SAVE_TH);
@@ -272,6 +536,33 @@ impl Simulation {
                 );
                 // println!("{:?}", self.atom_pos.cn_metal);
             }
+            if let Some(boundary) = self.boundary.filter(|_| iiter % SAVE_TH == 0) {
+                match self.keep_off_boundary(boundary) {
+                    Ok(Some((map, translation))) => {
+                        lowest_energy_struct
+                            .atoms
+                            .iter_mut()
+                            .for_each(|(site, _)| *site = map[*site as usize]);
+                        println!(
+                            "recentered the particle at iteration {} by {:?}",
+                            iiter, translation
+                        );
+                        recenterings.push(results::Recentering { iiter, translation });
+                    }
+                    Ok(None) => {}
+                    Err(err) => {
+                        eprintln!(
+                            "stopping {} at iteration {}: {:#}",
+                            self.save_folder, iiter, err
+                        );
+                        boundary_stop = Some(results::BoundaryStop {
+                            iiter,
+                            error: format!("{:#}", err),
+                        });
+                        break;
+                    }
+                }
+            }
             let is_recording_sections = iiter * self.optimization_cut_off_fraction[1]
                 >= self.niter * self.optimization_cut_off_fraction[0];
 
@@ -285,9 +576,13 @@ impl Simulation {
                 self.cn_dict_at_supp.iter_mut().for_each(|x| {
                     *x = 0;
                 });
+                self.cn_dict_by_element
+                    .iter_mut()
+                    .flatten()
+                    .for_each(|x| *x = 0);
                 for o in 0..self.atom_pos.len() {
-                    if self.atom_pos[o].occ == 1 {
-                        self.update_cn_dict(o, self.atom_pos[o as usize].cn_metal, true);
+                    if self.atom_pos[o].is_metal() {
+                        self.update_cn_dict(o, self.atom_pos[o].cn_metal, true);
                         // if let Some(nn_support) = self.atom_pos.nn_support {
                         //     if nn_support[o as usize] == 1 {
                         //         self.cn_dict_at_supp[self.atom_pos.cn_metal[o as usize]] += 1;
@@ -302,22 +597,51 @@ impl Simulation {
                 }
             };
 
-            let (move_from, move_to, _) =
-                self.possible_moves.choose_random_item_mc(&mut rng_choose);
-
-            let energy1000_diff = self.energy_change_by_move(move_from, move_to);
+            self.update_temperature(self.calculate_current_temp(iiter, cut_off_perc));
 
-            if self.is_acceptance_criteria_fulfilled(
-                energy1000_diff,
-                &mut rng_choose,
-                iiter,
-                cut_off_perc,
-            ) {
-                self.perform_move(move_from, move_to, energy1000_diff, is_recording_sections);
-                self.update_possible_moves(move_from, move_to);
-                if let Some(map) = &mut self.heat_map {
-                    map[move_to as usize] += 1;
-                    map[move_from as usize] += 1;
+            if adsorbate_ratio > 0. && rng_choose.gen_bool(adsorbate_ratio) {
+                adsorbate_moves.attempted += 1;
+                if self.exchange_adsorbate(&mut rng_choose, iiter, cut_off_perc) {
+                    adsorbate_moves.accepted += 1;
+                }
+            } else if let Some(swaps) = self.swaps.filter(|swaps| rng_choose.gen_bool(swaps.ratio))
+            {
+                if let Some((site_a, site_b)) = self.choose_swap(swaps.mode, &mut rng_choose) {
+                    swap_moves.attempted += 1;
+                    let energy1000_diff = self.energy_change_by_swap(site_a, site_b);
+                    if self.is_acceptance_criteria_fulfilled(
+                        energy1000_diff,
+                        &mut rng_choose,
+                        iiter,
+                        cut_off_perc,
+                    ) {
+                        swap_moves.accepted += 1;
+                        self.perform_swap(site_a, site_b, energy1000_diff, is_recording_sections);
+                    }
+                } else {
+                    swap_misses += 1;
+                }
+            } else if let Some((move_from, move_to, _)) =
+                self.possible_moves.choose_random_item_mc(&mut rng_choose)
+            {
+                let energy1000_diff = self.energy_change_by_move(move_from, move_to);
+
+                hop_moves.attempted += 1;
+                if !self.strands_adsorbate(move_from, move_to)
+                    && self.is_acceptance_criteria_fulfilled(
+                        energy1000_diff,
+                        &mut rng_choose,
+                        iiter,
+                        cut_off_perc,
+                    )
+                {
+                    hop_moves.accepted += 1;
+                    self.perform_move(move_from, move_to, energy1000_diff, is_recording_sections);
+                    self.update_possible_moves(move_from, move_to);
+                    if let Some(map) = &mut self.heat_map {
+                        map[move_to as usize] += 1;
+                        map[move_from as usize] += 1;
+                    }
                 }
             }
 
@@ -334,19 +658,18 @@ impl Simulation {
                     &iiter,
                     temp_energy_section,
                     &mut temp_cn_dict_section,
+                    &mut temp_element_cn_section,
                     section_size,
                 );
             }
         }
 
-        println!("heatmap section len: {:?}", self.heat_map_sections.len());
-
         read_and_write::write_occ_as_xyz(
             &self.atom_names,
             self.save_folder.clone(),
-            &lowest_energy_struct.onlyocc,
+            &lowest_energy_struct.atoms,
             &self.gridstructure.xsites_positions,
-            &self.gridstructure.unit_cell,
+            &self.gridstructure.cell,
             &self.atom_pos,
         );
 
@@ -355,7 +678,7 @@ impl Simulation {
             for heat_section in &self.heat_map_sections {
                 wtr.write_record(heat_section.iter().map(|x| {
                     if x == &1 {
-                        self.atom_names.atom.clone().unwrap()
+                        self.atom_names.atoms[0].clone()
                     } else {
                         self.atom_names.support.clone().unwrap()
                     }
@@ -371,7 +694,7 @@ impl Simulation {
                 &self.atom_names,
                 snap_shot_sections,
                 self.save_folder.clone() + "/snapshot_sections.xyz",
-                &self.gridstructure.unit_cell,
+                &self.gridstructure.cell,
             )
             .unwrap();
         }
@@ -382,6 +705,13 @@ impl Simulation {
             number_all_atoms: self.number_all_atoms,
             energy_section_list: self.energy_sections_list.clone(),
             cn_dict_sections: self.cn_dict_sections.clone(),
+            element_cn_dict_sections: self.element_cn_dict_sections.clone(),
+            recenterings,
+            boundary_stop,
+            hop_moves,
+            swap_moves,
+            swap_misses,
+            adsorbate_moves,
         }
     }
 
@@ -395,19 +725,28 @@ impl Simulation {
         &mut self,
         iiter: &u64,
         mut temp_energy_section_1000: i64,
-        temp_cn_dict_section: &mut [u64; CN + 1],
+        temp_cn_dict_section: &mut [u64],
+        temp_element_cn_section: &mut [Vec<u64>],
         section_size: u64,
     ) -> i64 {
-        if (iiter + 1) % SAVE_TH == 0 {
+        if (iiter + 1).is_multiple_of(SAVE_TH) {
             temp_energy_section_1000 += self.total_energy_1000;
 
             temp_cn_dict_section
                 .iter_mut()
                 .enumerate()
                 .for_each(|(i, v)| *v += self.cn_dict[i] as u64);
+            for (temp, cn_dict) in temp_element_cn_section
+                .iter_mut()
+                .zip(&self.cn_dict_by_element)
+            {
+                temp.iter_mut()
+                    .zip(cn_dict)
+                    .for_each(|(v, count)| *v += *count as u64);
+            }
         }
 
-        if (iiter + 1) % section_size == 0 {
+        if (iiter + 1).is_multiple_of(section_size) {
             self.energy_sections_list
                 .push(temp_energy_section_1000 as f64 / (section_size / SAVE_TH) as f64 / 1000.);
             temp_energy_section_1000 = 0;
@@ -417,8 +756,25 @@ impl Simulation {
                 section.insert(k as u8, *list as f64 / (section_size / SAVE_TH) as f64);
                 *list = 0;
             }
-            assert_eq!(temp_cn_dict_section, &mut [0_u64; CN + 1]);
-            self.cn_dict_sections.push(section.clone())
+            assert!(temp_cn_dict_section.iter().all(|x| *x == 0));
+            self.cn_dict_sections.push(section.clone());
+
+            if self.atom_names.atoms.len() > 1 {
+                let element_sections = temp_element_cn_section
+                    .iter_mut()
+                    .zip(&self.atom_names.atoms)
+                    .map(|(list, name)| {
+                        let section = list
+                            .iter()
+                            .enumerate()
+                            .map(|(k, v)| (k as u8, *v as f64 / (section_size / SAVE_TH) as f64))
+                            .collect();
+                        list.iter_mut().for_each(|x| *x = 0);
+                        (name.clone(), section)
+                    })
+                    .collect();
+                self.element_cn_dict_sections.push(element_sections);
+            }
         }
         temp_energy_section_1000
     }
@@ -447,6 +803,26 @@ impl Simulation {
         // }
     }
 
+    /// Rebuilds the energy terms that depend on the annealing temperature and shifts the energy
+    /// of the particle to their new energies.
+    fn update_temperature(&mut self, temperature: f64) {
+        if !self.terms.is_outdated(temperature) {
+            return;
+        }
+        let previous = self.terms_energy();
+        self.terms.set_temperature(temperature);
+        self.total_energy_1000 += self.terms_energy() - previous;
+    }
+
+    /// Energy of the terms summed over all atoms.
+    fn terms_energy(&self) -> i64 {
+        let sites = self.sites();
+        self.onlyocc
+            .iter()
+            .map(|o| self.terms.site_energy(&sites, *o))
+            .sum()
+    }
+
     fn is_acceptance_criteria_fulfilled(
         &mut self,
         energy1000_diff: i64,
@@ -454,7 +830,6 @@ impl Simulation {
         iiter: u64,
         cut_off_perc: f64,
     ) -> bool {
-        const KB: f64 = 8.6173324e-5;
         if energy1000_diff <= 0 {
             return true;
         }
@@ -464,6 +839,113 @@ impl Simulation {
         (rand_value) < ((-energy1000_diff as f64 / 1000.) / (KB * acceptance_temp)).exp()
     }
 
+    /// Acceptance of an adsorption or desorption, which is proposed `factor` times as often in
+    /// reverse as in this direction.
+    fn is_exchange_accepted(
+        &self,
+        energy1000_diff: i64,
+        factor: f64,
+        rng_e_number: &mut SmallRng,
+        iiter: u64,
+        cut_off_perc: f64,
+    ) -> bool {
+        let acceptance_temp = self.calculate_current_temp(iiter, cut_off_perc);
+        let probability =
+            factor * ((-energy1000_diff as f64 / 1000.) / (KB * acceptance_temp)).exp();
+        probability >= 1. || Uniform::new_inclusive(0., 1.).sample(rng_e_number) < probability
+    }
+
+    /// Adsorbs an adsorbate of a random species on a random empty site next to the particle or
+    /// desorbs a random adsorbate, each with half the probability. Returns whether it was
+    /// accepted.
+    fn exchange_adsorbate(
+        &mut self,
+        rng_choose: &mut SmallRng,
+        iiter: u64,
+        cut_off_perc: f64,
+    ) -> bool {
+        let adsorbates = self.adsorbates.as_ref().unwrap();
+        let species_count = adsorbates.species();
+        let free = self.adsorption_sites.len() as f64;
+        let adsorbed = self.adsorbate_list.len() as f64;
+        if rng_choose.gen_bool(0.5) {
+            if self.adsorption_sites.is_empty() {
+                return false;
+            }
+            let site = self.adsorption_sites.choose_random_site(rng_choose);
+            let species = rng_choose.gen_range(0..species_count);
+            let energy1000_diff = adsorbates.adsorption_energy(&self.sites(), site, species);
+            let factor = species_count as f64 * free / (adsorbed + 1.);
+            if !self.is_exchange_accepted(energy1000_diff, factor, rng_choose, iiter, cut_off_perc)
+            {
+                return false;
+            }
+            self.adsorb(site, species, energy1000_diff);
+        } else {
+            if self.adsorbate_list.is_empty() {
+                return false;
+            }
+            let site = self.adsorbate_list.choose_random_site(rng_choose);
+            let species = self.atom_pos[site as usize].adsorbate();
+            let energy1000_diff = -adsorbates.adsorption_energy(&self.sites(), site, species);
+            let factor = adsorbed / (species_count as f64 * (free + 1.));
+            if !self.is_exchange_accepted(energy1000_diff, factor, rng_choose, iiter, cut_off_perc)
+            {
+                return false;
+            }
+            self.desorb(site, energy1000_diff);
+        }
+        true
+    }
+
+    /// Puts an adsorbate of `species` on the empty adsorption site `site`, which no metal atom
+    /// can move to anymore.
+    fn adsorb(&mut self, site: u32, species: usize, energy1000_diff: i64) {
+        self.atom_pos[site as usize].occ = FIRST_ADSORBATE + species as u8;
+        self.adsorption_sites.remove(site);
+        self.adsorbate_list.insert(site);
+        for &neighbor in self.gridstructure.nn(site) {
+            if self.atom_pos[neighbor as usize].is_metal() {
+                self.possible_moves.remove_item(neighbor, site);
+            }
+        }
+        self.total_energy_1000 += energy1000_diff;
+    }
+
+    /// Removes the adsorbate at `site`, which becomes an adsorption site again.
+    fn desorb(&mut self, site: u32, energy1000_diff: i64) {
+        self.atom_pos[site as usize].occ = EMPTY;
+        self.adsorbate_list.remove(site);
+        self.adsorption_sites.insert(site);
+        if self.atom_pos[site as usize].cn_metal > 1 {
+            for &neighbor in self.gridstructure.nn(site) {
+                if self.atom_pos[neighbor as usize].is_metal() {
+                    self.possible_moves.add_item(neighbor, site, None);
+                }
+            }
+        }
+        self.total_energy_1000 += energy1000_diff;
+    }
+
+    /// Whether moving the atom at `move_from` leaves an adsorbate without metal neighbor.
+    #[inline]
+    fn strands_adsorbate(&self, move_from: u32, move_to: u32) -> bool {
+        !self.adsorbate_list.is_empty()
+            && adsorbate::Adsorbates::strands_adsorbate(&self.sites(), move_from, move_to)
+    }
+
+    /// Keeps `site` among the adsorption sites exactly when it is empty, next to a metal atom and
+    /// not at the boundary of a finite grid.
+    #[inline]
+    fn update_adsorption_site(&mut self, site: u32) {
+        let atom = &self.atom_pos[site as usize];
+        if atom.occ == EMPTY && atom.cn_metal > 0 && self.gridstructure.is_complete(site) {
+            self.adsorption_sites.insert(site);
+        } else {
+            self.adsorption_sites.remove(site);
+        }
+    }
+
     fn perform_move(
         &mut self,
         move_from: u32,
@@ -471,12 +953,15 @@ impl Simulation {
         energy1000_diff: i64,
         is_recording_sections: bool,
     ) {
-        self.atom_pos[move_to as usize].occ = self.atom_pos[move_from as usize].occ; // covers different alloys also
-        self.atom_pos[move_from as usize].occ = 0;
-
-        self.onlyocc.remove(&move_from);
-        self.onlyocc.insert(move_to);
+        let sites = Sites {
+            atom_pos: &self.atom_pos,
+            gridstructure: &self.gridstructure,
+            support_e: self.support_e,
+        };
+        self.energy
+            .update_cache(&sites, &mut self.energy_cache, move_from, move_to);
 
+        // the element histogram needs the atom still at move_from
         if SAVE_ENTIRE_SIM || is_recording_sections {
             // self.cn_dict[self.atom_pos.cn_metal[move_from as usize]] -= 1;
             self.update_cn_dict(
@@ -485,10 +970,18 @@ impl Simulation {
                 false,
             );
         }
+
+        self.atom_pos[move_to as usize].occ = self.atom_pos[move_from as usize].occ; // covers different alloys also
+        self.atom_pos[move_from as usize].occ = EMPTY;
+
+        self.onlyocc.remove(&move_from);
+        self.onlyocc.insert(move_to);
+        self.atom_list.replace(move_from, move_to);
         // let (from_change, to_change) = self.no_int_from_move(move_from, move_to);
-        for o in self.atom_pos[move_from as usize].nn {
+        for slot in 0..self.gridstructure.cn() {
+            let o = self.gridstructure.nn(move_from)[slot];
             if (SAVE_ENTIRE_SIM || is_recording_sections)
-                && self.atom_pos[o as usize].occ == 1
+                && self.atom_pos[o as usize].is_metal()
                 && o != move_to
             {
                 self.update_cn_dict(o as usize, self.atom_pos[o as usize].cn_metal, false);
@@ -498,9 +991,10 @@ impl Simulation {
             }
             self.atom_pos[o as usize].cn_metal -= 1;
         }
-        for o in self.atom_pos[move_to as usize].nn {
+        for slot in 0..self.gridstructure.cn() {
+            let o = self.gridstructure.nn(move_to)[slot];
             if (SAVE_ENTIRE_SIM || is_recording_sections)
-                && self.atom_pos[o as usize].occ == 1
+                && self.atom_pos[o as usize].is_metal()
                 && o != move_from
             {
                 self.update_cn_dict(o as usize, self.atom_pos[o as usize].cn_metal, false);
@@ -520,73 +1014,130 @@ impl Simulation {
             // self.cn_dict[self.atom_pos.cn_metal[move_to as usize]] += 1;
         }
 
+        if self.gridstructure.cn2() > 0 {
+            for o in self.gridstructure.nn2(move_from) {
+                self.atom_pos[*o as usize].cn2_metal -= 1;
+            }
+            for o in self.gridstructure.nn2(move_to) {
+                self.atom_pos[*o as usize].cn2_metal += 1;
+            }
+        }
+
+        if self.adsorbates.is_some() {
+            for site in [move_from, move_to] {
+                self.update_adsorption_site(site);
+                for slot in 0..self.gridstructure.cn() {
+                    self.update_adsorption_site(self.gridstructure.nn(site)[slot]);
+                }
+            }
+        }
+
         self.total_energy_1000 += energy1000_diff;
     }
 
     fn energy_change_by_move(&self, move_from: u32, move_to: u32) -> i64 {
-        let (from_at_support, to_at_support, support_e) = if self.support_e != 0 {
-            let from_at_support = self.atom_pos[move_from as usize].nn_support;
-            let to_at_support = self.atom_pos[move_to as usize].nn_support;
-            (from_at_support, to_at_support, self.support_e)
-        } else {
-            (0, 0, 0)
+        let sites = self.sites();
+        let adsorbate_diff = match &self.adsorbates {
+            Some(adsorbates) if !self.adsorbate_list.is_empty() => {
+                adsorbates.move_energy_diff(&sites, move_from, move_to)
+            }
+            _ => 0,
         };
+        let terms_diff = self.terms.move_energy_diff(&sites, move_from, move_to);
 
-        match &self.energy {
-            EnergyInput::LinearCn(energy_l_cn) => energy::energy_diff_l_cn(
-                energy_l_cn.complet_energy,
-                self.atom_pos[move_from as usize].cn_metal,
-                self.atom_pos[move_to as usize].cn_metal - 1,
-                from_at_support,
-                to_at_support,
-                support_e,
-            ),
-            EnergyInput::Cn(energy_cn) => {
-                let (from_change, to_change) = no_int_nn_from_move(
-                    move_from,
-                    move_to,
-                    &self.gridstructure.nn_pair_no_intersec,
-                );
+        terms_diff
+            + adsorbate_diff
+            + self
+                .energy
+                .move_energy_diff(&sites, &self.energy_cache, move_from, move_to)
+    }
 
-                energy::energy_diff_cn(
-                    energy_cn,
-                    from_change.iter().filter_map(|x| {
-                        if self.atom_pos[*x as usize].occ == 1 {
-                            Some((
-                                self.atom_pos[*x as usize].cn_metal,
-                                self.atom_pos[*x as usize].nn_support,
-                            ))
-                        } else {
-                            None
-                        }
-                    }),
-                    to_change.iter().filter_map(|x| {
-                        if self.atom_pos[*x as usize].occ == 1 {
-                            Some((
-                                self.atom_pos[*x as usize].cn_metal,
-                                self.atom_pos[*x as usize].nn_support,
-                            ))
-                        } else {
-                            None
-                        }
-                    }),
-                    self.atom_pos[move_from as usize].cn_metal,
-                    self.atom_pos[move_to as usize].cn_metal,
-                    from_at_support,
-                    to_at_support,
-                    support_e,
-                )
+    /// The sites as seen by the energy model.
+    #[inline]
+    fn sites(&self) -> Sites<'_> {
+        Sites {
+            atom_pos: &self.atom_pos,
+            gridstructure: &self.gridstructure,
+            support_e: self.support_e,
+        }
+    }
+
+    /// Two unlike metal atoms to swap, `None` if the drawn atoms are of the same element or for
+    /// `SwapMode::Neighbor` the drawn neighbor is no metal atom. Both modes propose every pair
+    /// with the same probability in both directions.
+    fn choose_swap(&self, mode: SwapMode, rng_choose: &mut SmallRng) -> Option<(u32, u32)> {
+        let site_a = self.atom_list.choose_random_site(rng_choose);
+        let site_b = match mode {
+            SwapMode::Neighbor => *self.gridstructure.nn(site_a).choose(rng_choose).unwrap(),
+            SwapMode::Surface => {
+                let site_b = self.atom_list.choose_random_site(rng_choose);
+                let cn = self.gridstructure.cn();
+                if self.atom_pos[site_a as usize].cn_metal == cn
+                    || self.atom_pos[site_b as usize].cn_metal == cn
+                {
+                    return None;
+                }
+                site_b
             }
+        };
+        let (atom_a, atom_b) = (
+            &self.atom_pos[site_a as usize],
+            &self.atom_pos[site_b as usize],
+        );
+        (atom_b.is_metal() && atom_a.occ != atom_b.occ).then_some((site_a, site_b))
+    }
+
+    /// Energy change of swapping the atoms at `site_a` and `site_b`.
+    fn energy_change_by_swap(&self, site_a: u32, site_b: u32) -> i64 {
+        let sites = self.sites();
+        let adsorbate_diff = match &self.adsorbates {
+            Some(adsorbates) if !self.adsorbate_list.is_empty() => {
+                adsorbates.swap_energy_diff(&sites, site_a, site_b)
+            }
+            _ => 0,
+        };
+        let terms_diff = self.terms.swap_energy_diff(&sites, site_a, site_b);
+        terms_diff
+            + adsorbate_diff
+            + self
+                .energy
+                .swap_energy_diff(&sites, &self.energy_cache, site_a, site_b)
+    }
+
+    /// Swaps the elements of two atoms, which leaves the occupied sites, the coordination numbers
+    /// and so the possible moves as they are.
+    fn perform_swap(
+        &mut self,
+        site_a: u32,
+        site_b: u32,
+        energy1000_diff: i64,
+        is_recording_sections: bool,
+    ) {
+        let (cn_a, cn_b) = (
+            self.atom_pos[site_a as usize].cn_metal,
+            self.atom_pos[site_b as usize].cn_metal,
+        );
+        if SAVE_ENTIRE_SIM || is_recording_sections {
+            self.update_cn_dict(site_a as usize, cn_a, false);
+            self.update_cn_dict(site_b as usize, cn_b, false);
         }
+        let occ_a = self.atom_pos[site_a as usize].occ;
+        self.atom_pos[site_a as usize].occ = self.atom_pos[site_b as usize].occ;
+        self.atom_pos[site_b as usize].occ = occ_a;
+        if SAVE_ENTIRE_SIM || is_recording_sections {
+            self.update_cn_dict(site_a as usize, cn_a, true);
+            self.update_cn_dict(site_b as usize, cn_b, true);
+        }
+        self.total_energy_1000 += energy1000_diff;
     }
 
     fn update_possible_moves(&mut self, move_from: u32, move_to: u32) {
         self.possible_moves.remove_item(move_from, move_to);
-        for neighbor_atom in self.atom_pos[move_from as usize].nn {
-            if self.atom_pos[neighbor_atom as usize].occ == 0 {
+        for &neighbor_atom in self.gridstructure.nn(move_from) {
+            if self.atom_pos[neighbor_atom as usize].occ == EMPTY {
                 self.possible_moves.remove_item(move_from, neighbor_atom);
             }
-            if self.atom_pos[neighbor_atom as usize].occ == 1 {
+            if self.atom_pos[neighbor_atom as usize].is_metal() {
                 // greater than one because of neighbor moving in this spot
                 if self.atom_pos[move_from as usize].cn_metal > 1 {
                     self.possible_moves.add_item(neighbor_atom, move_from, None);
@@ -594,13 +1145,15 @@ impl Simulation {
             }
         }
 
-        for empty_neighbor in self.atom_pos[move_to as usize].nn {
-            if self.atom_pos[empty_neighbor as usize].occ == 1 {
+        for &empty_neighbor in self.gridstructure.nn(move_to) {
+            if self.atom_pos[empty_neighbor as usize].is_metal() {
                 self.possible_moves.remove_item(empty_neighbor, move_to);
             }
-            if self.atom_pos[empty_neighbor as usize].occ == 0 {
+            if self.atom_pos[empty_neighbor as usize].occ == EMPTY {
                 // greater than one because of neighbor moving in this spot
-                if self.atom_pos[empty_neighbor as usize].cn_metal > 1 {
+                if self.atom_pos[empty_neighbor as usize].cn_metal > 1
+                    && self.gridstructure.is_complete(empty_neighbor)
+                {
                     self.possible_moves.add_item(move_to, empty_neighbor, None);
                 }
             }
@@ -611,7 +1164,7 @@ impl Simulation {
         // const NUMBER_HEAT_MAP_SECTIONS: u64 = 200;
 
         if let Some(snap_shot_sections) = &mut self.snap_shot_sections {
-            if (iiter + 1) % (self.niter / self.snap_shot_count as u64) == 0 {
+            if (iiter + 1).is_multiple_of(self.niter / self.snap_shot_count as u64) {
                 if iiter == &0 {
                     return;
                 }
@@ -625,7 +1178,7 @@ impl Simulation {
         }
 
         if let Some(heat_map) = &mut self.heat_map {
-            if (iiter + 1) % (self.niter / self.snap_shot_count as u64) == 0 {
+            if (iiter + 1).is_multiple_of(self.niter / self.snap_shot_count as u64) {
                 if iiter == &0 {
                     return;
                 }
@@ -639,28 +1192,165 @@ impl Simulation {
         }
     }
 
+    /// Recenters the particle by a lattice vector when an occupied site is closer to the cell
+    /// boundary than the margin, where the periodic images of the particle start to see each other
+    /// and snapshots show it cut in pieces. Returns the site permutation and the translation, an
+    /// error when the simulation has to stop.
+    fn keep_off_boundary(
+        &mut self,
+        boundary: Boundary,
+    ) -> anyhow::Result<Option<(Vec<u32>, [f64; 3])>> {
+        let distance = boundary::distance_to_boundary(
+            &self.gridstructure,
+            self.onlyocc
+                .iter()
+                .chain(self.adsorbate_list.iter())
+                .copied(),
+        );
+        if distance >= boundary.margin {
+            return Ok(None);
+        }
+        if boundary.action == BoundaryAction::Stop {
+            anyhow::bail!(
+                "the particle is {:.2} A from the grid boundary, closer than the margin of {} A",
+                distance,
+                boundary.margin
+            );
+        }
+        let mut occupied: Vec<u32> = self
+            .onlyocc
+            .iter()
+            .chain(self.adsorbate_list.iter())
+            .copied()
+            .collect();
+        occupied.sort_unstable();
+        let Some((map, translation)) = boundary::recentering_map(&self.gridstructure, &occupied)
+        else {
+            anyhow::bail!("the grid has no lattice translation to recenter the particle");
+        };
+        let distance = boundary::distance_to_boundary(
+            &self.gridstructure,
+            occupied.iter().map(|x| map[*x as usize]),
+        );
+        if distance < boundary.margin {
+            anyhow::bail!(
+                "the particle is too large for the grid, after recentering it would still be {:.2} A \
+                 from the boundary, closer than the margin of {} A",
+                distance,
+                boundary.margin
+            );
+        }
+        self.move_sites(&map);
+        Ok(Some((map, translation)))
+    }
+
+    /// Moves everything on the sites to the sites `map` gives for them.
+    fn move_sites(&mut self, map: &[u32]) {
+        let mut atom_pos = vec![AtomPosition::default(); self.atom_pos.len()];
+        for (site, position) in self.atom_pos.drain(..).enumerate() {
+            atom_pos[map[site] as usize] = position;
+        }
+        self.atom_pos = atom_pos;
+        let mut energy_cache = vec![E::SiteCache::default(); self.energy_cache.len()];
+        for (site, cache) in self.energy_cache.drain(..).enumerate() {
+            energy_cache[map[site] as usize] = cache;
+        }
+        self.energy_cache = energy_cache;
+        self.onlyocc = self.onlyocc.iter().map(|x| map[*x as usize]).collect();
+        self.atom_list =
+            listdict::SiteList::new(self.atom_pos.len() as u32, self.onlyocc.iter().copied());
+        self.adsorption_sites = listdict::SiteList::new(
+            self.atom_pos.len() as u32,
+            self.adsorption_sites.iter().map(|x| map[*x as usize]),
+        );
+        self.adsorbate_list = listdict::SiteList::new(
+            self.atom_pos.len() as u32,
+            self.adsorbate_list.iter().map(|x| map[*x as usize]),
+        );
+        let mut possible_moves = listdict::ListDict::new(self.atom_pos.len() as u32);
+        for (from, to, energy_change) in self.possible_moves.moves.iter() {
+            possible_moves.add_item(map[*from as usize], map[*to as usize], *energy_change);
+        }
+        self.possible_moves = possible_moves;
+        if let Some(heat_map) = &mut self.heat_map {
+            let mut moved = vec![0; heat_map.len()];
+            for (site, count) in heat_map.iter().enumerate() {
+                moved[map[site] as usize] = *count;
+            }
+            *heat_map = moved;
+        }
+    }
+
     #[inline]
     fn update_cn_dict(&mut self, atom: usize, cn: usize, change_is_positiv: bool) {
         match change_is_positiv {
             true => {
                 // if self.support.is_some() {
-                if self.atom_pos[atom].nn_support == 1 {
+                if self.atom_pos[atom].nn_support() == 1 {
                     self.cn_dict_at_supp[cn] += 1;
                 }
                 // }
                 self.cn_dict[cn] += 1;
+                self.cn_dict_by_element[self.atom_pos[atom].element()][cn] += 1;
             }
             false => {
                 // if self.support.is_some() {
-                if self.atom_pos[atom].nn_support == 1 {
+                if self.atom_pos[atom].nn_support() == 1 {
                     self.cn_dict_at_supp[cn] -= 1;
                 }
                 // }
                 self.cn_dict[cn] -= 1;
+                self.cn_dict_by_element[self.atom_pos[atom].element()][cn] -= 1;
             }
         }
     }
 
+    /// Surface atoms of an hcp particle by facet, judged by the sum of the vectors to their empty
+    /// neighbor sites: along c for basal (0001) facets, perpendicular to c for prismatic facets.
+    /// Atoms at edges and corners are counted as `other`.
+    pub fn count_hcp_facets(
+        &self,
+        onlyocc: &HashSet<u32, fnv::FnvBuildHasher>,
+    ) -> HashMap<String, u32> {
+        const TOLERANCE: f64 = 1e-3;
+        let xsites_positions = &self.gridstructure.xsites_positions;
+        let mut facets: HashMap<String, u32> = HashMap::new();
+        for atom in onlyocc.iter() {
+            let mut empty_direction = [0_f64; 3];
+            let mut is_surface = false;
+            for neighbor in self.gridstructure.nn(*atom) {
+                let site = &self.atom_pos[*neighbor as usize];
+                if site.occ != EMPTY && !site.is_adsorbate() {
+                    continue;
+                }
+                is_surface = true;
+                let diff = self.gridstructure.cell.minimum_image([0, 1, 2].map(|d| {
+                    xsites_positions[*neighbor as usize][d] - xsites_positions[*atom as usize][d]
+                }));
+                for (x, diff) in empty_direction.iter_mut().zip(diff) {
+                    *x += diff;
+                }
+            }
+            if !is_surface {
+                continue;
+            }
+            let in_plane = empty_direction[0].hypot(empty_direction[1]);
+            let along_c = empty_direction[2].abs();
+            let facet = if in_plane < TOLERANCE && along_c >= TOLERANCE {
+                "basal"
+            } else if along_c < TOLERANCE && in_plane >= TOLERANCE {
+                "prismatic"
+            } else {
+                "other"
+            };
+            facets
+                .entry(facet.to_string())
+                .and_modify(|counter| *counter += 1)
+                .or_insert(1);
+        }
+        facets
+    }
+
     pub fn count_empty_sites(
         &self,
         onlyocc: &HashSet<u32, fnv::FnvBuildHasher>,
@@ -668,29 +1358,25 @@ impl Simulation {
         let mut empty_sites = HashSet::new();
         let mut empty_sites_distribution: HashMap<String, u32> = HashMap::new();
         for atom in onlyocc.iter() {
-            for neigbor in self.atom_pos[*atom as usize].nn.iter() {
-                if self.atom_pos[*neigbor as usize].occ == 0 {
+            for neigbor in self.gridstructure.nn(*atom) {
+                if self.atom_pos[*neigbor as usize].occ == EMPTY {
                     empty_sites.insert(neigbor);
                 }
             }
         }
         for site in empty_sites.into_iter() {
             let mut neigbors_count = 0_u32;
-            for neighbor in self.atom_pos[*site as usize].nn {
-                if self.atom_pos[neighbor as usize].occ == 1 {
+            for &neighbor in self.gridstructure.nn(*site) {
+                if self.atom_pos[neighbor as usize].is_metal() {
                     neigbors_count += 1;
                 }
             }
             if neigbors_count == 5 {
                 let mut cn_ten_count = 0;
-                let mut cn_seven_count = 0;
-                for outer in self.atom_pos[*site as usize].nn {
+                for &outer in self.gridstructure.nn(*site) {
                     if self.atom_pos[outer as usize].cn_metal == 10 {
                         cn_ten_count += 1;
                     }
-                    if self.atom_pos[outer as usize].cn_metal == 7 {
-                        cn_seven_count += 1;
-                    }
                 }
                 if cn_ten_count >= 1 {
                     empty_sites_distribution
@@ -708,25 +1394,11 @@ impl Simulation {
     }
 }
 
-fn no_int_nn_from_move(
-    move_from: u32,
-    move_to: u32,
-    nn_pair_no_intersec: &HashMap<u64, [[u32; NN_PAIR_NO_INTERSEC_NUMBER]; 2], fnv::FnvBuildHasher>,
-) -> ([u32; 7], [u32; 7]) {
-    let no_int = nn_pair_no_intersec[&(std::cmp::min(move_from, move_to) as u64
-        + ((std::cmp::max(move_to, move_from) as u64) << 32))];
-    if move_to > move_from {
-        (no_int[0], no_int[1])
-    } else {
-        (no_int[1], no_int[0])
-    }
-}
-
 pub fn find_simulation_with_lowest_energy(folder: String) -> anyhow::Result<()> {
     let mut lowest_e: f64 = f64::INFINITY;
 
     for _ in 0..2 {
-        let paths = fs::read_dir(&folder).unwrap();
+        let paths = fs::read_dir(&folder)?;
         for path in paths {
             let ok_path = match path {
                 Ok(ok_path) => ok_path,
@@ -746,7 +1418,6 @@ pub fn find_simulation_with_lowest_energy(folder: String) -> anyhow::Result<()>
                         if !path2.path().is_dir() {
                             path2
                         } else {
-                            println!("unexpected folder");
                             continue;
                         }
                     }
@@ -759,7 +1430,7 @@ pub fn find_simulation_with_lowest_energy(folder: String) -> anyhow::Result<()>
                     continue;
                 }
 
-                let file = fs::File::open(file.path()).unwrap();
+                let file = fs::File::open(file.path())?;
                 let reader = BufReader::new(file);
                 let res: Result<Results, serde_json::Error> = serde_json::from_reader(reader);
                 match res {
@@ -793,8 +1464,356 @@ pub enum Structure {
 
 #[derive(Clone, Default)]
 pub struct CreateStructure {
-    pub atom_name: String,
-    pub atom_count: u32,
+    /// Element names and numbers of atoms, in the order of `AtomNames::atoms`.
+    pub atoms: Vec<(String, u32)>,
     pub support_vector: Option<Vec<i32>>,
     pub support_atom_name: Option<String>,
 }
+
+impl CreateStructure {
+    pub fn atom_count(&self) -> u32 {
+        self.atoms.iter().map(|(_, count)| count).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::cluster_expansion::ClusterExpansion;
+    use super::energy::{
+        AlloyEnergy, EnergyValues, GasAdsorption, GcnEnergy, PairBondEnergy, PowerCnEnergy,
+        SecondShellEnergy, SupportEnergy,
+    };
+    use super::free_energy::{ElementInput, FreeEnergy, FreeEnergyInput};
+    use super::test_support::{
+        check_moves_against_recomputation, fcc_grid, hop, particle, recomputed_energy,
+        run_and_recompute, swap, TestSimulation,
+    };
+    use super::*;
+
+    const SLOPE: i64 = -440;
+    const INTERCEPT: i64 = 3250;
+    const SUPPORT_E: i64 = -170;
+
+    fn supported_particle(energy: EnergyInput, repetition: usize) -> TestSimulation<EnergyInput> {
+        particle(
+            "pair_bond",
+            fcc_grid([8, 8, 8]),
+            &[("Pt", 60)],
+            true,
+            energy,
+            SimulationOptions {
+                niter: 20000,
+                temperature: 300.,
+                start_temperature: 300.,
+                repetition,
+                support_e: SUPPORT_E,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Atoms of a slab of half the box along `axis`, the rest of the grid empty.
+    fn slab(sim: &mut Simulation, axis: usize) -> HashSet<u32, fnv::FnvBuildHasher> {
+        let half = sim.gridstructure.cell.lengths()[axis] / 2.;
+        let mut onlyocc = HashSet::default();
+        for (site, xyz) in sim.gridstructure.xsites_positions.iter().enumerate() {
+            let is_atom = xyz[axis] < half - 1e-6;
+            sim.atom_pos[site].occ = if is_atom { 1 } else { EMPTY };
+            if is_atom {
+                onlyocc.insert(site as u32);
+            }
+        }
+        onlyocc
+    }
+
+    #[test]
+    fn hcp_slabs_have_basal_and_prismatic_facets() {
+        let gridstructure = GridStructure::generate(
+            Lattice::Hcp {
+                c_over_a: (8_f64 / 3.).sqrt(),
+            },
+            2.51,
+            [4, 4, 4],
+        )
+        .unwrap();
+        let mut sim = particle(
+            "hcp_facets",
+            Arc::new(gridstructure),
+            &[("Co", 20)],
+            false,
+            EnergyInput::PairBond(PairBondEnergy {
+                bond_energy: vec![vec![-700]],
+                atom_energy: vec![2500],
+            }),
+            SimulationOptions::default(),
+        );
+        // the slab along x is only two atoms thick in the layers, so every atom is at the surface
+        for (axis, facet, count) in [
+            (0, "prismatic", 128),
+            (1, "prismatic", 64),
+            (2, "basal", 64),
+        ] {
+            let onlyocc = slab(&mut sim, axis);
+            assert_eq!(onlyocc.len(), 128);
+            assert_eq!(
+                sim.count_hcp_facets(&onlyocc),
+                HashMap::from([(facet.to_string(), count)]),
+                "slab along axis {}",
+                axis
+            );
+        }
+    }
+
+    /// Every bond is counted by both of its atoms in `LinearCn`, so pair bonds of twice the slope
+    /// and the intercept as atom energy have to give the same energies for any move.
+    #[test]
+    fn pair_bond_equals_linear_cn_for_one_element() {
+        let mut linear = supported_particle(
+            EnergyInput::LinearCn(EnergyValues {
+                complet_energy: [SLOPE, INTERCEPT],
+                co_ads_energy: None,
+            }),
+            0,
+        );
+        let mut pair_bond = supported_particle(
+            EnergyInput::PairBond(PairBondEnergy {
+                bond_energy: vec![vec![2 * SLOPE]],
+                atom_energy: vec![INTERCEPT],
+            }),
+            1,
+        );
+        assert_eq!(linear.total_energy_1000, pair_bond.total_energy_1000);
+
+        let mut rng = SmallRng::seed_from_u64(15);
+        for _ in 0..5000 {
+            let (move_from, move_to, _) = linear
+                .possible_moves
+                .choose_random_item_mc(&mut rng)
+                .unwrap();
+            let energy1000_diff = linear.energy_change_by_move(move_from, move_to);
+            assert_eq!(
+                energy1000_diff,
+                pair_bond.energy_change_by_move(move_from, move_to)
+            );
+            for sim in [&mut linear, &mut pair_bond] {
+                sim.perform_move(move_from, move_to, energy1000_diff, true);
+                sim.update_possible_moves(move_from, move_to);
+            }
+            assert_eq!(linear.total_energy_1000, pair_bond.total_energy_1000);
+        }
+        assert_eq!(linear.total_energy_1000, recomputed_energy(&linear));
+        assert_eq!(pair_bond.total_energy_1000, recomputed_energy(&pair_bond));
+
+        run_and_recompute(&mut linear);
+        run_and_recompute(&mut pair_bond);
+    }
+
+    fn annealing(swaps: Option<Swaps>) -> SimulationOptions {
+        SimulationOptions {
+            niter: 40000,
+            temperature: 600.,
+            start_temperature: 1200.,
+            support_e: SUPPORT_E,
+            swaps,
+            ..Default::default()
+        }
+    }
+
+    fn pt_ni() -> AlloyEnergy {
+        AlloyEnergy {
+            cn_energy: vec![
+                EnergyValues {
+                    complet_energy: (0..=12).map(|cn| 3700 - 370 * cn).collect(),
+                    co_ads_energy: None,
+                },
+                EnergyValues {
+                    complet_energy: (0..=12).map(|cn| 3000 - 310 * cn + 4 * cn * cn).collect(),
+                    co_ads_energy: None,
+                },
+            ],
+            mixing_energy: vec![vec![0, -45], vec![-45, 0]],
+        }
+    }
+
+    /// Every term that can be added to a model, for Pt and Ni on an fcc grid with second shell.
+    fn all_terms() -> EnergyTerms {
+        let atoms = ["Pt".to_string(), "Ni".to_string()];
+        let gas = GasAdsorption {
+            temperature: 600.,
+            pressure: 1.,
+            standard_chemical_potential: -1000,
+        };
+        let adsorption_energy: Vec<Vec<i64>> = [(-1800, 60), (-2000, 80)]
+            .iter()
+            .map(|(intercept, slope)| (0..=12).map(|cn| intercept + slope * cn).collect())
+            .collect();
+        let row = |slope: i64| (0..=12).map(|cn| (slope * cn) as f64).collect();
+        let free_energy = FreeEnergyInput {
+            unit: energy_input::Unit::MilliElectronVolt,
+            temperatures: vec![300., 1000.],
+            free_energy: Some(ElementInput::All(vec![row(-10), row(-30)])),
+            vibration_energy: Some(ElementInput::All(
+                (0..=12).map(|cn| 10. + cn as f64).collect(),
+            )),
+        };
+        EnergyTerms {
+            second_shell: Some(SecondShellEnergy {
+                energy: vec![0, -10, -25, -45, -60, -70, -75],
+            }),
+            support: Some(SupportEnergy {
+                energy: vec![
+                    SupportEnergy::table(
+                        Some(&(0..=12).map(|cn| -20 * cn).collect::<Vec<i64>>()),
+                        Some(&[-150, -260, -330]),
+                        12,
+                    ),
+                    SupportEnergy::table(None, Some(&[-90]), 12),
+                ],
+            }),
+            gas_adsorption: Some(gas.site_free_energies(&adsorption_energy)),
+            free_energy: Some(FreeEnergy::new(&free_energy, &atoms, 12, 1200.).unwrap()),
+        }
+    }
+
+    /// The energy every model keeps track of with all terms through hops, swaps and the annealing
+    /// of a run, which rebuilds the free energies, has to stay the one summed up from scratch.
+    #[test]
+    fn energy_of_every_model_matches_recomputation() {
+        let mut gridstructure = GridStructure::generate(Lattice::Fcc, 3.92, [8, 8, 8]).unwrap();
+        gridstructure.add_second_shell().unwrap();
+        let gridstructure = Arc::new(gridstructure);
+        let cluster_expansion = ClusterExpansion::new(
+            &serde_json::from_str(include_str!("../example_data/eci_example.json")).unwrap(),
+            &["Pt".to_string(), "Ni".to_string()],
+            &gridstructure,
+        )
+        .unwrap();
+        let gcn = [(2500, -450, 12), (2200, -400, 9)]
+            .iter()
+            .map(|(intercept, slope, curvature)| {
+                let by_gcn: Vec<i64> = (0..=12)
+                    .map(|gcn| intercept + slope * gcn + curvature * gcn * gcn)
+                    .collect();
+                GcnEnergy::interpolated(&by_gcn, 12)
+            })
+            .collect();
+        for (seed, (name, energy, mode)) in [
+            (
+                "alloy_cn",
+                EnergyInput::AlloyCn(pt_ni()),
+                SwapMode::Neighbor,
+            ),
+            (
+                "pair_bond",
+                EnergyInput::PairBond(PairBondEnergy {
+                    bond_energy: vec![vec![-880, -900], vec![-900, -850]],
+                    atom_energy: vec![3250, 3000],
+                }),
+                SwapMode::Surface,
+            ),
+            (
+                "gcn",
+                EnergyInput::Gcn(GcnEnergy { energy: gcn }),
+                SwapMode::Neighbor,
+            ),
+            (
+                "power_cn",
+                EnergyInput::PowerCn(PowerCnEnergy::new(5840, 0.5, 12)),
+                SwapMode::Surface,
+            ),
+            (
+                "cluster_expansion",
+                EnergyInput::ClusterExpansion(cluster_expansion),
+                SwapMode::Neighbor,
+            ),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let mut sim = particle(
+                name,
+                gridstructure.clone(),
+                &[("Pt", 40), ("Ni", 30)],
+                true,
+                energy,
+                SimulationOptions {
+                    terms: all_terms(),
+                    ..annealing(Some(Swaps { ratio: 0.3, mode }))
+                },
+            );
+            let results = check_moves_against_recomputation(&mut sim, seed as u64);
+            assert!(results.hop_moves.accepted > 0, "{}", name);
+            assert!(results.swap_moves.accepted > 0, "{}", name);
+            assert!(results.swap_misses > 0, "{}", name);
+        }
+    }
+
+    /// Histograms of the CNs by element counted from the atoms.
+    fn recounted_cn_by_element(sim: &Simulation) -> Vec<Vec<u32>> {
+        let mut cn_by_element =
+            vec![vec![0; sim.gridstructure.cn() + 1]; sim.atom_names.atoms.len()];
+        for site in sim.onlyocc.iter() {
+            let atom = &sim.atom_pos[*site as usize];
+            cn_by_element[atom.element()][atom.cn_metal] += 1;
+        }
+        cn_by_element
+    }
+
+    #[test]
+    fn ternary_alloy_keeps_its_composition_and_cn_histograms() {
+        let atoms = ["Pt".to_string(), "Ni".to_string(), "Co".to_string()];
+        let mut json = energy_input::EnergyFile::read(
+            r#"{"mixing_energy_Pt_Ni": [-45], "mixing_energy_Co_Pt": [-30], "mixing_energy_Ni_Co": [-10]}"#,
+        )
+        .unwrap();
+        let mixing_energy = json.pair_energy("mixing_energy", &atoms, false).unwrap();
+        json.check_all_used().unwrap();
+        assert_eq!(
+            mixing_energy,
+            vec![vec![0, -45, -30], vec![-45, 0, -10], vec![-30, -10, 0]]
+        );
+        let cn_energy = [(3700, -370), (3400, -340), (3600, -360)]
+            .iter()
+            .map(|(intercept, slope)| EnergyValues {
+                complet_energy: (0..=12).map(|cn| intercept + slope * cn).collect(),
+                co_ads_energy: None,
+            })
+            .collect();
+        let mode = SwapMode::Surface;
+        let mut sim = particle(
+            "ternary",
+            fcc_grid([8, 8, 8]),
+            &[("Pt", 30), ("Ni", 20), ("Co", 10)],
+            true,
+            EnergyInput::AlloyCn(AlloyEnergy {
+                cn_energy,
+                mixing_energy,
+            }),
+            annealing(Some(Swaps { ratio: 0.3, mode })),
+        );
+        let mut rng = SmallRng::seed_from_u64(14);
+        for step in 0..300 {
+            if step % 3 == 0 {
+                swap(&mut sim, mode, &mut rng);
+            } else {
+                hop(&mut sim, &mut rng);
+            }
+            assert_eq!(sim.total_energy_1000, recomputed_energy(&sim));
+            assert_eq!(sim.cn_dict_by_element, recounted_cn_by_element(&sim));
+        }
+        run_and_recompute(&mut sim);
+
+        let mut lowest = results::LowestEnergy::new();
+        assert!(lowest.update(&sim, &0));
+        let composition: HashMap<String, u32> =
+            HashMap::from([("Pt".into(), 30), ("Ni".into(), 20), ("Co".into(), 10)]);
+        assert_eq!(lowest.composition, composition);
+        for (element, cn_dict) in recounted_cn_by_element(&sim).iter().enumerate() {
+            let reported: Vec<u32> = lowest.element_cn[&atoms[element]]
+                .values()
+                .copied()
+                .collect();
+            assert_eq!(&reported, cn_dict);
+        }
+    }
+}
@@ -41,8 +41,12 @@ impl ListDict {
         }
     }
 
-    pub fn choose_random_item_mc(&self, rng_choose: &mut SmallRng) -> (u32, u32, Option<i64>) {
-        *self.moves.choose(rng_choose).unwrap()
+    /// A random move, `None` if adsorbates cover all empty sites next to the particle.
+    pub fn choose_random_item_mc(
+        &self,
+        rng_choose: &mut SmallRng,
+    ) -> Option<(u32, u32, Option<i64>)> {
+        self.moves.choose(rng_choose).copied()
     }
 
     pub fn _iter(&self) -> std::slice::Iter<'_, (u32, u32, Option<i64>)> {
@@ -53,3 +57,72 @@ impl ListDict {
         self.moves.len()
     }
 }
+
+/// Occupied sites in a list for drawing one at random, the index of every site in it kept next to
+/// it.
+#[derive(Clone)]
+pub struct SiteList {
+    sites: Vec<u32>,
+    position: Vec<u32>,
+}
+
+impl SiteList {
+    pub fn new(nsites: u32, sites: impl Iterator<Item = u32>) -> SiteList {
+        let mut position = vec![u32::MAX; nsites as usize];
+        let sites: Vec<u32> = sites.collect();
+        for (i, site) in sites.iter().enumerate() {
+            position[*site as usize] = i as u32;
+        }
+        SiteList { sites, position }
+    }
+
+    /// The atom at `from` moved to `to`.
+    #[inline]
+    pub fn replace(&mut self, from: u32, to: u32) {
+        let i = self.position[from as usize];
+        self.sites[i as usize] = to;
+        self.position[to as usize] = i;
+        self.position[from as usize] = u32::MAX;
+    }
+
+    /// Adds `site` unless it is in the list already.
+    #[inline]
+    pub fn insert(&mut self, site: u32) {
+        if self.position[site as usize] == u32::MAX {
+            self.position[site as usize] = self.sites.len() as u32;
+            self.sites.push(site);
+        }
+    }
+
+    /// Removes `site` if it is in the list.
+    #[inline]
+    pub fn remove(&mut self, site: u32) {
+        let i = self.position[site as usize];
+        if i == u32::MAX {
+            return;
+        }
+        let last = self.sites.pop().unwrap();
+        if i as usize != self.sites.len() {
+            self.sites[i as usize] = last;
+            self.position[last as usize] = i;
+        }
+        self.position[site as usize] = u32::MAX;
+    }
+
+    pub fn len(&self) -> usize {
+        self.sites.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sites.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, u32> {
+        self.sites.iter()
+    }
+
+    #[inline]
+    pub fn choose_random_site(&self, rng_choose: &mut SmallRng) -> u32 {
+        *self.sites.choose(rng_choose).unwrap()
+    }
+}
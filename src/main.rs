@@ -1,52 +1,73 @@
+use anyhow::{bail, Context};
 use chemfiles::{Frame, Trajectory};
-use clap::{ArgGroup, Parser};
-use core::panic;
-use std::collections::HashMap;
-use std::io::BufReader;
+use clap::{ArgGroup, Parser, Subcommand};
 use std::sync::Arc;
 use std::{fs, thread};
-use MC_Cluster::energy::{EnergyInput, EnergyValues};
-use MC_Cluster::{CreateStructure, GridStructure, Simulation, Structure};
-
-fn atoms_input(atom_name: &str, atom_names: &mut MC_Cluster::AtomNames) {
+use MC_Cluster::adsorbate::{AdsorbateInput, Adsorbates};
+use MC_Cluster::cluster_expansion::{ClusterExpansion, ClusterExpansionInput};
+use MC_Cluster::energy::{
+    AlloyEnergy, EnergyInput, EnergyValues, GasAdsorption, GcnEnergy, PairBondEnergy,
+    PowerCnEnergy, SecondShellEnergy, SupportEnergy,
+};
+use MC_Cluster::energy_input::{self, EnergyFile};
+use MC_Cluster::free_energy::{FreeEnergy, FreeEnergyInput};
+use MC_Cluster::{
+    Boundary, BoundaryAction, CreateStructure, EnergyTerms, GridStructure, Lattice, Simulation,
+    SimulationOptions, Structure, SwapMode, Swaps, MAX_METALS,
+};
+
+/// Lattice constant in Angstrom of the fcc grid generated when neither a grid folder nor a
+/// lattice constant is given.
+const PT_LATTICE_CONSTANT: f64 = 3.92;
+
+/// Fraction of the iterations adsorbing or desorbing an adsorbate unless `--adsorbate-ratio` is
+/// given.
+const ADSORBATE_RATIO: f64 = 0.1;
+
+fn atoms_input(atom_name: &str, atom_names: &mut MC_Cluster::AtomNames) -> anyhow::Result<()> {
     if let Some(supp) = atom_names.support.as_ref() {
         if atom_name == supp {
-            return;
+            return Ok(());
         }
     }
-    if atom_names.atom.is_none() {
-        atom_names.atom = Some(atom_name.to_string());
-    } else if let Some(atom) = atom_names.atom.as_ref() {
-        if atom != atom_name {
-            panic!("to many atoms in input file");
+    if atom_names.adsorbates.iter().any(|x| x == atom_name) {
+        return Ok(());
+    }
+    if !atom_names.atoms.iter().any(|atom| atom == atom_name) {
+        if atom_names.atoms.len() == MAX_METALS {
+            bail!(
+                "to many atoms in input file, at most {} metals and the support are possible",
+                MAX_METALS
+            );
         }
+        atom_names.atoms.push(atom_name.to_string());
     }
+    Ok(())
 }
 
 fn read_sample(
     input_file: &str,
     atom_names: &mut MC_Cluster::AtomNames,
-) -> Vec<(String, [f64; 3])> {
-    if input_file.contains(".poscar") {
-        todo!();
-        // let newatoms = Poscar::from_path(input_file).unwrap();
-        // println!("poscar naem {:?}", newatoms.group_symbols().unwrap().next());
-        // let xyz = newatoms.scaled_cart_positions();
-        // xyz.into_owned()
+) -> anyhow::Result<Vec<(String, [f64; 3])>> {
+    let mut trajectory = if input_file.contains(".poscar") {
+        Trajectory::open_with_format(input_file, 'r', "POSCAR")
     } else if input_file.contains(".xyz") {
-        let mut trajectory = Trajectory::open(input_file, 'r').unwrap();
-        let mut frame = Frame::new();
-        trajectory.read(&mut frame).unwrap();
-        let mut atom_vec: Vec<(String, [f64; 3])> = Vec::new();
-        let positions = frame.positions().to_owned();
-        for (i, atom) in frame.iter_atoms().enumerate() {
-            atoms_input(&atom.name(), atom_names);
-            atom_vec.push((atom.name().clone(), positions[i]));
-        }
-        atom_vec
+        Trajectory::open(input_file, 'r')
     } else {
-        panic!("no .poscar or .xyz, cant read file");
+        bail!("no .poscar or .xyz, cant read file {}", input_file);
+    }
+    .with_context(|| format!("could not open start structure {}", input_file))?;
+    let mut frame = Frame::new();
+    trajectory
+        .read(&mut frame)
+        .with_context(|| format!("could not read start structure {}", input_file))?;
+    let mut atom_vec: Vec<(String, [f64; 3])> = Vec::new();
+    let positions = frame.positions().to_owned();
+    for (i, atom) in frame.iter_atoms().enumerate() {
+        atoms_input(&atom.name(), atom_names)?;
+        atom_vec.push((atom.name().clone(), positions[i]));
     }
+    Ok(atom_vec)
 }
 
 fn prepend<T>(v: Vec<T>, s: &[T]) -> Vec<T>
@@ -58,63 +79,248 @@ where
     tmp
 }
 
-fn fmt_scient(num: &str) -> u64 {
+fn fmt_scient(num: &str) -> anyhow::Result<u64> {
     let mut parts = num.split(['e', 'E']);
 
-    let pre_num = parts.next().unwrap();
+    let pre_num = parts.next().unwrap_or_default();
     let exp = parts.next().unwrap_or("0");
     if parts.next().is_some() {
-        panic!("wrong iterations input");
+        bail!("wrong iterations input: {}", num);
     }
 
     let base: u64 = 10;
-    pre_num.parse::<u64>().expect("wrong iterations input")
-        * base.pow(exp.parse::<u32>().expect("wrong iterations input"))
+    let pre_num = pre_num
+        .parse::<u64>()
+        .with_context(|| format!("wrong iterations input: {}", num))?;
+    let exp = exp
+        .parse::<u32>()
+        .with_context(|| format!("wrong iterations input: {}", num))?;
+    Ok(pre_num * base.pow(exp))
 }
 
-fn collect_energy_values<const N: usize>(inp: String) -> EnergyValues<[i64; N]> {
-    let json =
-        if inp.chars().next().unwrap().is_numeric() || inp.starts_with('-') || inp.starts_with('{')
-        {
-            let res: Result<HashMap<String, Vec<i64>, fnv::FnvBuildHasher>, serde_json::Error> =
-                serde_json::from_str(&inp);
-            res
-        } else if inp.ends_with(".json") {
-            let file = fs::File::open(inp).expect("can't find energy file");
-            let reader = BufReader::new(file);
-            let res: Result<HashMap<String, Vec<i64>, fnv::FnvBuildHasher>, serde_json::Error> =
-                serde_json::from_reader(reader);
-            res
+/// The energy model of the `--e-*` flag which is given.
+fn energy_input(
+    args: &EnergyArgs,
+    atoms: &[String],
+    gridstructure: &GridStructure,
+) -> anyhow::Result<EnergyInput> {
+    let cn = gridstructure.cn();
+    let (json, energy) = if let Some(e_l_cn) = &args.e_l_cn {
+        if atoms.len() > 1 {
+            bail!("alloy particles need CN energies for each element, use --e-cn");
+        }
+        let mut json = EnergyFile::read(e_l_cn)?;
+        let linear = json.require("CN_energy", Some(&atoms[0]), 2)?;
+        let energy = EnergyInput::LinearCn(EnergyValues {
+            complet_energy: [linear[0], linear[1]],
+            co_ads_energy: None,
+        });
+        (json, energy)
+    } else if let Some(e_cn) = &args.e_cn {
+        let mut json = EnergyFile::read(e_cn)?;
+        let energy = if atoms.len() > 1 {
+            EnergyInput::AlloyCn(AlloyEnergy {
+                cn_energy: atoms
+                    .iter()
+                    .map(|atom| json.cn_energy(atom, cn))
+                    .collect::<anyhow::Result<_>>()?,
+                mixing_energy: json.pair_energy("mixing_energy", atoms, false)?,
+            })
         } else {
-            panic!("energy input is neither JSON nor a file path");
-            // fs::read_to_string(inp).expect("can't find energy file")
+            EnergyInput::Cn(json.cn_energy(&atoms[0], cn)?)
         };
-    let mut energy: [i64; N] = [0; N];
-    #[allow(non_snake_case)]
-    let mut CO_ads: [i64; N] = [0; N];
-    for (i, val) in json
-        .as_ref()
-        .unwrap()
-        .get("CN_energy")
-        .unwrap()
-        .iter()
-        .enumerate()
-    {
-        energy[i] = *val;
-    }
-    #[allow(non_snake_case)]
-    let CO_ads_opt = if let Some(it) = json.unwrap().get("ads_e_CO") {
-        for (i, val) in it.iter().enumerate() {
-            CO_ads[i] = *val;
-        }
-        Some(CO_ads)
+        (json, energy)
+    } else if let Some(e_pair) = &args.e_pair {
+        let mut json = EnergyFile::read(e_pair)?;
+        let energy = EnergyInput::PairBond(PairBondEnergy {
+            bond_energy: json.pair_energy("bond_energy", atoms, true)?,
+            atom_energy: atoms
+                .iter()
+                .map(|atom| Ok(json.single("atom_energy", atom)?.unwrap_or(0)))
+                .collect::<anyhow::Result<_>>()?,
+        });
+        (json, energy)
+    } else if let Some(e_gcn) = &args.e_gcn {
+        let mut json = EnergyFile::read(e_gcn)?;
+        let energy = EnergyInput::Gcn(GcnEnergy {
+            energy: atoms
+                .iter()
+                .map(|atom| {
+                    let (key, values) =
+                        json.get_for_element("GCN_energy", atom)?.with_context(|| {
+                            format!(
+                                "energy input has neither GCN_energy_{} nor GCN_energy",
+                                atom
+                            )
+                        })?;
+                    match values.len() {
+                        2 => Ok(GcnEnergy::linear(values[0], values[1], cn)),
+                        len if len == cn + 1 => Ok(GcnEnergy::interpolated(&values, cn)),
+                        len => bail!(
+                            "{} needs a slope and intercept or {} values, one for every GCN from 0 \
+                             to {}, but has {}",
+                            key,
+                            cn + 1,
+                            cn,
+                            len
+                        ),
+                    }
+                })
+                .collect::<anyhow::Result<_>>()?,
+        });
+        (json, energy)
+    } else if let Some(e_power_cn) = &args.e_power_cn {
+        let mut json = EnergyFile::read(e_power_cn)?;
+        let mut cohesive_energy = |atom: &str| {
+            json.single("cohesive_energy", atom)?.with_context(|| {
+                format!(
+                    "energy input has neither cohesive_energy_{} nor cohesive_energy",
+                    atom
+                )
+            })
+        };
+        let energy = if atoms.len() > 1 {
+            let cn_energy = atoms
+                .iter()
+                .map(|atom| {
+                    Ok(EnergyValues {
+                        complet_energy: PowerCnEnergy::table(
+                            cohesive_energy(atom)?,
+                            args.cn_exponent,
+                            cn,
+                        ),
+                        co_ads_energy: None,
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?;
+            EnergyInput::AlloyCn(AlloyEnergy {
+                cn_energy,
+                mixing_energy: json.pair_energy("mixing_energy", atoms, false)?,
+            })
+        } else {
+            EnergyInput::PowerCn(PowerCnEnergy::new(
+                cohesive_energy(&atoms[0])?,
+                args.cn_exponent,
+                cn,
+            ))
+        };
+        (json, energy)
+    } else if let Some(e_cluster_expansion) = &args.e_cluster_expansion {
+        let input: ClusterExpansionInput = energy_input::read_json(e_cluster_expansion)?;
+        return Ok(EnergyInput::ClusterExpansion(ClusterExpansion::new(
+            &input,
+            atoms,
+            gridstructure,
+        )?));
     } else {
-        None
+        bail!("no energy input");
     };
-    EnergyValues {
-        complet_energy: energy,
-        co_ads_energy: CO_ads_opt,
+    json.check_all_used()?;
+    Ok(energy)
+}
+
+/// Energies of an atom by its number of metal second shell neighbors from 0 to `cn2`.
+fn second_shell_energy(input: &str, cn2: usize) -> anyhow::Result<SecondShellEnergy> {
+    let mut json = EnergyFile::read(input)?;
+    let values = json
+        .require("second_shell_energy", None, cn2 + 1)
+        .with_context(|| {
+            format!(
+                "the second shell energies need a value for every number of second shell \
+                 neighbors from 0 to {}",
+                cn2
+            )
+        })?;
+    json.check_all_used()?;
+    Ok(SecondShellEnergy { energy: values })
+}
+
+/// Support energies of every element by its number of support neighbors and CN from 0 to `cn`.
+fn support_energy(input: &str, atoms: &[String], cn: usize) -> anyhow::Result<SupportEnergy> {
+    let mut json = EnergyFile::read(input)?;
+    let energy = atoms
+        .iter()
+        .map(|atom| {
+            let by_cn = json.get_for_element("support_energy_by_cn", atom)?;
+            if let Some((key, values)) = &by_cn {
+                if values.len() != cn + 1 {
+                    bail!(
+                        "{} has {} values but needs one for every CN from 0 to {}",
+                        key,
+                        values.len(),
+                        cn
+                    );
+                }
+            }
+            let by_contacts = json.get_for_element("support_energy_by_contacts", atom)?;
+            match (&by_cn, &by_contacts) {
+                (None, None) => bail!(
+                    "energy input has neither support_energy_by_cn nor \
+                     support_energy_by_contacts for {}",
+                    atom
+                ),
+                (_, Some((key, values))) if values.is_empty() => {
+                    bail!("{} needs a value for at least one support neighbor", key)
+                }
+                _ => {}
+            }
+            Ok(SupportEnergy::table(
+                by_cn.as_ref().map(|(_, values)| values.as_slice()),
+                by_contacts.as_ref().map(|(_, values)| values.as_slice()),
+                cn,
+            ))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    json.check_all_used()?;
+    Ok(SupportEnergy { energy })
+}
+
+/// Gas of `--e-adsorption` and the adsorption energies of every element by CN from 0 to `cn`.
+fn gas_adsorption(
+    args: &EnergyArgs,
+    temperature: f64,
+    atoms: &[String],
+    cn: usize,
+) -> anyhow::Result<Option<(GasAdsorption, Vec<Vec<i64>>)>> {
+    let Some(e_adsorption) = &args.e_adsorption else {
+        return Ok(None);
+    };
+    let temperature = args.gas_temperature.unwrap_or(temperature);
+    if temperature <= 0. || args.gas_pressure <= 0. {
+        bail!(
+            "gas adsorption needs a positive temperature and pressure but got {} K and {} bar",
+            temperature,
+            args.gas_pressure
+        );
     }
+    let mut json = EnergyFile::read(e_adsorption)?;
+    let standard_chemical_potential = match json.get("standard_chemical_potential")? {
+        Some(values) if values.len() == 1 => values[0],
+        Some(values) => bail!(
+            "standard_chemical_potential has {} values but needs 1",
+            values.len()
+        ),
+        None => 0,
+    };
+    let adsorption_energy = atoms
+        .iter()
+        .map(|atom| {
+            json.require("adsorption_energy", Some(atom), cn + 1)
+                .with_context(|| {
+                    format!(
+                        "the adsorption energies of {} need a value for every CN from 0 to {}",
+                        atom, cn
+                    )
+                })
+        })
+        .collect::<anyhow::Result<_>>()?;
+    json.check_all_used()?;
+    let gas = GasAdsorption {
+        temperature,
+        pressure: args.gas_pressure,
+        standard_chemical_potential,
+    };
+    Ok(Some((gas, adsorption_energy)))
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -129,6 +335,8 @@ struct StartStructure {
     start_cluster: Option<String>,
 
     /// Atom name and number of that atom seperated by a comma. "Pt,4000"
+    /// For alloy particles the name and number of each element "Pt,2000,Ni,2000", the elements
+    /// start randomly mixed.
     #[arg(short, long, value_delimiter = ',', allow_hyphen_values(true))]
     atoms: Option<Vec<String>>,
 
@@ -153,15 +361,144 @@ struct StartStructure {
 //     support: Option<Vec<String>>,
 // }
 
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Create a periodic fcc, bcc or hcp grid, or a grid from a crystal structure file, and write
+    /// the setup files, which can be used with `--grid-folder`.
+    Grid(GridArgs),
+    /// Check the setup files of a grid folder: symmetric neighbor lists, neighbor indices in range,
+    /// no duplicate sites and matching line counts of `nearest_neighbor` and `atom_sites`.
+    ValidateGrid(ValidateGridArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct ValidateGridArgs {
+    /// Folder containing the grid files
+    #[arg(short, long)]
+    grid_folder: String,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum GridLattice {
+    /// Face-centered cubic, 12 nearest neighbors
+    Fcc,
+    /// Body-centered cubic, 8 nearest neighbors
+    Bcc,
+    /// Hexagonal close-packed with c along z, 12 nearest neighbors
+    Hcp,
+}
+
+#[derive(clap::Args, Debug)]
+struct GridArgs {
+    /// Lattice type of the grid
+    #[arg(long, value_enum, default_value_t = GridLattice::Fcc)]
+    lattice: GridLattice,
+
+    /// Lattice constant in Angstrom, the in-plane lattice constant a for hcp
+    #[arg(short, long, required_unless_present = "structure")]
+    lattice_constant: Option<f64>,
+
+    /// c/a ratio of hcp grids, the ideal ratio sqrt(8/3) if not given. "1.623" for Co
+    #[arg(long)]
+    c_over_a: Option<f64>,
+
+    /// Crystal structure file (CIF, XYZ with lattice, POSCAR, ...) whose atoms become the sites of
+    /// the grid, instead of one of the built-in lattices
+    #[arg(long, requires = "cutoff", conflicts_with_all = ["lattice_constant", "c_over_a"])]
+    structure: Option<String>,
+
+    /// Sites of the crystal structure closer than this distance in Angstrom are neighbors
+    #[arg(long, requires = "structure")]
+    cutoff: Option<f64>,
+
+    /// Number of unit cells in x, y and z direction seperated by a comma. "30,30,30"
+    /// For hcp the orthorhombic cell with 4 sites and edges a, sqrt(3)a and c is repeated, for a
+    /// crystal structure file the cell of the file.
+    #[arg(short, long, value_delimiter = ',', required = true)]
+    size: Vec<u32>,
+
+    /// Folder the grid files are written to
+    #[arg(short, long)]
+    grid_folder: String,
+}
+
+#[derive(clap::Args, Debug, Clone)]
 #[clap(group(
         ArgGroup::new("energy")
             .multiple(true)
             .required(true)
-            .args(&["e_l_cn", "e_cn"]),
+            .args(&["e_l_cn", "e_cn", "e_pair", "e_gcn", "e_power_cn", "e_cluster_expansion"]),
     ))]
+struct EnergyArgs {
+    /// File path or string containing a JSON-formatted list of energies
+    #[arg(long, allow_hyphen_values(true))]
+    e_l_cn: Option<String>,
+
+    /// File path or string containing JSON-formatted energies
+    #[arg(long, allow_hyphen_values(true))]
+    e_cn: Option<String>,
+
+    /// File path or string containing JSON-formatted bond energies of every pair of elements
+    #[arg(long, allow_hyphen_values(true))]
+    e_pair: Option<String>,
+
+    /// File path or string containing JSON-formatted energies by the generalized coordination
+    /// number, a slope and intercept or a value for every whole GCN
+    #[arg(long, allow_hyphen_values(true))]
+    e_gcn: Option<String>,
+
+    /// File path or string containing JSON-formatted cohesive energies, the CN energies fall with
+    /// CN to the power of `--cn-exponent`
+    #[arg(long, allow_hyphen_values(true))]
+    e_power_cn: Option<String>,
+
+    /// File path or string containing JSON-formatted effective cluster interactions of pairs,
+    /// triplets and quadruplets, each cluster given by the distances between its sites
+    #[arg(long, allow_hyphen_values(true))]
+    e_cluster_expansion: Option<String>,
+
+    /// Exponent of the CN in the `--e-power-cn` energies, 0.5 for second moment tight binding
+    #[arg(long, default_value_t = 0.5)]
+    cn_exponent: f64,
+
+    /// File path or string containing JSON-formatted energies of an atom by its number of metal
+    /// second shell neighbors, added to the other energies
+    #[arg(long, allow_hyphen_values(true))]
+    e_second_shell: Option<String>,
+
+    /// File path or string containing JSON-formatted energies of an atom at the support by its
+    /// CN and its number of support neighbors, replacing `--support-e`
+    #[arg(long, allow_hyphen_values(true), conflicts_with = "support_e")]
+    e_support: Option<String>,
+
+    /// File path or string containing JSON-formatted free energies of an atom by its CN at
+    /// several temperatures and/or its vibration energies by CN, added to the other energies at
+    /// the current annealing temperature
+    #[arg(long, allow_hyphen_values(true))]
+    e_free_energy: Option<String>,
+
+    /// File path or string containing JSON-formatted adsorption energies of a gas molecule on an
+    /// atom by its CN. The Langmuir free energy of the adsorption sites of all exposed atoms at
+    /// `--gas-temperature` and `--gas-pressure` is added to the energies
+    #[arg(long, allow_hyphen_values(true))]
+    e_adsorption: Option<String>,
+
+    /// Temperature of the gas in K, the final temperature of the annealing if not given
+    #[arg(long)]
+    gas_temperature: Option<f64>,
+
+    /// Partial pressure of the gas in bar
+    #[arg(long, default_value_t = 1.)]
+    gas_pressure: f64,
+}
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[command(flatten)]
     start_structure: StartStructure,
 
@@ -170,8 +507,8 @@ struct Args {
     folder: String,
 
     /// Determines the length of the simulation
-    #[arg(short, long)]
-    iterations: String,
+    #[arg(short, long, required = true)]
+    iterations: Option<String>,
 
     /// Temperature where the annealing process starts
     #[arg(short, long, default_value_t = 5000.)]
@@ -190,13 +527,8 @@ struct Args {
     #[arg(long, allow_hyphen_values(true))]
     support_e: Option<i64>,
 
-    /// File path or string containing a JSON-formatted list of energies
-    #[arg(long, allow_hyphen_values(true))]
-    e_l_cn: Option<String>,
-
-    /// File path or string containing JSON-formatted energies
-    #[arg(long, allow_hyphen_values(true))]
-    e_cn: Option<String>,
+    #[command(flatten)]
+    energy: EnergyArgs,
 
     /// How many times the same simulation is run. Multiple runs allow for convergence tests.
     /// The number will be part of the simulation folder name. After running `-r 0-1`, you can run `-r 1-2`
@@ -204,10 +536,32 @@ struct Args {
     #[arg(short, long, value_delimiter = '-', default_values_t = vec!(0,1))]
     repetition: Vec<usize>,
 
-    /// Folder containing the setup files like neighbor sites. It can be created using the Python
-    /// script `create_sites.py`.
-    #[arg(short, long, default_value_t = String::from("../303030-pair"))]
-    grid_folder: String,
+    /// Folder containing the setup files like neighbor sites. It can be created using the `grid`
+    /// subcommand. The pairs of non-intersecting neighbors are derived from the neighbor list, an
+    /// `nn_pair_no_intersec` file in the folder is optional and only checked against them.
+    /// After the first run a binary `grid.bin` is stored in the folder and memory-mapped instead of
+    /// reading the text files, as long as none of them changes.
+    /// Without a grid folder a grid of `--lattice` just large enough for the particle is generated.
+    #[arg(short, long)]
+    grid_folder: Option<String>,
+
+    /// Lattice of the grid generated when no grid folder is given
+    #[arg(long, value_enum, default_value_t = GridLattice::Fcc)]
+    lattice: GridLattice,
+
+    /// Lattice constant in Angstrom of the generated grid, the in-plane lattice constant a for hcp.
+    /// Defaults to the one of Pt for fcc grids of Pt particles and has to be given otherwise
+    #[arg(short, long, required_if_eq_any([("lattice", "bcc"), ("lattice", "hcp")]))]
+    lattice_constant: Option<f64>,
+
+    /// c/a ratio of a generated hcp grid, the ideal ratio sqrt(8/3) if not given
+    #[arg(long)]
+    c_over_a: Option<f64>,
+
+    /// Free space in Angstrom between the particle and the boundary of a generated grid, on top of
+    /// room for the particle to change its shape. A grid folder smaller than that gives a warning.
+    #[arg(long, default_value_t = 10.)]
+    grid_margin: f64,
 
     #[arg(short, long)]
     /// Set how many snapshots are saved in each simulation.
@@ -217,9 +571,66 @@ struct Args {
     /// Generate a heat map
     #[arg(long, default_value_t = false)]
     heat_map: bool,
+
+    /// What happens when an atom of the particle comes closer to the grid boundary than
+    /// `--boundary-margin`
+    #[arg(long, value_enum, default_value_t = OnBoundary::Ignore)]
+    on_boundary: OnBoundary,
+
+    /// Smallest allowed distance of the particle to the grid boundary in Angstrom
+    #[arg(long, default_value_t = 5.)]
+    boundary_margin: f64,
+
+    /// Fraction of the iterations swapping two unlike atoms of an alloy particle instead of
+    /// moving an atom to an empty site
+    #[arg(long, default_value_t = 0.)]
+    swap_ratio: f64,
+
+    /// Which atoms are swapped
+    #[arg(long, value_enum, default_value_t = SwapTargets::Neighbor)]
+    swap_mode: SwapTargets,
+
+    /// File path or string containing JSON-formatted adsorbate species with their chemical
+    /// potential, bond energies to the metal atoms by CN and repulsion, which adsorb on the empty
+    /// sites next to the particle
+    #[arg(long, allow_hyphen_values(true))]
+    adsorbates: Option<String>,
+
+    /// Fraction of the iterations adsorbing or desorbing an adsorbate, 0.1 by default, only with
+    /// adsorbates
+    #[arg(long, requires = "adsorbates")]
+    adsorbate_ratio: Option<f64>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum SwapTargets {
+    /// An atom and one of its neighbors
+    Neighbor,
+    /// Any two atoms at the surface of the particle
+    Surface,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum OnBoundary {
+    /// Translate the particle back to the center of the grid by a lattice vector
+    Recenter,
+    /// End the simulation with an error, the results up to then are written
+    Stop,
+    /// Do not check the distance to the boundary
+    Ignore,
 }
 
-fn file_paths(grid_folder: String) -> (String, String, String, String) {
+fn to_lattice(lattice: GridLattice, c_over_a: Option<f64>) -> Lattice {
+    match lattice {
+        GridLattice::Fcc => Lattice::Fcc,
+        GridLattice::Bcc => Lattice::Bcc,
+        GridLattice::Hcp => Lattice::Hcp {
+            c_over_a: c_over_a.unwrap_or((8_f64 / 3.).sqrt()),
+        },
+    }
+}
+
+fn file_paths(grid_folder: String) -> (String, String, String, String, String) {
     (
         format!("{}/nearest_neighbor", grid_folder),
         format!("{}/nn_pair_no_intersec", grid_folder),
@@ -227,82 +638,185 @@ fn file_paths(grid_folder: String) -> (String, String, String, String) {
         // format!("{}/nn_pairlist", grid_folder),
         format!("{}/atom_sites", grid_folder),
         format!("{}/grid_file.xyz", grid_folder),
+        format!("{}/grid.bin", grid_folder),
     )
 }
 
-fn unpack_support_input(atoms_opt: Option<Vec<String>>) -> (Option<String>, Option<Vec<i32>>) {
+fn unpack_support_input(
+    atoms_opt: Option<Vec<String>>,
+) -> anyhow::Result<(Option<String>, Option<Vec<i32>>)> {
     if let Some(atoms) = atoms_opt {
         if atoms.len() == 1 {
-            (Some(atoms[0].clone()), None)
+            Ok((Some(atoms[0].clone()), None))
         } else if atoms.len() == 4 {
-            (
-                Some(atoms[0].clone()),
-                Some(vec![
-                    atoms[1].parse::<i32>().expect("wrong support vector"),
-                    atoms[2].parse::<i32>().expect("wrong support vector"),
-                    atoms[3].parse::<i32>().expect("wrong support vector"),
-                ]),
-            )
+            let support_vector = atoms[1..]
+                .iter()
+                .map(|x| {
+                    x.parse::<i32>()
+                        .with_context(|| format!("wrong support vector: {}", x))
+                })
+                .collect::<anyhow::Result<_>>()?;
+            Ok((Some(atoms[0].clone()), Some(support_vector)))
         } else {
-            panic!("wrong support input")
-            // panic!("wrong support input, use one of the two input options: \n number of atoms: '-a x' \n or number of atoms with miller indices: '-a x h k l' ")
+            bail!("wrong support input, use the support atom name or the name and a vector orthogonal to the support surface: 'Al,1,1,1'")
         }
     } else {
-        (None, None)
+        Ok((None, None))
     }
 }
 
-fn main() {
+fn main() -> anyhow::Result<()> {
     // enable_data_collection(true);
-    println!("determined next-nearest neighbor list");
 
     let mut atom_names = MC_Cluster::AtomNames::default();
     let args = Args::parse();
+
+    if let Some(Command::Grid(grid_args)) = args.command {
+        let Ok(cells) = <[u32; 3]>::try_from(grid_args.size.clone()) else {
+            bail!(
+                "--size needs 3 numbers of unit cells, got {:?}",
+                grid_args.size
+            );
+        };
+        let gridstructure = if let Some(structure) = grid_args.structure {
+            let cutoff = grid_args.cutoff.context("--structure needs --cutoff")?;
+            GridStructure::from_crystal(&structure, cutoff, cells)
+        } else {
+            let lattice_constant = grid_args
+                .lattice_constant
+                .context("the grid needs --lattice-constant or --structure")?;
+            GridStructure::generate(
+                to_lattice(grid_args.lattice, grid_args.c_over_a),
+                lattice_constant,
+                cells,
+            )
+        }
+        .context("could not create grid")?;
+        println!("coordination number: {}", gridstructure.cn());
+        println!("nsites: {}", gridstructure.xsites_positions.len());
+        gridstructure
+            .write(&grid_args.grid_folder)
+            .context("could not write grid")?;
+        return Ok(());
+    }
+
+    if let Some(Command::ValidateGrid(validate_args)) = args.command {
+        let (pairlist_file, nn_pair_no_int_file, atom_sites, bulk_file_name, grid_cache_file) =
+            file_paths(validate_args.grid_folder);
+        let gridstructure = GridStructure::from_text_files(
+            &pairlist_file,
+            &nn_pair_no_int_file,
+            &atom_sites,
+            &bulk_file_name,
+        )?;
+        let boundary_sites = (0..gridstructure.nsites() as u32)
+            .filter(|x| !gridstructure.is_complete(*x))
+            .count();
+        println!(
+            "grid is valid: {} sites with {} neighbors, {} of them at the boundary with fewer",
+            gridstructure.nsites(),
+            gridstructure.cn(),
+            boundary_sites
+        );
+        if std::path::Path::new(&grid_cache_file).exists() {
+            GridStructure::verify_cache(&grid_cache_file)?;
+            println!("grid cache {} is intact", grid_cache_file);
+        }
+        return Ok(());
+    }
+
     let save_folder: String = args.folder;
     let temperature: f64 = args.temperature;
     let start_temperature: f64 = args.begin_temperature;
     if !std::path::Path::new(&save_folder).exists() {
-        fs::create_dir_all(&save_folder).unwrap();
+        fs::create_dir_all(&save_folder)
+            .with_context(|| format!("could not create {}", save_folder))?;
     }
 
     if let Some(supp_atom) = args.start_structure.support.clone() {
         atom_names.support = Some(supp_atom[0].clone());
     }
+    // the species are needed to tell adsorbates of a start structure from metals
+    let adsorbate_input = if let Some(input) = args.adsorbates.as_ref() {
+        let input: AdsorbateInput =
+            energy_input::read_json(input).context("invalid adsorbate input")?;
+        atom_names.adsorbates = input.names().context("invalid adsorbate input")?;
+        Some(input)
+    } else {
+        None
+    };
     let start_structure = if let Some(start_cluster) = args.start_structure.start_cluster {
-        let xyz = read_sample(&start_cluster, &mut atom_names);
+        let xyz = read_sample(&start_cluster, &mut atom_names)?;
         Structure::StartStructure(Arc::new(xyz))
     } else if let Some(atoms) = args.start_structure.atoms {
-        let (supp_atom_name, support_indices) = unpack_support_input(args.start_structure.support);
-        let (atom_name, atom_count) = (
-            atoms[0].clone(),
-            atoms[1].parse().expect("bad number of atoms"),
-        );
-        atom_names.atom = Some(atom_name.clone());
+        let (supp_atom_name, support_indices) = unpack_support_input(args.start_structure.support)?;
+        if atoms.len() % 2 != 0 || atoms.len() > 2 * MAX_METALS {
+            bail!(
+                "atoms need a name and a number for each of at most {} elements",
+                MAX_METALS
+            );
+        }
+        let atoms = atoms
+            .chunks(2)
+            .map(|atom| {
+                let number = atom[1]
+                    .parse()
+                    .with_context(|| format!("bad number of atoms for {}", atom[0]))?;
+                Ok((atom[0].clone(), number))
+            })
+            .collect::<anyhow::Result<Vec<(String, u32)>>>()?;
+        atom_names.atoms = atoms.iter().map(|(name, _)| name.clone()).collect();
+        if (1..atoms.len()).any(|i| atom_names.atoms[..i].contains(&atom_names.atoms[i])) {
+            bail!("every element can only be given once in atoms");
+        }
         atom_names.support = supp_atom_name.clone();
 
         Structure::CreateCluster(CreateStructure {
-            atom_name,
-            atom_count,
+            atoms,
             support_vector: support_indices,
             support_atom_name: supp_atom_name,
         })
     } else {
-        panic!("either file path to start structure or vlaues for creating a cluster are required");
+        bail!("either file path to start structure or vlaues for creating a cluster are required");
     };
     println!("atom_names {:?}", atom_names);
 
     let support_e = args.support_e.unwrap_or(0);
 
-    let grid_folder: String = Args::parse().grid_folder;
-
-    let niter_str = args.iterations;
-    let niter = fmt_scient(&niter_str);
-    let mut write_snap_shots = args.xyz_trajectory;
+    let niter_str = args.iterations.context("--iterations is required")?;
+    let niter = fmt_scient(&niter_str)?;
+    let write_snap_shots = args.xyz_trajectory;
     let heat_map: bool = args.heat_map;
     if heat_map && write_snap_shots.is_none() {
-        panic!("heatmap without snapshots");
+        bail!("heatmap without snapshots");
     }
     let optimization_cut_off_fraction: Vec<u64> = args.optimization_cut_off_fraction;
+    let boundary = match args.on_boundary {
+        OnBoundary::Recenter => Some(BoundaryAction::Recenter),
+        OnBoundary::Stop => Some(BoundaryAction::Stop),
+        OnBoundary::Ignore => None,
+    }
+    .map(|action| Boundary {
+        margin: args.boundary_margin,
+        action,
+    });
+    if !(0. ..=1.).contains(&args.swap_ratio) {
+        bail!("--swap-ratio has to be between 0 and 1");
+    }
+    let swaps = if args.swap_ratio > 0. {
+        if atom_names.atoms.len() < 2 {
+            bail!("swap moves need an alloy particle with more than one metal");
+        }
+        Some(Swaps {
+            ratio: args.swap_ratio,
+            mode: match args.swap_mode {
+                SwapTargets::Neighbor => SwapMode::Neighbor,
+                SwapTargets::Surface => SwapMode::Surface,
+            },
+        })
+    } else {
+        None
+    };
     let repetition = args.repetition;
 
     let repetition = if repetition.len() == 1 {
@@ -311,53 +825,179 @@ fn main() {
         repetition
     };
 
-    let energy = if args.e_l_cn.is_some() {
-        EnergyInput::LinearCn(collect_energy_values(args.e_l_cn.unwrap()))
-    } else if args.e_cn.is_some() {
-        EnergyInput::Cn(collect_energy_values(args.e_cn.unwrap()))
+    let atom_count = match &start_structure {
+        Structure::StartStructure(xyz) => xyz
+            .iter()
+            .filter(|(name, _)| atom_names.atoms.contains(name))
+            .count() as u32,
+        Structure::CreateCluster(cluster) => cluster.atom_count(),
+    };
+    let supported = atom_names.support.is_some();
+    let mut gridstructure: GridStructure = if let Some(grid_folder) = args.grid_folder {
+        let gridstructure =
+            GridStructure::new(file_paths(grid_folder)).context("could not load grid")?;
+        let recommended_length =
+            gridstructure.recommended_length(atom_count, supported, args.grid_margin);
+        let plane_distances = gridstructure.cell.plane_distances();
+        if plane_distances.iter().any(|x| *x < recommended_length) {
+            eprintln!(
+                "warning: the grid of {:.1?} A is smaller than the {:.1} A recommended for {} atoms, \
+                 leave out --grid-folder to generate a large enough grid",
+                plane_distances, recommended_length, atom_count
+            );
+        }
+        gridstructure
     } else {
-        panic!("no energy")
+        let lattice_constant = match args.lattice_constant {
+            Some(lattice_constant) => lattice_constant,
+            None if atom_names.atoms.iter().all(|atom| atom == "Pt") => PT_LATTICE_CONSTANT,
+            None => bail!(
+                "the lattice constant of the generated grid is only known for Pt, give \
+                 --lattice-constant for {} or a --grid-folder",
+                atom_names.atoms.join(", ")
+            ),
+        };
+        let gridstructure = GridStructure::for_particle(
+            to_lattice(args.lattice, args.c_over_a),
+            lattice_constant,
+            atom_count,
+            supported,
+            args.grid_margin,
+        )
+        .context("could not create grid")?;
+        println!(
+            "generated a grid of {:.1?} A with {} sites for {} atoms",
+            gridstructure.cell.lengths(),
+            gridstructure.nsites(),
+            atom_count
+        );
+        gridstructure
     };
 
+    let energy = energy_input(&args.energy, &atom_names.atoms, &gridstructure)
+        .context("invalid energy input")?;
+
+    let mut terms = EnergyTerms::default();
+    if let Some((gas, adsorption_energy)) = gas_adsorption(
+        &args.energy,
+        temperature,
+        &atom_names.atoms,
+        gridstructure.cn(),
+    )
+    .context("invalid adsorption energy input")?
+    {
+        for (atom, adsorption_energy) in atom_names.atoms.iter().zip(&adsorption_energy) {
+            let coverage: Vec<f64> = adsorption_energy[..gridstructure.cn()]
+                .iter()
+                .map(|x| gas.coverage(*x))
+                .collect();
+            println!("adsorbate coverage of {} by CN: {:.2?}", atom, coverage);
+        }
+        terms.gas_adsorption = Some(gas.site_free_energies(&adsorption_energy));
+    }
+
+    if let Some(e_second_shell) = args.energy.e_second_shell {
+        gridstructure
+            .add_second_shell()
+            .context("could not find second shell neighbors")?;
+        terms.second_shell = Some(
+            second_shell_energy(&e_second_shell, gridstructure.cn2())
+                .context("invalid second shell energy input")?,
+        );
+    }
+
+    if let Some(e_support) = args.energy.e_support {
+        terms.support = Some(
+            support_energy(&e_support, &atom_names.atoms, gridstructure.cn())
+                .context("invalid support energy input")?,
+        );
+    }
+
+    if let Some(e_free_energy) = args.energy.e_free_energy {
+        let free_energy = energy_input::read_json::<FreeEnergyInput>(&e_free_energy)
+            .and_then(|input| {
+                FreeEnergy::new(&input, &atom_names.atoms, gridstructure.cn(), temperature)
+            })
+            .context("invalid free energy input")?;
+        for (atom, free_energy) in atom_names.atoms.iter().zip(free_energy.table()) {
+            println!(
+                "free energy of {} by CN at {} K: {:?}",
+                atom, temperature, free_energy
+            );
+        }
+        terms.free_energy = Some(free_energy);
+    }
+
+    let adsorbate_ratio = args.adsorbate_ratio.unwrap_or(ADSORBATE_RATIO);
+    if !(0. ..1.).contains(&adsorbate_ratio) {
+        bail!("--adsorbate-ratio has to be at least 0 and below 1");
+    }
+    let adsorbates = adsorbate_input
+        .map(|input| {
+            Adsorbates::new(
+                &input,
+                &atom_names.atoms,
+                gridstructure.cn(),
+                adsorbate_ratio,
+            )
+            .context("invalid adsorbate input")
+        })
+        .transpose()?;
+
     println!("energy: {:?}", energy);
-    println!("{:?}", repetition);
+    if let Some(adsorbates) = &adsorbates {
+        println!("adsorbates {:?}: {:?}", atom_names.adsorbates, adsorbates);
+    }
 
     let mut handle_vec = Vec::new();
-    let gridstructure: GridStructure = GridStructure::new(file_paths(grid_folder));
 
     let gridstructure = Arc::new(gridstructure);
 
+    let options = SimulationOptions {
+        niter,
+        temperature,
+        start_temperature,
+        save_folder,
+        snap_shots: write_snap_shots,
+        heat_map,
+        repetition: 0,
+        optimization_cut_off_fraction,
+        support_e,
+        terms,
+        boundary,
+        swaps,
+        adsorbates,
+    };
+
     for rep in repetition[0]..repetition[1] {
-        let save_folder = save_folder.clone();
-        let optimization_cut_off_fraction = optimization_cut_off_fraction.clone();
         let energy = energy.clone();
         let gridstructure_arc = Arc::clone(&gridstructure);
         let start_structure = start_structure.clone();
         let atom_names = atom_names.clone();
+        let options = SimulationOptions {
+            repetition: rep,
+            ..options.clone()
+        };
 
         handle_vec.push(thread::spawn(move || {
             let mut sim = Simulation::new(
                 atom_names,
-                niter,
                 start_structure,
-                temperature,
-                start_temperature,
-                save_folder,
-                write_snap_shots,
-                heat_map,
-                rep,
-                optimization_cut_off_fraction,
                 energy,
                 gridstructure_arc,
-                support_e,
-            );
+                options,
+            )?;
             let exp = sim.run();
             sim.write_exp_file(&exp);
+            anyhow::Ok(())
         }));
     }
     for handle in handle_vec {
-        handle.join().unwrap();
+        handle
+            .join()
+            .map_err(|_| anyhow::anyhow!("a simulation thread panicked"))??;
     }
+    Ok(())
     // MC_Cluster::find_simulation_with_lowest_energy(save_folder).unwrap_or_else(|err| {
     //     println!(
     //         "{:?}",
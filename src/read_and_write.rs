@@ -1,36 +1,79 @@
+use anyhow::{bail, Context};
 use chemfiles::{Atom, Frame, Trajectory, UnitCell};
 use fnv::FnvBuildHasher;
 use fnv::FnvHashMap;
 use std::collections::HashMap;
-use std::collections::HashSet;
 use std::fs;
-use std::io::{self, BufRead};
+use std::io::{self, BufRead, BufWriter, Write};
+
+/// Chemfiles cell in the standard orientation the positions of the grid are stored in.
+fn unit_cell(cell: &super::Cell) -> UnitCell {
+    if cell.is_orthorhombic() {
+        UnitCell::new(cell.lengths())
+    } else {
+        UnitCell::triclinic(cell.lengths(), cell.angles())
+    }
+}
+
+/// Cell vectors as rows, whichever way chemfiles stores them in the matrix, found by comparing
+/// the matrix with the cell lengths.
+fn cell_vectors(cell: &UnitCell) -> [[f64; 3]; 3] {
+    let matrix = cell.matrix();
+    let transposed = [0, 1, 2].map(|row| [0, 1, 2].map(|col| matrix[col][row]));
+    let mismatch = |vectors: &[[f64; 3]; 3]| {
+        vectors
+            .iter()
+            .zip(cell.lengths())
+            .map(|(v, length)| (v.iter().map(|x| x * x).sum::<f64>().sqrt() - length).abs())
+            .sum::<f64>()
+    };
+    if mismatch(&matrix) <= mismatch(&transposed) {
+        matrix
+    } else {
+        transposed
+    }
+}
+
+/// Cell and fractional atom positions of the first frame of a crystal structure file in any
+/// format chemfiles reads, like CIF, XYZ with a lattice or POSCAR.
+pub fn read_crystal(structure_file: &str) -> anyhow::Result<(super::Cell, Vec<[f64; 3]>)> {
+    println!("reading crystal structure from: {}", structure_file);
+    let mut trajectory = Trajectory::open(structure_file, 'r')?;
+    let mut frame = Frame::new();
+    trajectory.read(&mut frame)?;
+    let file_cell = super::Cell::from_matrix(cell_vectors(&frame.cell()))
+        .map_err(|err| anyhow::anyhow!("{} needs a periodic cell: {}", structure_file, err))?;
+    let fractional = frame
+        .positions()
+        .iter()
+        .map(|xyz| file_cell.fractional(*xyz))
+        .collect();
+    // the grid is kept in the orientation chemfiles writes
+    let cell = super::Cell::from_lengths_angles(file_cell.lengths(), file_cell.angles())?;
+    Ok((cell, fractional))
+}
 
 pub fn write_occ_as_xyz(
     atom_names: &super::AtomNames,
     save_folder: String,
-    onlyocc: &HashSet<u32, fnv::FnvBuildHasher>,
+    atoms: &[(u32, u8)],
     xsites_positions: &[[f64; 3]],
-    unit_cell: &[f64; 3],
+    cell: &super::Cell,
     atom_pos: &[super::AtomPosition],
 ) {
     let mut trajectory = Trajectory::open(save_folder.clone() + "/lowest_energy.xyz", 'w').unwrap();
-    let mut xyz: Vec<[f64; 3]> = Vec::new();
-    for (j, ii) in onlyocc.iter().enumerate() {
-        xyz.insert(j, xsites_positions[*ii as usize]);
-    }
     let mut frame = Frame::new();
-    frame.set_cell(&UnitCell::new(*unit_cell));
+    frame.set_cell(&unit_cell(cell));
 
-    for atom in xyz.into_iter() {
+    for (site, occ) in atoms {
         frame.add_atom(
-            &Atom::new(atom_names.atom.as_ref().unwrap().as_str()),
-            [atom[0], atom[1], atom[2]],
+            &Atom::new(atom_names.name(*occ).unwrap()),
+            xsites_positions[*site as usize],
             None,
         );
     }
     for (i, atom) in atom_pos.iter().enumerate() {
-        if atom.occ == 2 {
+        if atom.occ == super::SUPPORT {
             frame.add_atom(
                 &Atom::new(atom_names.support.as_ref().unwrap().as_str()),
                 xsites_positions[i],
@@ -44,91 +87,109 @@ pub fn write_occ_as_xyz(
         .unwrap_or_else(|x| eprintln!("{}", x));
 }
 
-fn fmt_scient(num: &str) -> f64 {
-    let mut parts = num.split('e');
-
-    let pre_num = parts.next().unwrap();
-    let exp = parts.next().unwrap();
-
-    let base: f64 = 10.;
-    pre_num.parse::<f64>().unwrap() * base.powi(exp.parse::<i32>().unwrap())
-}
-
-pub fn read_atom_sites(input_file: &str, nsites: u32) -> Vec<[f64; 3]> {
+pub fn read_atom_sites(input_file: &str) -> anyhow::Result<Vec<[f64; 3]>> {
     println!("reading atom_sites from: {}", input_file);
-    let mut xsites_positions: Vec<[f64; 3]> = Vec::with_capacity(nsites as usize);
-    let pairlist = fs::File::open(input_file).expect("Should have been able to read the file");
+    let mut xsites_positions: Vec<[f64; 3]> = Vec::new();
+    let pairlist = fs::File::open(input_file)
+        .with_context(|| format!("could not open atom_sites file {}", input_file))?;
     let lines = io::BufReader::new(pairlist);
 
-    for line in lines.lines() {
-        let r = line.unwrap();
-        let list: Vec<&str> = r.split_whitespace().clone().collect();
-        let temp_str_vec: [&str; 3] = [list[0], list[1], list[2]];
-        // let temp_vec: [f64; 3] = temp_str_vec.map(|i| fmt_scient(i));
-        let temp_vec: [f64; 3] = temp_str_vec.map(fmt_scient);
-        xsites_positions.push(temp_vec);
+    for (i, line) in lines.lines().enumerate() {
+        let r = line?;
+        let list: Vec<&str> = r.split_whitespace().collect();
+        if list.len() < 3 {
+            bail!("{} line {}: expected x y z, got {:?}", input_file, i + 1, r);
+        }
+        let mut xyz = [0.; 3];
+        for (x, value) in xyz.iter_mut().zip(&list) {
+            *x = value.parse::<f64>().with_context(|| {
+                format!("{} line {}: {:?} is not a number", input_file, i + 1, value)
+            })?;
+        }
+        xsites_positions.push(xyz);
     }
-    xsites_positions
+    Ok(xsites_positions)
 }
 
-pub fn read_nn(pairlist_file: &str) -> HashMap<u32, [u32; super::CN], FnvBuildHasher> {
+/// Lines of a `nearest_neighbor` file as site and its neighbors, in the order of the file.
+pub fn read_nn(pairlist_file: &str) -> anyhow::Result<Vec<(u32, Vec<u32>)>> {
     println!("reading pairlists from: {}", pairlist_file);
 
-    let pairlist = fs::File::open(pairlist_file).expect("Should have been able to read the file");
+    let pairlist = fs::File::open(pairlist_file)
+        .with_context(|| format!("could not open neighbor list {}", pairlist_file))?;
 
     let lines = io::BufReader::new(pairlist);
-    let mut nn: HashMap<u32, [u32; super::CN], FnvBuildHasher> =
-        FnvHashMap::with_capacity_and_hasher(5400, Default::default());
-
-    for line in lines.lines() {
-        let r = line.unwrap();
-        let list: Vec<&str> = r.split_whitespace().clone().collect();
-        let mut neighbors: [u32; super::CN] = [0; super::CN];
-        let prime = list.first();
-        for (i, l) in list.iter().skip(1).enumerate() {
-            neighbors[i] = l.parse::<u32>().unwrap()
-        }
-        nn.insert(prime.unwrap().parse::<u32>().unwrap(), neighbors);
+    let mut nn: Vec<(u32, Vec<u32>)> = Vec::new();
+
+    for (i, line) in lines.lines().enumerate() {
+        let r = line?;
+        let mut list = r.split_whitespace().map(|l| {
+            l.parse::<u32>().with_context(|| {
+                format!(
+                    "{} line {}: {:?} is not a site index",
+                    pairlist_file,
+                    i + 1,
+                    l
+                )
+            })
+        });
+        let Some(site) = list.next() else {
+            bail!("{} line {} is empty", pairlist_file, i + 1);
+        };
+        nn.push((site?, list.collect::<anyhow::Result<Vec<u32>>>()?));
     }
-    nn
+    Ok(nn)
 }
 
+/// Lines of a `nn_pair_no_intersec` file by their pair key, the smaller site plus the larger one
+/// shifted by 32 bits, with the non-intersecting neighbors of the smaller and of the larger site.
 pub fn read_nn_pair_no_intersec(
     nn_pairlist_file: &str,
-) -> HashMap<u64, [[u32; super::NN_PAIR_NO_INTERSEC_NUMBER]; 2], FnvBuildHasher> {
-    let nn_pairlist =
-        fs::File::open(nn_pairlist_file).expect("Should have been able to read the file");
+) -> anyhow::Result<HashMap<u64, [Vec<u32>; 2], FnvBuildHasher>> {
+    let nn_pairlist = fs::File::open(nn_pairlist_file)
+        .with_context(|| format!("could not open pair list {}", nn_pairlist_file))?;
 
     let lines = io::BufReader::new(nn_pairlist);
 
-    let mut nn_pair: HashMap<u64, [[u32; super::NN_PAIR_NO_INTERSEC_NUMBER]; 2], FnvBuildHasher> =
+    let mut nn_pair: HashMap<u64, [Vec<u32>; 2], FnvBuildHasher> =
         FnvHashMap::with_capacity_and_hasher(32000, Default::default());
 
-    for line in lines.lines() {
-        let r = line.unwrap();
-        let test: Vec<&str> = r.split_whitespace().clone().collect();
-        let site: u32 = std::cmp::min(
-            test[0].parse::<u32>().unwrap(),
-            test[1].parse::<u32>().unwrap(),
-        );
-        let j: u32 = std::cmp::max(
-            test[0].parse::<u32>().unwrap(),
-            test[1].parse::<u32>().unwrap(),
-        );
-        let mut neighbors: [[u32; super::NN_PAIR_NO_INTERSEC_NUMBER]; 2] =
-            [[0; super::NN_PAIR_NO_INTERSEC_NUMBER]; 2];
-
-        for (i, l) in test.iter().skip(2).enumerate() {
-            if i < 7 {
-                neighbors[0][i] = l.parse::<u32>().unwrap()
-            } else if i < 14 {
-                neighbors[1][i - 7] = l.parse::<u32>().unwrap()
-            }
+    for (i, line) in lines.lines().enumerate() {
+        let r = line?;
+        let test = r
+            .split_whitespace()
+            .map(|l| {
+                l.parse::<u32>().with_context(|| {
+                    format!(
+                        "{} line {}: {:?} is not a site index",
+                        nn_pairlist_file,
+                        i + 1,
+                        l
+                    )
+                })
+            })
+            .collect::<anyhow::Result<Vec<u32>>>()?;
+        if test.len() < 2 {
+            bail!(
+                "{} line {}: expected a pair, got {:?}",
+                nn_pairlist_file,
+                i + 1,
+                r
+            );
+        }
+        let site = std::cmp::min(test[0], test[1]);
+        let j = std::cmp::max(test[0], test[1]);
+        // the non-intersecting neighbors of both sites, one half each, which only have the same
+        // length for pairs away from the boundary of a finite grid
+        let mut neighbors = test[2..].to_vec();
+        let mut second = neighbors.split_off(neighbors.len() / 2);
+        if test[0] > test[1] {
+            std::mem::swap(&mut neighbors, &mut second);
         }
-        nn_pair.insert(site as u64 + ((j as u64) << 32), neighbors);
+        nn_pair.insert(site as u64 + ((j as u64) << 32), [neighbors, second]);
     }
 
-    nn_pair
+    Ok(nn_pair)
 }
 
 pub fn xyz_write(
@@ -137,27 +198,17 @@ pub fn xyz_write(
     atom_names: &super::AtomNames,
     snap_shot_sections: &[Vec<u8>],
     path: String,
-    unit_cell: &[f64; 3],
+    cell: &super::Cell,
 ) -> anyhow::Result<()> {
     let mut trajectory = Trajectory::open(path, 'w')?;
     for snapshot in snap_shot_sections {
         let mut frame = Frame::new();
-        frame.set_cell(&UnitCell::new(*unit_cell));
+        frame.set_cell(&unit_cell(cell));
 
         for (i, atom) in snapshot.iter().enumerate() {
-            if atom == &1 {
+            if let Some(name) = atom_names.name(*atom) {
                 frame.add_atom(
-                    &Atom::new(atom_names.atom.as_ref().unwrap().as_str()),
-                    [
-                        xsites_positions[i][0],
-                        xsites_positions[i][1],
-                        xsites_positions[i][2],
-                    ],
-                    None,
-                );
-            } else if atom == &2 {
-                frame.add_atom(
-                    &Atom::new(atom_names.support.as_ref().unwrap().as_str()),
+                    &Atom::new(name),
                     [
                         xsites_positions[i][0],
                         xsites_positions[i][1],
@@ -173,3 +224,37 @@ pub fn xyz_write(
     }
     Ok(())
 }
+
+pub fn write_grid(grid_folder: &str, gridstructure: &super::GridStructure) -> anyhow::Result<()> {
+    fs::create_dir_all(grid_folder)?;
+    println!("writing grid to: {}", grid_folder);
+
+    let mut nn_file = BufWriter::new(fs::File::create(format!(
+        "{}/nearest_neighbor",
+        grid_folder
+    ))?);
+    for site in 0..gridstructure.nsites() as u32 {
+        write!(nn_file, "{}", site)?;
+        for neighbor in gridstructure.nn(site) {
+            write!(nn_file, " {}", neighbor)?;
+        }
+        writeln!(nn_file)?;
+    }
+    nn_file.flush()?;
+
+    let mut atom_sites_file =
+        BufWriter::new(fs::File::create(format!("{}/atom_sites", grid_folder))?);
+    for xyz in gridstructure.xsites_positions.iter() {
+        writeln!(atom_sites_file, "{:e} {:e} {:e}", xyz[0], xyz[1], xyz[2])?;
+    }
+    atom_sites_file.flush()?;
+
+    let mut trajectory = Trajectory::open(format!("{}/grid_file.xyz", grid_folder), 'w')?;
+    let mut frame = Frame::new();
+    frame.set_cell(&unit_cell(&gridstructure.cell));
+    for xyz in gridstructure.xsites_positions.iter() {
+        frame.add_atom(&Atom::new("X"), *xyz, None);
+    }
+    trajectory.write(&frame)?;
+    Ok(())
+}
@@ -1,8 +1,8 @@
 use serde::{Deserialize, Serialize, Serializer};
 use serde_with::serde_as;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap};
 
-use super::Simulation;
+use super::{EnergyModel, EnergyTerm, Lattice, Simulation};
 
 // const KB: f64 = 8.6173324e-5;
 
@@ -15,9 +15,22 @@ pub struct LowestEnergy {
     pub empty_cn: HashMap<String, u32>,
     #[serde(serialize_with = "ordered_map")]
     pub cn_dict_at_supp: HashMap<u8, u32>,
+    /// Surface atoms on basal and prismatic facets, only for hcp grids.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub hcp_facets: HashMap<String, u32>,
+    /// Number of atoms of every element, only for alloy particles.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub composition: HashMap<String, u32>,
+    /// `cn_total` of every element, only for alloy particles.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub element_cn: HashMap<String, BTreeMap<u8, u32>>,
+    /// Number of adsorbates of every species, only with adsorbates.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub adsorbates: BTreeMap<String, u32>,
     pub iiter: u64,
+    /// Sites of the atoms and adsorbates with their occupancy codes.
     #[serde(skip_serializing)]
-    pub onlyocc: HashSet<u32, fnv::FnvBuildHasher>,
+    pub atoms: Vec<(u32, u8)>,
 }
 
 impl LowestEnergy {
@@ -28,25 +41,76 @@ impl LowestEnergy {
         }
     }
 
-    pub fn update(&mut self, sim: &Simulation, iiter: &u64) -> bool {
+    pub fn update<E: EnergyModel, T: EnergyTerm>(
+        &mut self,
+        sim: &Simulation<E, T>,
+        iiter: &u64,
+    ) -> bool {
         if self.energy > (sim.total_energy_1000 as f64 / 1000.) {
             let empty_neighbor_cn = sim.count_empty_sites(&sim.onlyocc);
             self.empty_cn = empty_neighbor_cn;
+            if let Some(Lattice::Hcp { .. }) = sim.gridstructure.lattice() {
+                self.hcp_facets = sim.count_hcp_facets(&sim.onlyocc);
+            }
             self.energy = sim.total_energy_1000 as f64 / 1000.;
             self.iiter = *iiter;
 
             let mut cn_hash_map: HashMap<u8, u32> = HashMap::new();
-            for (i, v) in sim.cn_dict.into_iter().enumerate() {
+            for (i, v) in sim.cn_dict.iter().copied().enumerate() {
                 cn_hash_map.insert(i as u8, v);
             }
             self.cn_total = cn_hash_map;
 
             let mut cn_hash_map_at_supp: HashMap<u8, u32> = HashMap::new();
-            for (i, v) in sim.cn_dict_at_supp.into_iter().enumerate() {
+            for (i, v) in sim.cn_dict_at_supp.iter().copied().enumerate() {
                 cn_hash_map_at_supp.insert(i as u8, v);
             }
             self.cn_dict_at_supp = cn_hash_map_at_supp;
-            self.onlyocc = sim.onlyocc.clone();
+
+            if sim.atom_names.atoms.len() > 1 {
+                self.element_cn = sim
+                    .atom_names
+                    .atoms
+                    .iter()
+                    .zip(&sim.cn_dict_by_element)
+                    .map(|(name, cn_dict)| {
+                        let cn_dict = cn_dict
+                            .iter()
+                            .enumerate()
+                            .map(|(i, v)| (i as u8, *v))
+                            .collect();
+                        (name.clone(), cn_dict)
+                    })
+                    .collect();
+                self.composition = sim
+                    .atom_names
+                    .atoms
+                    .iter()
+                    .zip(&sim.cn_dict_by_element)
+                    .map(|(name, cn_dict)| (name.clone(), cn_dict.iter().sum()))
+                    .collect();
+            }
+            self.atoms = sim
+                .onlyocc
+                .iter()
+                .chain(sim.adsorbate_list.iter())
+                .map(|site| (*site, sim.atom_pos[*site as usize].occ))
+                .collect();
+            if sim.adsorbates.is_some() {
+                self.adsorbates = sim
+                    .atom_names
+                    .adsorbates
+                    .iter()
+                    .map(|name| (name.clone(), 0))
+                    .collect();
+                for site in sim.adsorbate_list.iter() {
+                    let species = sim.atom_pos[*site as usize].adsorbate();
+                    *self
+                        .adsorbates
+                        .get_mut(&sim.atom_names.adsorbates[species])
+                        .unwrap() += 1;
+                }
+            }
 
             true
         } else {
@@ -84,6 +148,48 @@ pub struct Results {
     pub number_all_atoms: u32,
     pub energy_section_list: Vec<f64>,
     pub cn_dict_sections: Vec<HashMap<u8, f64>>,
+    /// `cn_dict_sections` of every element, only for alloy particles. The histograms of an element
+    /// add up to its number of atoms.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub element_cn_dict_sections: Vec<HashMap<String, HashMap<u8, f64>>>,
+    /// Translations of the particle away from the grid boundary.
+    #[serde(default)]
+    pub recenterings: Vec<Recentering>,
+    /// Set when the simulation ended early because the particle reached the grid boundary.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boundary_stop: Option<BoundaryStop>,
+    /// Moves of an atom to an empty site.
+    #[serde(default)]
+    pub hop_moves: MoveStatistics,
+    /// Swaps of two unlike atoms, only tried for alloy particles.
+    #[serde(default)]
+    pub swap_moves: MoveStatistics,
+    /// Iterations of swap moves that drew no pair of unlike atoms and left the particle as it
+    /// was. They are not counted in `swap_moves`.
+    #[serde(default)]
+    pub swap_misses: u64,
+    /// Adsorptions and desorptions, only tried with adsorbates.
+    #[serde(default)]
+    pub adsorbate_moves: MoveStatistics,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct MoveStatistics {
+    pub attempted: u64,
+    pub accepted: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Recentering {
+    pub iiter: u64,
+    /// Translation of all sites in Angstrom.
+    pub translation: [f64; 3],
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BoundaryStop {
+    pub iiter: u64,
+    pub error: String,
 }
 
 fn ordered_map<S>(value: &HashMap<u8, u32>, serializer: S) -> Result<S::Ok, S::Error>
@@ -1,5 +1,8 @@
 use fnv::FnvBuildHasher;
-use std::collections::{HashMap, HashSet};
+use rand::rngs::SmallRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::collections::HashSet;
 use std::sync::Arc;
 
 fn is_in_pane(point_xyz: &[f64; 3], fixpoint: &[f64; 3], ort_vector: &[i32]) -> bool {
@@ -15,22 +18,22 @@ fn is_in_pane(point_xyz: &[f64; 3], fixpoint: &[f64; 3], ort_vector: &[i32]) ->
 
 fn create_support(
     atom_pos: &mut [super::AtomPosition],
-    xsites_positions: &[[f64; 3]],
+    gridstructure: &super::GridStructure,
     support_indices: &[i32],
-    nn: &HashMap<u32, [u32; 12], FnvBuildHasher>,
     iclose: u32,
 ) {
+    let xsites_positions = &gridstructure.xsites_positions;
     let center_of_mass: &[f64; 3] = &xsites_positions[iclose as usize];
     let mut support_vec = Vec::new();
     for (i, xyz) in xsites_positions.iter().enumerate() {
         if is_in_pane(xyz, center_of_mass, support_indices) {
-            atom_pos[i].occ = 2;
+            atom_pos[i].occ = super::SUPPORT;
             support_vec.push(i as u32)
         };
     }
     let mut second_layer_fixpoint = 0;
-    for neighbor in nn[&iclose] {
-        if atom_pos[neighbor as usize].occ == 0 {
+    for &neighbor in gridstructure.nn(iclose) {
+        if atom_pos[neighbor as usize].occ == super::EMPTY {
             second_layer_fixpoint = neighbor;
         }
     }
@@ -40,14 +43,27 @@ fn create_support(
             &xsites_positions[second_layer_fixpoint as usize],
             support_indices,
         ) {
-            atom_pos[i].occ = 2;
+            atom_pos[i].occ = super::SUPPORT;
             support_vec.push(i as u32)
         };
     }
+    count_support_contacts(atom_pos, gridstructure, &support_vec);
+}
+
+/// Counts the support neighbors of every site next to one of the support sites.
+fn count_support_contacts(
+    atom_pos: &mut [super::AtomPosition],
+    gridstructure: &super::GridStructure,
+    support_vec: &[u32],
+) {
     for sup in support_vec.iter() {
-        for neighbor in nn[sup] {
-            if atom_pos[neighbor as usize].occ != 2 {
-                atom_pos[neighbor as usize].nn_support = 1;
+        for &neighbor in gridstructure.nn(*sup) {
+            if atom_pos[neighbor as usize].occ != super::SUPPORT {
+                atom_pos[neighbor as usize].support_contacts = gridstructure
+                    .nn(neighbor)
+                    .iter()
+                    .filter(|x| atom_pos[**x as usize].occ == super::SUPPORT)
+                    .count() as u8;
             }
         }
     }
@@ -56,14 +72,14 @@ fn create_support(
 pub fn create_input_cluster(
     atom_pos: &mut [super::AtomPosition],
     number_of_atoms: &u32,
-    xsites_positions: &Vec<[f64; 3]>,
-    nn: &HashMap<u32, [u32; 12], FnvBuildHasher>,
+    gridstructure: &super::GridStructure,
     nsites: u32,
     support_indices: Option<&Vec<i32>>,
 ) -> HashSet<u32, FnvBuildHasher> {
+    let xsites_positions = &gridstructure.xsites_positions;
     let center_of_mass: [f64; 3] = {
         let mut d: [Vec<f64>; 3] = [Vec::new(), Vec::new(), Vec::new()];
-        for coord in xsites_positions {
+        for coord in xsites_positions.iter() {
             d[0].push(coord[0]);
             d[1].push(coord[1]);
             d[2].push(coord[2]);
@@ -89,24 +105,19 @@ pub fn create_input_cluster(
         }
         index_of_center
     };
-    println!(
-        "{:?}, {:?}, {:?}",
-        center_of_mass, iclose, xsites_positions[iclose as usize]
-    );
-    println!("nsites: {}", nsites);
     assert_eq!(xsites_positions.len(), nsites as usize);
     let mut onlyocc: HashSet<u32, FnvBuildHasher> =
         fnv::FnvHashSet::with_capacity_and_hasher(*number_of_atoms as usize, Default::default());
 
     if let Some(supp) = support_indices {
-        create_support(atom_pos, xsites_positions, supp, nn, iclose);
+        create_support(atom_pos, gridstructure, supp, iclose);
     }
 
-    if atom_pos[iclose as usize].occ == 0 {
+    if atom_pos[iclose as usize].occ == super::EMPTY {
         onlyocc.insert(iclose);
     } else {
-        for neighbor in nn[&iclose] {
-            if atom_pos[neighbor as usize].occ == 0 {
+        for &neighbor in gridstructure.nn(iclose) {
+            if atom_pos[neighbor as usize].occ == super::EMPTY {
                 onlyocc.insert(neighbor);
                 break;
             }
@@ -119,10 +130,10 @@ pub fn create_input_cluster(
     loop {
         let mut onlyocc_temp_storag: HashSet<u32> = HashSet::new();
         for site in onlyocc.iter() {
-            for j in &nn[site] {
+            for j in gridstructure.nn(*site) {
                 if !onlyocc_temp_storag.contains(j)
                     && !onlyocc.contains(j)
-                    && atom_pos[*j as usize].occ == 0
+                    && atom_pos[*j as usize].occ == super::EMPTY
                 {
                     onlyocc_temp_storag.insert(*j);
                     // onlyocc.insert(j);
@@ -151,42 +162,126 @@ pub fn create_input_cluster(
     onlyocc
 }
 
+/// Distributes the elements randomly over the atoms of a new particle, `counts` atoms of each.
+pub fn assign_elements(
+    atom_pos: &mut [super::AtomPosition],
+    onlyocc: &HashSet<u32, FnvBuildHasher>,
+    counts: &[u32],
+) {
+    let mut sites: Vec<u32> = onlyocc.iter().copied().collect();
+    sites.sort_unstable();
+    sites.shuffle(&mut SmallRng::from_entropy());
+    let mut sites = sites.into_iter();
+    for (element, count) in counts.iter().enumerate() {
+        for site in sites.by_ref().take(*count as usize) {
+            atom_pos[site as usize].occ = element as u8 + 1;
+        }
+    }
+}
+
 pub fn occ_onlyocc_from_xyz(
     atom_pos: &mut [super::AtomPosition],
     xyz: Arc<Vec<(String, [f64; 3])>>,
     nsites: u32,
-    xsites_positions: &[[f64; 3]],
     atom_names: &super::AtomNames,
-    nn: &HashMap<u32, [u32; 12], FnvBuildHasher>,
+    gridstructure: &super::GridStructure,
 ) -> HashSet<u32, FnvBuildHasher> {
+    let xsites_positions = &gridstructure.xsites_positions;
     let mut onlyocc: HashSet<u32, FnvBuildHasher> =
         fnv::FnvHashSet::with_capacity_and_hasher(xyz.len(), Default::default());
 
     let mut support_vec = Vec::new();
     for x in xyz.iter() {
         for site in 0..nsites {
-            let dist = (x.1[0] - xsites_positions[site as usize][0]).powf(2.)
-                + (x.1[1] - xsites_positions[site as usize][1]).powf(2.)
-                + (x.1[2] - xsites_positions[site as usize][2]).powf(2.);
+            // atoms outside of the cell are matched with their periodic image
+            let diff = gridstructure
+                .cell
+                .minimum_image([0, 1, 2].map(|d| x.1[d] - xsites_positions[site as usize][d]));
+            let dist = diff[0].powf(2.) + diff[1].powf(2.) + diff[2].powf(2.);
             if dist < 0.15 {
-                if &x.0 == atom_names.atom.as_ref().unwrap() {
-                    atom_pos[site as usize].occ = 1;
-                    onlyocc.insert(site);
-                } else if let Some(supp) = atom_names.support.as_ref() {
-                    if supp == &x.0 {
-                        atom_pos[site as usize].occ = 2;
+                match atom_names.occ(&x.0) {
+                    Some(super::SUPPORT) => {
+                        atom_pos[site as usize].occ = super::SUPPORT;
                         support_vec.push(site)
                     }
+                    Some(occ) => {
+                        atom_pos[site as usize].occ = occ;
+                        // adsorbates are not part of the particle
+                        if atom_pos[site as usize].is_metal() {
+                            onlyocc.insert(site);
+                        }
+                    }
+                    None => {}
                 }
             }
         }
     }
-    for sup in support_vec.iter() {
-        for neighbor in nn[sup] {
-            if atom_pos[neighbor as usize].occ != 2 {
-                atom_pos[neighbor as usize].nn_support = 1;
-            }
+    count_support_contacts(atom_pos, gridstructure, &support_vec);
+    onlyocc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::fcc_grid;
+    use crate::{AtomPosition, GridStructure, SUPPORT};
+
+    /// Sites of a new support along `support_indices` through the middle of `gridstructure`.
+    fn support(gridstructure: &GridStructure, support_indices: &[i32]) -> Vec<AtomPosition> {
+        let mut atom_pos = vec![AtomPosition::default(); gridstructure.nsites()];
+        let iclose = gridstructure.nsites() as u32 / 2;
+        create_support(&mut atom_pos, gridstructure, support_indices, iclose);
+        atom_pos
+    }
+
+    /// Support neighbors of every site that is not part of the support, counted from scratch.
+    fn contacts(gridstructure: &GridStructure, atom_pos: &[AtomPosition]) -> Vec<u8> {
+        (0..gridstructure.nsites() as u32)
+            .map(|site| {
+                if atom_pos[site as usize].occ == SUPPORT {
+                    return 0;
+                }
+                gridstructure
+                    .nn(site)
+                    .iter()
+                    .filter(|x| atom_pos[**x as usize].occ == SUPPORT)
+                    .count() as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sites_on_a_100_support_have_four_contacts() {
+        let gridstructure = fcc_grid([6, 6, 6]);
+        let atom_pos = support(&gridstructure, &[1, 0, 0]);
+        // two layers of 2 * 6 * 6 sites with a layer of sites on either side
+        assert_eq!(atom_pos.iter().filter(|x| x.occ == SUPPORT).count(), 144);
+        let counted: Vec<u8> = atom_pos.iter().map(|x| x.support_contacts()).collect();
+        assert_eq!(counted, contacts(&gridstructure, &atom_pos));
+        assert_eq!(counted.iter().filter(|x| **x == 4).count(), 144);
+        assert!(counted.iter().all(|x| [0, 4].contains(x)));
+    }
+
+    #[test]
+    fn sites_on_a_111_support_have_up_to_three_contacts() {
+        let gridstructure = fcc_grid([6, 6, 6]);
+        let atom_pos = support(&gridstructure, &[1, 1, 1]);
+        let counted: Vec<u8> = atom_pos.iter().map(|x| x.support_contacts()).collect();
+        assert_eq!(counted, contacts(&gridstructure, &atom_pos));
+        assert_eq!(counted.iter().max(), Some(&3));
+        assert!(counted.contains(&3));
+    }
+
+    #[test]
+    fn contacts_are_counted_for_the_neighbors_of_the_support_only() {
+        let gridstructure = fcc_grid([6, 6, 6]);
+        let mut atom_pos = vec![AtomPosition::default(); gridstructure.nsites()];
+        let site = 100;
+        atom_pos[site].occ = SUPPORT;
+        count_support_contacts(&mut atom_pos, &gridstructure, &[site as u32]);
+        for (other, atom) in atom_pos.iter().enumerate() {
+            let is_neighbor = gridstructure.nn(site as u32).contains(&(other as u32));
+            assert_eq!(atom.support_contacts(), is_neighbor as u8, "site {}", other);
         }
     }
-    onlyocc
 }
@@ -0,0 +1,62 @@
+use memmap2::Mmap;
+use std::ops::Deref;
+use std::sync::Arc;
+
+enum Storage<T> {
+    Owned(#[allow(dead_code)] Vec<T>),
+    Mapped(#[allow(dead_code)] Arc<Mmap>),
+}
+
+/// Read-only per-site table, either owned or pointing directly into a memory-mapped grid cache.
+pub struct SiteTable<T> {
+    ptr: *const T,
+    len: usize,
+    _storage: Storage<T>,
+}
+
+// SAFETY: the table is never mutated after creation and the storage behind `ptr` lives as long
+// as the table.
+unsafe impl<T: Sync> Send for SiteTable<T> {}
+unsafe impl<T: Sync> Sync for SiteTable<T> {}
+
+impl<T> SiteTable<T> {
+    /// View `len` values of `T` starting at `offset` bytes into the mapped file.
+    ///
+    /// # Safety
+    /// `T` has to be plain old data whose in-memory layout matches the file layout.
+    pub unsafe fn from_mmap(mmap: &Arc<Mmap>, offset: usize, len: usize) -> Option<SiteTable<T>> {
+        let size = len.checked_mul(std::mem::size_of::<T>())?;
+        if offset.checked_add(size)? > mmap.len() {
+            return None;
+        }
+        let ptr = mmap.as_ptr().add(offset) as *const T;
+        if !ptr.is_aligned() {
+            return None;
+        }
+        Some(SiteTable {
+            ptr,
+            len,
+            _storage: Storage::Mapped(Arc::clone(mmap)),
+        })
+    }
+}
+
+impl<T> From<Vec<T>> for SiteTable<T> {
+    fn from(vec: Vec<T>) -> SiteTable<T> {
+        SiteTable {
+            ptr: vec.as_ptr(),
+            len: vec.len(),
+            _storage: Storage::Owned(vec),
+        }
+    }
+}
+
+impl<T> Deref for SiteTable<T> {
+    type Target = [T];
+
+    #[inline]
+    fn deref(&self) -> &[T] {
+        // SAFETY: `ptr` and `len` describe memory owned by `_storage`
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
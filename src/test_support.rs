@@ -0,0 +1,215 @@
+//! Particles and checks shared by the unit tests.
+use super::*;
+
+/// Generated fcc grid with the lattice constant of Pt.
+pub(crate) fn fcc_grid(cells: [u32; 3]) -> Arc<GridStructure> {
+    Arc::new(GridStructure::generate(Lattice::Fcc, 3.92, cells).unwrap())
+}
+
+/// Simulation of a test with the temporary folder it writes to, which is removed with it.
+pub(crate) struct TestSimulation<E: EnergyModel> {
+    sim: Simulation<E>,
+    _folder: tempfile::TempDir,
+}
+
+impl<E: EnergyModel> std::ops::Deref for TestSimulation<E> {
+    type Target = Simulation<E>;
+
+    fn deref(&self) -> &Simulation<E> {
+        &self.sim
+    }
+}
+
+impl<E: EnergyModel> std::ops::DerefMut for TestSimulation<E> {
+    fn deref_mut(&mut self) -> &mut Simulation<E> {
+        &mut self.sim
+    }
+}
+
+/// Particle of `atoms` created on `gridstructure`, on an Al support along (1, 1, 1) if
+/// `supported`. The simulation folder goes to a temporary folder named after the test, the
+/// adsorbate species of `options` are named A0, A1, ...
+pub(crate) fn particle<E: EnergyModel>(
+    test: &str,
+    gridstructure: Arc<GridStructure>,
+    atoms: &[(&str, u32)],
+    supported: bool,
+    energy: E,
+    options: SimulationOptions,
+) -> TestSimulation<E> {
+    let folder = tempfile::Builder::new()
+        .prefix(&format!("mc_cluster_{}", test))
+        .tempdir()
+        .unwrap();
+    let support = supported.then(|| "Al".to_string());
+    let adsorbates = options
+        .adsorbates
+        .as_ref()
+        .map_or(0, |adsorbates| adsorbates.species());
+    let sim = Simulation::new(
+        AtomNames {
+            atoms: atoms.iter().map(|(name, _)| name.to_string()).collect(),
+            support: support.clone(),
+            adsorbates: (0..adsorbates).map(|i| format!("A{}", i)).collect(),
+        },
+        Structure::CreateCluster(CreateStructure {
+            atoms: atoms
+                .iter()
+                .map(|(name, count)| (name.to_string(), *count))
+                .collect(),
+            support_vector: supported.then(|| vec![1, 1, 1]),
+            support_atom_name: support,
+        }),
+        energy,
+        gridstructure,
+        SimulationOptions {
+            save_folder: format!("{}/", folder.path().to_str().unwrap()),
+            ..options
+        },
+    )
+    .unwrap();
+    TestSimulation {
+        sim,
+        _folder: folder,
+    }
+}
+
+/// Atoms of the `occupation` of every site with their neighbors counted from it alone.
+pub(crate) fn counted_atoms(gridstructure: &GridStructure, occupation: &[u8]) -> Vec<AtomPosition> {
+    let occupied: Vec<AtomPosition> = occupation
+        .iter()
+        .map(|occ| AtomPosition {
+            occ: *occ,
+            ..Default::default()
+        })
+        .collect();
+    let count = |sites: &[u32], is_counted: fn(&AtomPosition) -> bool| {
+        sites
+            .iter()
+            .filter(|x| is_counted(&occupied[**x as usize]))
+            .count()
+    };
+    let mut atom_pos = occupied.clone();
+    for (site, atom) in atom_pos.iter_mut().enumerate() {
+        let neighbors = gridstructure.nn(site as u32);
+        atom.cn_metal = count(neighbors, AtomPosition::is_metal);
+        // only counted for the sites atoms can go to
+        if atom.occ != SUPPORT {
+            atom.support_contacts = count(neighbors, |x| x.occ == SUPPORT) as u8;
+        }
+        if gridstructure.cn2() > 0 {
+            atom.cn2_metal = count(gridstructure.nn2(site as u32), AtomPosition::is_metal) as u8;
+        }
+    }
+    atom_pos
+}
+
+/// Atoms of the elements given by their index in `AtomNames::atoms` at the sites of
+/// `gridstructure`, all other sites empty.
+pub(crate) fn atoms_at(gridstructure: &GridStructure, atoms: &[(u32, usize)]) -> Vec<AtomPosition> {
+    let mut occupation = vec![EMPTY; gridstructure.nsites()];
+    for (site, element) in atoms {
+        occupation[*site as usize] = *element as u8 + 1;
+    }
+    counted_atoms(gridstructure, &occupation)
+}
+
+/// Energy of the metal atoms of `sites` summed up with a new cache of the energy model.
+pub(crate) fn summed_energy<E: EnergyModel, T: EnergyTerm>(
+    energy: &E,
+    terms: &T,
+    sites: &Sites,
+) -> i64 {
+    let mut cache = vec![E::SiteCache::default(); sites.atom_pos.len()];
+    energy.init_cache(sites, &mut cache);
+    (0..sites.atom_pos.len() as u32)
+        .filter(|site| sites.atom(*site).is_metal())
+        .map(|site| energy.site_energy(sites, &cache, site) + terms.site_energy(sites, site))
+        .sum()
+}
+
+/// Counts the neighbors of every site from the occupation alone and checks them against the
+/// ones the simulation keeps up to date, then returns the energy of the particle summed up from
+/// scratch with a new cache of the energy model.
+pub(crate) fn recomputed_energy<E: EnergyModel>(sim: &Simulation<E>) -> i64 {
+    let occupation: Vec<u8> = sim.atom_pos.iter().map(|x| x.occ).collect();
+    let atom_pos = counted_atoms(&sim.gridstructure, &occupation);
+    for (site, (kept, atom)) in sim.atom_pos.iter().zip(&atom_pos).enumerate() {
+        assert_eq!(
+            (
+                kept.cn_metal,
+                kept.support_contacts,
+                kept.cn2_metal,
+                kept.is_metal()
+            ),
+            (
+                atom.cn_metal,
+                atom.support_contacts,
+                atom.cn2_metal,
+                sim.onlyocc.contains(&(site as u32))
+            ),
+            "neighbors of site {}",
+            site
+        );
+    }
+
+    let sites = Sites {
+        atom_pos: &atom_pos,
+        gridstructure: &sim.gridstructure,
+        support_e: sim.support_e,
+    };
+    let mut energy = summed_energy(&sim.energy, &sim.terms, &sites);
+    if let Some(adsorbates) = &sim.adsorbates {
+        energy += adsorbates.total_energy(&sites);
+    }
+    energy
+}
+
+/// Runs `sim` and checks the energy it kept track of against the one from scratch.
+pub(crate) fn run_and_recompute<E: EnergyModel>(sim: &mut Simulation<E>) -> Results {
+    let results = sim.run();
+    assert_eq!(sim.total_energy_1000, recomputed_energy(sim));
+    results
+}
+
+/// Performs random hops, every third step a swap instead if `sim` has swap moves, and checks the
+/// energy after every step against the one from scratch, then runs `sim` and checks it again.
+pub(crate) fn check_moves_against_recomputation<E: EnergyModel>(
+    sim: &mut Simulation<E>,
+    seed: u64,
+) -> Results {
+    assert_eq!(sim.total_energy_1000, recomputed_energy(sim));
+    let mut rng = SmallRng::seed_from_u64(seed);
+    for step in 0..300 {
+        match sim.swaps {
+            Some(swaps) if step % 3 == 0 => swap(sim, swaps.mode, &mut rng),
+            _ => hop(sim, &mut rng),
+        }
+        assert_eq!(
+            sim.total_energy_1000,
+            recomputed_energy(sim),
+            "step {}",
+            step
+        );
+    }
+    run_and_recompute(sim)
+}
+
+/// Performs a random move of an atom to an empty site whatever its energy.
+pub(crate) fn hop<E: EnergyModel>(sim: &mut Simulation<E>, rng: &mut SmallRng) {
+    let (move_from, move_to, _) = sim.possible_moves.choose_random_item_mc(rng).unwrap();
+    let energy1000_diff = sim.energy_change_by_move(move_from, move_to);
+    sim.perform_move(move_from, move_to, energy1000_diff, true);
+    sim.update_possible_moves(move_from, move_to);
+}
+
+/// Performs a random swap of two unlike atoms whatever its energy.
+pub(crate) fn swap<E: EnergyModel>(sim: &mut Simulation<E>, mode: SwapMode, rng: &mut SmallRng) {
+    let (site_a, site_b) = loop {
+        if let Some(pair) = sim.choose_swap(mode, rng) {
+            break pair;
+        }
+    };
+    let energy1000_diff = sim.energy_change_by_swap(site_a, site_b);
+    sim.perform_swap(site_a, site_b, energy1000_diff, true);
+}